@@ -4,9 +4,20 @@ use secrecy::ExposeSecret;
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
-    let credentials = envy::from_env::<Credentials>().unwrap();
+    let credentials = credentials();
 
     let user_session = login(credentials).await.unwrap();
 
-    println!("{:?}", user_session.0.expose_secret());
+    println!("{:?}", user_session.token().expose_secret());
+}
+
+/// Reads the credentials from the environment, or prompts for them when
+/// built with the `prompt` feature and run with `--prompt`
+fn credentials() -> Credentials {
+    #[cfg(feature = "prompt")]
+    if std::env::args().any(|arg| arg == "--prompt") {
+        return Credentials::prompt_interactive().unwrap();
+    }
+
+    envy::from_env::<Credentials>().unwrap()
 }
@@ -0,0 +1,90 @@
+//! Records of login attempts for audit trails
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use reqwest::Url;
+
+use crate::LoginError;
+
+/// A secret-safe record of one login attempt at one endpoint
+///
+/// Records are passed to [`LoginObserver::on_attempt`](crate::LoginObserver::on_attempt)
+/// after every attempt, whether it succeeded or failed. The account is
+/// masked and no password, token or cookie is included, so records can be
+/// written to audit logs as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the attempt started
+    pub timestamp: SystemTime,
+    /// `"success"`, or a stable code naming the `LoginError`, e.g. `"forbidden"`
+    pub outcome: &'static str,
+    /// The login endpoint that was tried
+    pub endpoint: Url,
+    /// The email address or phone number with most of it masked
+    pub account: String,
+    /// How long the attempt took
+    pub latency: Duration,
+    /// The address family of the connection, if one was established
+    ///
+    /// With a proxy, this is the family of the connection to the proxy.
+    pub address_family: Option<AddressFamily>,
+}
+
+/// IP address family of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// IPv4
+    Ipv4,
+    /// IPv6
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Returns the family of a socket address
+    pub(crate) fn of(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => AddressFamily::Ipv4,
+            SocketAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+/// Returns the outcome code of the result of an attempt
+pub(crate) fn outcome<T>(result: &Result<T, LoginError>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(error) => error.code(),
+    }
+}
+
+/// Masks an email address or phone number for an audit record
+///
+/// An email address keeps the first character of its local part and its
+/// domain, e.g. `u***@example.com`. Anything else keeps its last two
+/// characters, e.g. `***78`.
+pub(crate) fn mask_account(mail_tel: &str) -> String {
+    if let Some((local, domain)) = mail_tel.rsplit_once('@') {
+        let first: String = local.chars().take(1).collect();
+        return format!("{first}***@{domain}");
+    }
+
+    let chars: Vec<char> = mail_tel.chars().collect();
+    let tail: String = chars[chars.len().saturating_sub(2)..].iter().collect();
+    format!("***{tail}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that accounts are masked down to a hint
+    #[test]
+    fn test_mask_account() {
+        assert_eq!(mask_account("user@example.com"), "u***@example.com");
+        assert_eq!(mask_account("@example.com"), "***@example.com");
+        assert_eq!(mask_account("09012345678"), "***78");
+        assert_eq!(mask_account("7"), "***7");
+        assert_eq!(mask_account(""), "***");
+    }
+}
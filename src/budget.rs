@@ -0,0 +1,195 @@
+//! Limits on the resources used by a whole login operation
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::Request;
+use tokio::time::Instant;
+
+use crate::LoginError;
+
+/// Upper bounds for a whole login operation
+///
+/// Unlike the timeouts of a [`LoginBuilder`](crate::LoginBuilder), which
+/// apply to each request, the budget covers every request made by one call to
+/// [`login`](crate::LoginBuilder::login) or
+/// [`login_with_retry`](crate::LoginBuilder::login_with_retry), including
+/// retries and endpoint failover. Exceeding it fails with
+/// `LoginError::BudgetExceeded`. No limit is set by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoginBudget {
+    /// Maximum number of bytes sent and received
+    ///
+    /// This counts the URL, headers and body of every request and the
+    /// headers and body read of every response, without the framing of the
+    /// HTTP protocol, so it is an approximation of the bytes on the wire.
+    pub max_bytes: Option<u64>,
+    /// Maximum wall-clock time of the whole operation
+    pub max_duration: Option<Duration>,
+}
+
+/// The limit of a [`LoginBudget`] that was exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetLimit {
+    /// [`LoginBudget::max_bytes`] was exceeded
+    Bytes,
+    /// [`LoginBudget::max_duration`] was exceeded
+    Duration,
+}
+
+impl fmt::Display for BudgetLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BudgetLimit::Bytes => "byte",
+            BudgetLimit::Duration => "time",
+        })
+    }
+}
+
+/// Tracks the resources used so far by one login operation
+#[derive(Debug)]
+pub(crate) struct BudgetTracker {
+    max_bytes: Option<u64>,
+    deadline: Option<Instant>,
+    used_bytes: AtomicU64,
+}
+
+impl BudgetTracker {
+    /// Starts tracking an operation limited by the budget
+    pub(crate) fn start(budget: &LoginBudget) -> Self {
+        Self {
+            max_bytes: budget.max_bytes,
+            deadline: budget
+                .max_duration
+                .and_then(|max_duration| Instant::now().checked_add(max_duration)),
+            used_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Fails if the deadline has passed
+    pub(crate) fn check_deadline(&self) -> Result<(), LoginError> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(LoginError::BudgetExceeded(BudgetLimit::Duration))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether waiting for a delay would still end before the deadline
+    pub(crate) fn leaves_time_for(&self, delay: Duration) -> bool {
+        self.deadline
+            .is_none_or(|deadline| Instant::now() + delay < deadline)
+    }
+
+    /// Runs a future, failing if it does not complete before the deadline
+    pub(crate) async fn within<T>(
+        &self,
+        future: impl Future<Output = Result<T, LoginError>>,
+    ) -> Result<T, LoginError> {
+        let Some(deadline) = self.deadline else {
+            return future.await;
+        };
+
+        tokio::time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| LoginError::BudgetExceeded(BudgetLimit::Duration))?
+    }
+
+    /// Counts bytes against the budget, failing once it is exceeded
+    pub(crate) fn add_bytes(&self, bytes: usize) -> Result<(), LoginError> {
+        let bytes = u64::try_from(bytes).unwrap_or(u64::MAX);
+        let used = self
+            .used_bytes
+            .fetch_add(bytes, Ordering::Relaxed)
+            .saturating_add(bytes);
+
+        match self.max_bytes {
+            Some(max_bytes) if used > max_bytes => {
+                Err(LoginError::BudgetExceeded(BudgetLimit::Bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the number of bytes of a request counted against the budget
+pub(crate) fn request_size(request: &Request) -> usize {
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .map_or(0, <[u8]>::len);
+
+    request.url().as_str().len() + headers_size(request.headers()) + body
+}
+
+/// Returns the number of bytes of a set of headers counted against the budget
+pub(crate) fn headers_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the byte limit fails once the counted bytes exceed it
+    #[test]
+    fn test_add_bytes() {
+        let tracker = BudgetTracker::start(&LoginBudget {
+            max_bytes: Some(100),
+            max_duration: None,
+        });
+
+        assert!(tracker.add_bytes(60).is_ok());
+        assert!(tracker.add_bytes(40).is_ok());
+        assert!(matches!(
+            tracker.add_bytes(1),
+            Err(LoginError::BudgetExceeded(BudgetLimit::Bytes))
+        ));
+    }
+
+    /// Tests that a future running past the deadline is cut off
+    #[tokio::test]
+    async fn test_within_deadline() {
+        let tracker = BudgetTracker::start(&LoginBudget {
+            max_bytes: None,
+            max_duration: Some(Duration::from_millis(50)),
+        });
+
+        let result = tracker
+            .within(async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Ok(())
+            })
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(LoginError::BudgetExceeded(BudgetLimit::Duration))
+        ));
+        assert!(tracker.check_deadline().is_err());
+    }
+
+    /// Tests that the deadline follows a paused Tokio clock
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_paused_clock() {
+        let tracker = BudgetTracker::start(&LoginBudget {
+            max_bytes: None,
+            max_duration: Some(Duration::from_secs(5)),
+        });
+        assert!(tracker.leaves_time_for(Duration::from_secs(4)));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!tracker.leaves_time_for(Duration::from_secs(4)));
+        assert!(tracker.check_deadline().is_ok());
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(tracker.check_deadline().is_err());
+    }
+}
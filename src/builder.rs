@@ -0,0 +1,3403 @@
+//! Configurable login requests
+//!
+//! [`LoginBuilder`] exposes the knobs of the login request that the plain
+//! [`login`](crate::login) function fixes to their defaults.
+
+use std::error::Error as _;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::dns::{Name, Resolve, Resolving};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Method, Request, Response, StatusCode, Url};
+use secrecy::ExposeSecret;
+#[cfg(feature = "socks")]
+use secrecy::SecretString;
+use tokio::sync::Semaphore;
+
+use crate::budget::{headers_size, request_size, BudgetTracker};
+use crate::clock::SharedClock;
+use crate::idempotency::IDEMPOTENCY_KEY_HEADER;
+use crate::ip_family::FamilyResolver;
+use crate::observer::SharedObserver;
+use crate::profile::fetch_user_id_with;
+use crate::rate_limit::SharedRateLimiter;
+use crate::timing::DnsTimer;
+use crate::validation::verify_session;
+use crate::{
+    audit, captcha, cookie, parse_response_header_in, redirect, region, request_error,
+    AddressFamily, AuditRecord, CapturedResponse, Clock, ClockSkew, CookieDiagnostics, Credentials,
+    HtmlResponse, IdempotencyKey, IpFamily, LoginBudget, LoginDetails, LoginError, LoginObserver,
+    LoginResult, LoginTimings, LoginWarning, RateLimiter, RawSetCookies, RedirectHop, UserId,
+    UserSession, DEFAULT_MAX_CREDENTIAL_LENGTH, DEFAULT_MAX_SET_COOKIES,
+};
+
+/// Default endpoint that accepts the login form
+pub(crate) const LOGIN_URL: &str = "https://account.nicovideo.jp/login/redirector";
+
+/// Maximum number of bytes of the response body read for a snippet
+const BODY_READ_LIMIT: usize = 16 * 1024;
+
+/// Maximum number of characters kept in a body snippet
+const BODY_SNIPPET_LENGTH: usize = 1024;
+
+/// Minimum length of a run of word characters scrubbed as a possible token
+const TOKEN_LIKE_LENGTH: usize = 24;
+
+/// Default user agent sent with the login request
+pub(crate) const USER_AGENT: &str = "toof-jp/niconico";
+
+/// Default `Origin` header, matching the login form of the account domain
+const ORIGIN: &str = "https://account.nicovideo.jp";
+
+/// Default `Referer` header, matching the login form of the account domain
+const REFERER: &str = "https://account.nicovideo.jp/login";
+
+/// Encoding used for the body of the login request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyFormat {
+    /// `application/x-www-form-urlencoded` with UTF-8, which is what Niconico accepts today
+    #[default]
+    Form,
+    /// `application/json`, for a JSON variant of the login endpoint
+    Json,
+}
+
+/// Where the session token is read from in a successful login response
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionSource {
+    /// The `user_session` cookie of a redirect, which is what Niconico sends today
+    #[default]
+    Cookie,
+    /// The `user_session` field of a JSON body answering with a success
+    /// status, falling back to the cookie when the field is missing
+    Json,
+    /// The JSON body when the response is labelled
+    /// `Content-Type: application/json`, and the cookie otherwise
+    Auto,
+}
+
+/// Field of a JSON login response that carries the session token
+const JSON_SESSION_FIELD: &str = "user_session";
+
+/// Niconico service that the session is obtained for
+///
+/// The subsites open the login form with their own `site` parameter and
+/// redirect back to themselves afterwards. Selecting one sends the same
+/// parameter and, unless [`LoginBuilder::next_url`] is set, redirects to the
+/// subsite. The session cookie is issued for the whole `nicovideo.jp` domain
+/// in every case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Site {
+    /// The main video site, which sends no `site` parameter and is redirected
+    /// to the account page, as the plain login form does
+    #[default]
+    Main,
+    /// Niconico Live, sent as `site=nicolive` and redirected to
+    /// `https://live.nicovideo.jp/`
+    Live,
+    /// Niconico Channel, sent as `site=nicochannel` and redirected to
+    /// `https://ch.nicovideo.jp/`
+    Channel,
+}
+
+impl Site {
+    /// Returns the value of the `site` parameter sent for the site
+    fn param(self) -> Option<&'static str> {
+        match self {
+            Site::Main => None,
+            Site::Live => Some("nicolive"),
+            Site::Channel => Some("nicochannel"),
+        }
+    }
+
+    /// Returns where the site redirects after logging in when no next URL is set
+    fn next_url(self) -> Option<&'static str> {
+        match self {
+            Site::Main => None,
+            Site::Live => Some("https://live.nicovideo.jp/"),
+            Site::Channel => Some("https://ch.nicovideo.jp/"),
+        }
+    }
+}
+
+/// Builder for a customized login request
+///
+/// # Examples
+///
+/// ```no_run
+/// use niconico::{BodyFormat, Credentials, LoginBuilder};
+///
+/// # async fn run(credentials: Credentials) -> Result<(), niconico::LoginError> {
+/// let user_session = LoginBuilder::new()
+///     .body_format(BodyFormat::Form)
+///     .login(&credentials)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoginBuilder {
+    body_format: BodyFormat,
+    session_source: SessionSource,
+    mail_tel_field: String,
+    password_field: String,
+    max_credential_length: usize,
+    trim_password: bool,
+    max_set_cookies: usize,
+    session_cookie_domain: String,
+    site: Site,
+    base_urls: Vec<Url>,
+    next_url: Option<String>,
+    success_statuses: Vec<StatusCode>,
+    reject_unexpected_status: bool,
+    capture_raw_set_cookies: bool,
+    session_not_found_retries: u32,
+    capture_body_snippet: bool,
+    capture_timings: bool,
+    capture_response_to: Option<PathBuf>,
+    idempotency_key: Option<IdempotencyKey>,
+    #[cfg(feature = "cassette")]
+    cassette: Option<crate::cassette::Cassette>,
+    warn_on_missing_secure: bool,
+    require_secure_cookie: bool,
+    expected_user_id: Option<UserId>,
+    verify_after_login: bool,
+    max_redirects: u32,
+    redirect_hosts: Vec<String>,
+    on_redirect: Option<RedirectCallback>,
+    include_profile: bool,
+    include_csrf_token: bool,
+    budget: LoginBudget,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    local_address: Option<IpAddr>,
+    proxy: Option<reqwest::Proxy>,
+    dns_resolver: Option<DnsResolver>,
+    ip_family: IpFamily,
+    observer: Option<SharedObserver>,
+    clock: SharedClock,
+    rate_limiter: Option<SharedRateLimiter>,
+    connection_limit: Option<Arc<Semaphore>>,
+    request_hook: Option<RequestHook>,
+    response_classifier: Option<ResponseClassifier>,
+    user_agent: HeaderValue,
+    default_headers: bool,
+    origin: Option<HeaderValue>,
+    referer: Option<HeaderValue>,
+    client: Option<reqwest::Client>,
+    #[cfg(feature = "middleware")]
+    middleware_client: Option<reqwest_middleware::ClientWithMiddleware>,
+}
+
+impl Default for LoginBuilder {
+    fn default() -> Self {
+        Self {
+            body_format: BodyFormat::default(),
+            session_source: SessionSource::default(),
+            mail_tel_field: "mail_tel".to_string(),
+            password_field: "password".to_string(),
+            max_credential_length: DEFAULT_MAX_CREDENTIAL_LENGTH,
+            trim_password: true,
+            max_set_cookies: DEFAULT_MAX_SET_COOKIES,
+            session_cookie_domain: crate::session::COOKIE_DOMAIN.to_string(),
+            site: Site::default(),
+            base_urls: Vec::new(),
+            next_url: None,
+            success_statuses: vec![StatusCode::FOUND],
+            reject_unexpected_status: false,
+            capture_raw_set_cookies: false,
+            session_not_found_retries: 0,
+            capture_body_snippet: false,
+            capture_timings: false,
+            capture_response_to: None,
+            idempotency_key: None,
+            #[cfg(feature = "cassette")]
+            cassette: None,
+            warn_on_missing_secure: false,
+            require_secure_cookie: false,
+            expected_user_id: None,
+            verify_after_login: false,
+            max_redirects: 0,
+            redirect_hosts: redirect::DEFAULT_REDIRECT_HOSTS.map(String::from).to_vec(),
+            on_redirect: None,
+            include_profile: false,
+            include_csrf_token: false,
+            budget: LoginBudget::default(),
+            timeout: None,
+            connect_timeout: None,
+            read_timeout: None,
+            local_address: None,
+            proxy: None,
+            dns_resolver: None,
+            ip_family: IpFamily::default(),
+            observer: None,
+            clock: SharedClock::default(),
+            rate_limiter: None,
+            connection_limit: None,
+            request_hook: None,
+            response_classifier: None,
+            user_agent: HeaderValue::from_static(USER_AGENT),
+            default_headers: true,
+            origin: Some(HeaderValue::from_static(ORIGIN)),
+            referer: Some(HeaderValue::from_static(REFERER)),
+            client: None,
+            #[cfg(feature = "middleware")]
+            middleware_client: None,
+        }
+    }
+}
+
+impl LoginBuilder {
+    /// Creates a builder with the same settings as [`login`](crate::login)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the encoding of the request body
+    ///
+    /// Defaults to [`BodyFormat::Form`].
+    pub fn body_format(mut self, body_format: BodyFormat) -> Self {
+        self.body_format = body_format;
+        self
+    }
+
+    /// Sets where the session token is read from in the login response
+    ///
+    /// A token read from a JSON body must start with `user_session_`, like
+    /// the cookie, and one with characters not allowed in a cookie value is
+    /// reported as `LoginError::InvalidSessionToken`. It is not checked by
+    /// [`require_secure_cookie`](Self::require_secure_cookie) since it carries
+    /// no cookie attributes. Defaults to [`SessionSource::Cookie`].
+    pub fn session_source(mut self, session_source: SessionSource) -> Self {
+        self.session_source = session_source;
+        self
+    }
+
+    /// Overrides the names of the fields carrying the credentials
+    ///
+    /// Defaults to `mail_tel` and `password`, the names Niconico expects today.
+    /// This is an escape hatch for when Niconico renames the fields before
+    /// this crate catches up; there is no need to set it otherwise.
+    pub fn field_names(mut self, mail_tel: impl Into<String>, password: impl Into<String>) -> Self {
+        self.mail_tel_field = mail_tel.into();
+        self.password_field = password.into();
+        self
+    }
+
+    /// Sets the maximum length of the email address and password, in bytes
+    ///
+    /// Longer credentials fail with `LoginError::InvalidCredentialFormat`
+    /// without being sent. Defaults to
+    /// [`DEFAULT_MAX_CREDENTIAL_LENGTH`].
+    pub fn max_credential_length(mut self, max_length: usize) -> Self {
+        self.max_credential_length = max_length;
+        self
+    }
+
+    /// Sets whether a single trailing line ending is removed from the password
+    ///
+    /// A password read from a file or piped from `echo` usually ends with a
+    /// `\n` that is not part of it, and sending it makes Niconico reject the
+    /// credentials without any hint why. So one trailing `\r\n` or `\n` is
+    /// removed before sending, and other whitespace is kept. Disable this for
+    /// a password that really ends with a line break. Defaults to `true`.
+    pub fn trim_password(mut self, trim: bool) -> Self {
+        self.trim_password = trim;
+        self
+    }
+
+    /// Sets how many cookies of a response are scanned for the session
+    ///
+    /// This bounds the work done on a broken or malicious response with
+    /// thousands of `Set-Cookie` headers. When the limit is reached before the
+    /// session cookie, the login fails with `LoginError::UserSessionNotFound`
+    /// noting the limit. Defaults to [`DEFAULT_MAX_SET_COOKIES`].
+    pub fn max_set_cookies(mut self, max_cookies: usize) -> Self {
+        self.max_set_cookies = max_cookies;
+        self
+    }
+
+    /// Sets the domain whose `user_session` cookies are accepted
+    ///
+    /// A session cookie whose `Domain` attribute is neither the domain nor
+    /// one of its subdomains is ignored, and a cookie without the attribute is
+    /// accepted. Defaults to `nicovideo.jp`; a test server issuing cookies for
+    /// another domain can set it.
+    pub fn session_cookie_domain(mut self, domain: impl Into<String>) -> Self {
+        self.session_cookie_domain = domain.into();
+        self
+    }
+
+    /// Selects the Niconico service to log in for
+    ///
+    /// Defaults to [`Site::Main`].
+    pub fn site(mut self, site: Site) -> Self {
+        self.site = site;
+        self
+    }
+
+    /// Sets the candidate base URLs of the account service, for failover
+    ///
+    /// The login path is resolved against each base URL, so a base URL with
+    /// a path must end with a slash. Defaults to `https://account.nicovideo.jp`.
+    ///
+    /// The endpoints are tried one after another in the given order, never
+    /// concurrently, so the credentials are only sent to the next endpoint
+    /// once the previous one has failed. Only retryable errors, such as
+    /// network failures, timeouts and maintenance, move on to the next
+    /// endpoint; any other error, such as rejected credentials, is returned
+    /// immediately. When every endpoint fails, the errors are returned
+    /// together as `LoginError::AllEndpointsFailed`.
+    pub fn base_urls(mut self, base_urls: impl IntoIterator<Item = Url>) -> Self {
+        self.base_urls = base_urls.into_iter().collect();
+        self
+    }
+
+    /// Sets where Niconico should redirect after logging in
+    ///
+    /// This is sent as the `next_url` query parameter of the login request,
+    /// e.g. `/watch/sm9`. The resolved redirect target is returned in
+    /// [`LoginDetails::location`](crate::LoginDetails::location), so the caller
+    /// can follow it with the session.
+    pub fn next_url(mut self, next_url: impl Into<String>) -> Self {
+        self.next_url = Some(next_url.into());
+        self
+    }
+
+    /// Sets the response statuses that are expected on a successful login
+    ///
+    /// Defaults to `302 Found`, which is how the redirector answers today. A
+    /// session cookie found on any other status is still accepted, but logged
+    /// as a warning when the `tracing` feature is enabled, unless
+    /// [`reject_unexpected_status`](Self::reject_unexpected_status) is set.
+    pub fn success_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.success_statuses = statuses.into_iter().collect();
+        self
+    }
+
+    /// Rejects a session cookie found on a status outside of the expected ones
+    ///
+    /// When enabled, such a response fails with `LoginError::UnexpectedStatus`.
+    /// Disabled by default.
+    pub fn reject_unexpected_status(mut self, reject: bool) -> Self {
+        self.reject_unexpected_status = reject;
+        self
+    }
+
+    /// Keeps the raw `Set-Cookie` header values of the login response
+    ///
+    /// When enabled, [`login_details`](Self::login_details) returns them in
+    /// [`LoginDetails::raw_set_cookies`](crate::LoginDetails::raw_set_cookies)
+    /// alongside the parsed session, for feeding them into other cookie
+    /// handling. Disabled by default.
+    pub fn capture_raw_set_cookies(mut self, capture: bool) -> Self {
+        self.capture_raw_set_cookies = capture;
+        self
+    }
+
+    /// Sets how many times a response without a session cookie is retried
+    ///
+    /// Niconico occasionally accepts the credentials but answers without the
+    /// session cookie, and an immediate retry then succeeds. Such a response
+    /// fails with `LoginError::UserSessionNotFound`, which is retried right
+    /// away up to this many times. This is separate from the network retries
+    /// of a [`RetryPolicy`](crate::RetryPolicy), as it covers a different kind
+    /// of failure.
+    ///
+    /// Defaults to 0. Enabling it sends the credentials again on every retry
+    /// and may mask a genuine change of the response that this crate fails to
+    /// parse, so keep the number small.
+    pub fn session_not_found_retries(mut self, retries: u32) -> Self {
+        self.session_not_found_retries = retries;
+        self
+    }
+
+    /// Keeps the beginning of the response body when the session cookie is missing
+    ///
+    /// When enabled, `LoginError::UserSessionNotFound` carries up to 1024
+    /// characters of the response body in
+    /// [`CookieDiagnostics::body_snippet`](crate::CookieDiagnostics::body_snippet),
+    /// which helps to understand a changed login flow or an interstitial page.
+    /// The credentials and anything resembling a token are replaced by
+    /// `[REDACTED]` first. Disabled by default.
+    pub fn capture_body_snippet(mut self, capture: bool) -> Self {
+        self.capture_body_snippet = capture;
+        self
+    }
+
+    /// Warns when the login sets the session without its secure counterpart
+    ///
+    /// When enabled, a login whose response did not set the
+    /// `user_session_secure` cookie reports
+    /// [`LoginWarning::SecureSessionMissing`] in
+    /// [`LoginDetails::warnings`](crate::LoginDetails::warnings), before an
+    /// HTTPS-only endpoint fails with a confusing `403 Forbidden`. The login
+    /// itself still succeeds. Disabled by default.
+    pub fn warn_on_missing_secure(mut self, warn: bool) -> Self {
+        self.warn_on_missing_secure = warn;
+        self
+    }
+
+    /// Rejects a session cookie that is not marked `Secure` and `HttpOnly`
+    ///
+    /// Niconico sets both flags on the `user_session` cookie, so a cookie
+    /// without them points to a downgraded or spoofed response. When enabled,
+    /// such a login fails with `LoginError::InsecureCookie` instead of
+    /// returning the session. Disabled by default.
+    pub fn require_secure_cookie(mut self, require: bool) -> Self {
+        self.require_secure_cookie = require;
+        self
+    }
+
+    /// Checks that the login issues a session for the given account
+    ///
+    /// A login whose session belongs to another account fails with
+    /// `LoginError::AccountMismatch`, which catches credentials mixed up
+    /// between accounts. The account is identified with
+    /// [`fetch_user_id`](crate::fetch_user_id), which costs another request
+    /// only when the ID is not embedded in the session token.
+    pub fn expected_user_id(mut self, user_id: UserId) -> Self {
+        self.expected_user_id = Some(user_id);
+        self
+    }
+
+    /// Sets whether the issued session is checked with Niconico before the login succeeds
+    ///
+    /// When enabled, one request to `nvapi.nicovideo.jp/v1/users/me` confirms
+    /// that the session is logged in, and a rejected session fails the login
+    /// with `LoginError::VerificationFailed`. This catches a cookie that looks
+    /// like a session but is not usable, at the cost of a request. Defaults to
+    /// `false`.
+    pub fn verify_after_login(mut self, verify: bool) -> Self {
+        self.verify_after_login = verify;
+        self
+    }
+
+    /// Follows up to `max_hops` redirects after the login response, collecting their cookies
+    ///
+    /// Some flows only set all cookies of the session over a chain of
+    /// redirects. When enabled, the redirect of a successful login response
+    /// is followed with the session, and the cookies set on the way are
+    /// folded into it, until a response does not redirect or `max_hops`
+    /// redirects were followed; stopping at the limit on a redirect is
+    /// reported as `LoginWarning::RedirectLimitReached`. Every hop must stay
+    /// within the [`redirect_hosts`](Self::redirect_hosts), or the login fails
+    /// with `LoginError::UnexpectedRedirectHost`, and must use `https`, or it
+    /// fails with `LoginError::InsecureRedirect`, before the session is sent
+    /// there; plain `http` is only allowed to the loopback interface, for
+    /// tests. Each hop only receives the cookies scoped to it, and relative
+    /// redirects are resolved against the URL of the previous hop. Disabled
+    /// by default, which follows no redirect.
+    pub fn follow_redirects(mut self, max_hops: u32) -> Self {
+        self.max_redirects = max_hops;
+        self
+    }
+
+    /// Sets the hosts that [`follow_redirects`](Self::follow_redirects) may lead to
+    ///
+    /// Subdomains of the hosts are allowed as well. Defaults to
+    /// `nicovideo.jp`, so a redirect elsewhere can never exfiltrate the
+    /// session.
+    pub fn redirect_hosts<I>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.redirect_hosts = hosts.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Registers a callback deciding whether to follow each redirect
+    ///
+    /// The callback is called before every hop of
+    /// [`follow_redirects`](Self::follow_redirects), once the host was found
+    /// to be allowed. Returning `ControlFlow::Break(())` stops following, and
+    /// the login returns the session with the cookies collected so far.
+    pub fn on_redirect<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&RedirectHop) -> ControlFlow<()> + Send + Sync + 'static,
+    {
+        self.on_redirect = Some(RedirectCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Writes every login response to a file, for replaying it in tests
+    ///
+    /// The status, the headers and up to 1024 characters of the body are
+    /// written as a [`CapturedResponse`](crate::CapturedResponse), with the
+    /// cookie values, the credentials and anything resembling a token
+    /// replaced by `[REDACTED]`. Each response replaces the previous contents
+    /// of the file. This is a debugging aid: the body is read even when the
+    /// login would not need it, and a failure to write the file does not fail
+    /// the login and is only logged when the `tracing` feature is enabled.
+    pub fn capture_response_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.capture_response_to = Some(path.into());
+        self
+    }
+
+    /// Records the login response to a cassette file, or replays it from there
+    ///
+    /// Requires the `cassette` feature. A recorded login is replayed without
+    /// sending anything, which makes integration tests hermetic. The cassette
+    /// holds the response to the login request as a
+    /// [`CapturedResponse`](crate::CapturedResponse), with the cookie values,
+    /// the credentials and anything resembling a token replaced by
+    /// `[REDACTED]`, so a replayed session carries the token
+    /// `user_session_[REDACTED]`. Redirects after the login response are not
+    /// followed when replaying, while checks that send their own requests,
+    /// such as [`verify_after_login`](Self::verify_after_login), still do.
+    /// A cassette that cannot be read or written fails the login with
+    /// `LoginError::CassetteError`.
+    #[cfg(feature = "cassette")]
+    pub fn cassette(mut self, path: impl Into<PathBuf>, mode: crate::CassetteMode) -> Self {
+        self.cassette = Some(crate::cassette::Cassette::new(path.into(), mode));
+        self
+    }
+
+    /// Sets the `User-Agent` header of the login request
+    ///
+    /// Defaults to `toof-jp/niconico`. Changing it may help when Niconico
+    /// answers with `LoginError::Forbidden`.
+    pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.user_agent = user_agent;
+        self.client = None;
+        self
+    }
+
+    /// Stops this crate from setting any header of the login request
+    ///
+    /// The `User-Agent`, `Origin`, `Referer` and `Content-Type` headers are
+    /// then left to the caller, who sets them with a
+    /// [`request_hook`](Self::request_hook), e.g. to replicate the headers of
+    /// a specific browser. Omitting a header that Niconico requires, such as
+    /// `Content-Type`, makes the login fail. reqwest still adds the headers
+    /// of the HTTP protocol, like `Host` and `Content-Length`, as well as
+    /// `Accept: */*` unless the hook sets `Accept`. Redirects are still not
+    /// followed, so the session cookie is captured as usual.
+    pub fn no_default_headers(mut self) -> Self {
+        self.default_headers = false;
+        self.client = None;
+        self
+    }
+
+    /// Sets the `Origin` header of the login request, or omits it with `None`
+    ///
+    /// Defaults to `https://account.nicovideo.jp`, as sent by a browser
+    /// submitting the login form.
+    pub fn origin(mut self, origin: Option<HeaderValue>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Sets the `Referer` header of the login request, or omits it with `None`
+    ///
+    /// Defaults to `https://account.nicovideo.jp/login`, as sent by a browser
+    /// submitting the login form.
+    pub fn referer(mut self, referer: Option<HeaderValue>) -> Self {
+        self.referer = referer;
+        self
+    }
+
+    /// Measures where the time of the login is spent
+    ///
+    /// When enabled, [`login_details`](Self::login_details) returns the
+    /// measurements in
+    /// [`LoginDetails::timings`](crate::LoginDetails::timings). Disabled by
+    /// default, as resolving host names through a timing wrapper adds a
+    /// small overhead.
+    pub fn capture_timings(mut self, capture: bool) -> Self {
+        self.capture_timings = capture;
+        self
+    }
+
+    /// Fetches the profile of the account as part of [`login_full`](Self::login_full)
+    ///
+    /// Disabled by default, as it costs another request.
+    pub fn include_profile(mut self, include: bool) -> Self {
+        self.include_profile = include;
+        self
+    }
+
+    /// Fetches the CSRF token of the session as part of [`login_full`](Self::login_full)
+    ///
+    /// The token is stored on the session, from where
+    /// [`UserSession::apply_to`](crate::UserSession::apply_to) attaches it to
+    /// requests. See [`fetch_csrf_token`](crate::fetch_csrf_token) for the
+    /// endpoints that need it. Disabled by default, as it costs another
+    /// request.
+    pub fn include_csrf_token(mut self, include: bool) -> Self {
+        self.include_csrf_token = include;
+        self
+    }
+
+    /// Limits the bytes and time used by a whole login operation
+    ///
+    /// See [`LoginBudget`](crate::LoginBudget) for how this differs from the
+    /// per-request timeouts.
+    pub fn budget(mut self, budget: LoginBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sets a timeout for the whole login request
+    ///
+    /// Expiry is reported as `LoginError::Timeout`. No timeout is set by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self.client = None;
+        self
+    }
+
+    /// Sets a timeout for establishing the connection
+    ///
+    /// This bounds both the TCP connect and the TLS handshake, as reqwest does
+    /// not expose a separate TLS handshake timeout. See [`TimeoutPhase`](crate::TimeoutPhase)
+    /// for how the phase of an expired timeout is reported.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self.client = None;
+        self
+    }
+
+    /// Sets a timeout for each read of the response
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self.client = None;
+        self
+    }
+
+    /// Binds outgoing connections to a local address
+    ///
+    /// Multicast addresses are rejected with `LoginError::InvalidLocalAddress`
+    /// when logging in, and an address that cannot be bound, for example
+    /// because it is not assigned to this host, fails with
+    /// `LoginError::ClientError`.
+    pub fn local_address(mut self, address: IpAddr) -> Self {
+        self.local_address = Some(address);
+        self.client = None;
+        self
+    }
+
+    /// Sends the login through a proxy
+    ///
+    /// Without this option the proxies of the `HTTP_PROXY`, `HTTPS_PROXY` and
+    /// `ALL_PROXY` environment variables are used. A proxy in Japan is the
+    /// remedy for `LoginError::RegionBlocked`.
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self.client = None;
+        self
+    }
+
+    /// Sends the login through a SOCKS5 proxy, optionally authenticating to it
+    ///
+    /// `url` uses the `socks5` scheme, or `socks5h` to let the proxy resolve
+    /// host names, as Tor requires. Requires the `socks` feature, which
+    /// enables the `socks` feature of reqwest and its `tokio-socks`
+    /// dependency. This replaces any proxy set with [`proxy`](Self::proxy).
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The address of the proxy, e.g. `socks5h://127.0.0.1:9050`
+    /// * `credentials` - The username and password for the proxy, if it requires them
+    ///
+    /// # Returns
+    ///
+    /// Returns the builder, or `LoginError::ClientError` if the URL is not a
+    /// usable proxy address.
+    #[cfg(feature = "socks")]
+    pub fn socks5_proxy(
+        self,
+        mut url: Url,
+        credentials: Option<(String, SecretString)>,
+    ) -> Result<Self, LoginError> {
+        if let Some((username, password)) = credentials {
+            // Only URLs without a host reject credentials, and reqwest rejects those as well
+            let _ = url.set_username(&username);
+            let _ = url.set_password(Some(password.expose_secret()));
+        }
+        let proxy = reqwest::Proxy::all(url).map_err(LoginError::ClientError)?;
+        Ok(self.proxy(proxy))
+    }
+
+    /// Resolves host names with a custom resolver instead of the system one
+    ///
+    /// Without this option the system resolver is used, or reqwest's built-in
+    /// Hickory resolver when the `hickory-dns` feature is enabled.
+    pub fn dns_resolver<R: Resolve + 'static>(mut self, resolver: Arc<R>) -> Self {
+        self.dns_resolver = Some(DnsResolver(resolver));
+        self.client = None;
+        self
+    }
+
+    /// Sets which IP address families the login connection uses
+    ///
+    /// Defaults to [`IpFamily::DualStack`](crate::IpFamily::DualStack). Any
+    /// other choice resolves names with the system resolver, or the custom
+    /// [`dns_resolver`](Self::dns_resolver), even when the `hickory-dns`
+    /// feature is enabled. See [`IpFamily`](crate::IpFamily) for how
+    /// connections fall back between the families.
+    pub fn ip_family(mut self, ip_family: IpFamily) -> Self {
+        self.ip_family = ip_family;
+        self.client = None;
+        self
+    }
+
+    /// Registers an observer notified about the progress of the login
+    pub fn observer(mut self, observer: Arc<dyn LoginObserver>) -> Self {
+        self.observer = Some(SharedObserver(observer));
+        self
+    }
+
+    /// Sets the clock that the current time is read from
+    ///
+    /// The clock stamps the records passed to
+    /// [`LoginObserver::on_attempt`](crate::LoginObserver::on_attempt) and
+    /// decides when [`SessionManager`](crate::SessionManager) reports an
+    /// expiring session. Defaults to [`SystemClock`](crate::SystemClock);
+    /// tests can attach a [`FakeClock`](crate::FakeClock) instead.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = SharedClock(clock);
+        self
+    }
+
+    /// Waits for a rate limiter before sending every login request
+    ///
+    /// Attach the same limiter to the builders of all tasks to throttle all
+    /// of their logins together. Waiting counts against the
+    /// [`budget`](Self::budget) of the login, but not against the request
+    /// timeouts. Retries and the requests to every endpoint wait as well.
+    pub fn rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.rate_limiter = Some(SharedRateLimiter(rate_limiter));
+        self
+    }
+
+    /// Sends an `Idempotency-Key` header that stays the same across the retries of a login
+    ///
+    /// See [`IdempotencyKey`](crate::IdempotencyKey) for which requests share
+    /// a key and for the caveat that Niconico is not known to honor it. By
+    /// default no key is sent.
+    pub fn idempotency_key(mut self, idempotency_key: IdempotencyKey) -> Self {
+        self.idempotency_key = Some(idempotency_key);
+        self
+    }
+
+    /// Returns the idempotency key of a new logical login, if keys are sent
+    pub(crate) fn new_idempotency_key(&self) -> Option<HeaderValue> {
+        self.idempotency_key.as_ref().map(IdempotencyKey::for_login)
+    }
+
+    /// Caps how many logins talk to Niconico at the same time
+    ///
+    /// Every login sends its requests one after another, so this also caps
+    /// the concurrent connections to the login host, which matters for bursts
+    /// of logins that may trip connection-based limits. Unlike a
+    /// [`rate_limiter`](Self::rate_limiter), it does not space requests out.
+    /// The cap is shared by the clones of the builder, and a `max_connections`
+    /// of 0 is treated as 1. Waiting for a free connection counts against the
+    /// [`budget`](Self::budget) of the login. By default there is no cap.
+    /// [`login_many`](Self::login_many) logs in one account after another and
+    /// so uses a single connection either way; the cap matters for logins
+    /// spawned concurrently from clones of the builder.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.connection_limit = Some(Arc::new(Semaphore::new(max_connections.max(1))));
+        self
+    }
+
+    /// Registers an asynchronous hook that rewrites the login request before it is sent
+    ///
+    /// The hook receives the fully built request, after this crate has set
+    /// its own headers and body, so it can override any of them or sign the
+    /// request, e.g. for an authenticating gateway in front of Niconico. The
+    /// `User-Agent` header is only added afterwards if the hook did not set
+    /// one. An error returned by the hook aborts the login with
+    /// `LoginError::RequestHookError`.
+    ///
+    /// The hook is called for every attempt, including retries, but is not
+    /// applied by [`to_curl`](Self::to_curl).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use niconico::LoginBuilder;
+    /// use reqwest::header::{HeaderValue, AUTHORIZATION};
+    ///
+    /// let builder = LoginBuilder::new().request_hook(|mut request| async move {
+    ///     request
+    ///         .headers_mut()
+    ///         .insert(AUTHORIZATION, HeaderValue::from_static("Bearer gateway-token"));
+    ///     Ok(request)
+    /// });
+    /// ```
+    pub fn request_hook<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Request, HookError>> + Send + 'static,
+    {
+        self.request_hook = Some(RequestHook(Arc::new(move |request| {
+            Box::pin(hook(request))
+        })));
+        self
+    }
+
+    /// Registers a classifier for responses that the built-in detection does not recognize
+    ///
+    /// The built-in detection runs first. Responses without a session cookie
+    /// that it cannot explain, which would otherwise fail with
+    /// `LoginError::UserSessionNotFound` or `LoginError::UnexpectedHtmlResponse`,
+    /// are passed to the classifier after the retries of
+    /// [`session_not_found_retries`](Self::session_not_found_retries). It
+    /// returns the session or error to report, or `None` to keep the
+    /// built-in error. This keeps up with changes of Niconico's responses
+    /// without waiting for a release of this crate.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use niconico::{LoginBuilder, LoginError};
+    /// use reqwest::StatusCode;
+    ///
+    /// let builder = LoginBuilder::new().response_classifier(|response| {
+    ///     (response.status == StatusCode::OK && response.body.contains("メンテナンス"))
+    ///         .then_some(Err(LoginError::ServiceUnavailable))
+    /// });
+    /// ```
+    pub fn response_classifier<F>(mut self, classifier: F) -> Self
+    where
+        F: Fn(&UnrecognizedResponse) -> Option<LoginResult> + Send + Sync + 'static,
+    {
+        self.response_classifier = Some(ResponseClassifier(Arc::new(classifier)));
+        self
+    }
+
+    /// Sends the login request through a `reqwest-middleware` client
+    ///
+    /// The client's own configuration, such as its user agent, timeouts and
+    /// redirect policy, is used instead of the one held by this builder.
+    /// Redirects must be disabled on it for the session cookie to be captured
+    /// from the redirector response.
+    #[cfg(feature = "middleware")]
+    pub fn middleware_client(mut self, client: reqwest_middleware::ClientWithMiddleware) -> Self {
+        self.middleware_client = Some(client);
+        self
+    }
+
+    /// Connects to the login host ahead of the first login
+    ///
+    /// This resolves the host name, establishes the connection and completes
+    /// the TLS handshake with a `HEAD` request to the first login endpoint,
+    /// and keeps the connection pooled in the client of the returned builder,
+    /// so that the first [`login`](Self::login) does not pay for them.
+    ///
+    /// This only helps as long as the connection is reused: the pooled
+    /// connection is shared by the clones of the builder, but not by the
+    /// requests of [`get`](crate::get) and [`post`](crate::post), and it is
+    /// dropped when a setting of the client, such as a timeout or the user
+    /// agent, is changed afterwards, so call this last. It has no effect with
+    /// a [`middleware_client`](Self::middleware_client), and name resolution
+    /// is not measured by [`capture_timings`](Self::capture_timings) on a
+    /// prewarmed connection.
+    pub async fn prewarm(mut self) -> Result<Self, LoginError> {
+        #[cfg(feature = "middleware")]
+        if self.middleware_client.is_some() {
+            return Ok(self);
+        }
+
+        let client = self.build_client(None)?;
+        let mut url = self.login_urls().remove(0);
+        url.set_path("/");
+        url.set_query(None);
+        client.head(url).send().await.map_err(request_error)?;
+        self.client = Some(client);
+
+        Ok(self)
+    }
+
+    /// Attempts to log in to Niconico using the provided credentials
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The user credentials to use for login
+    ///
+    /// # Returns
+    ///
+    /// Returns a `LoginResult` which is either:
+    /// * `Ok(UserSession)` containing the session token on successful login
+    /// * `Err(LoginError)` containing the specific error that occurred
+    pub async fn login(&self, credentials: &Credentials) -> LoginResult {
+        self.login_details(credentials)
+            .await
+            .map(|details| details.user_session)
+    }
+
+    /// Logs in to several accounts one after another over a shared connection
+    ///
+    /// All logins are sent with the same client, so the connection to the
+    /// login host and its TLS session are established once and reused by the
+    /// following logins for as long as the server keeps the connection alive.
+    /// The client of a [`prewarm`](Self::prewarm)ed builder is used as is.
+    /// As on a prewarmed connection, name resolution is not measured by
+    /// [`capture_timings`](Self::capture_timings).
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The credentials of the accounts to log in to
+    ///
+    /// # Returns
+    ///
+    /// Returns the result of every login, in the order of the credentials. A
+    /// client that cannot be built fails every login with a copy of its error.
+    pub async fn login_many(&self, credentials: &[Credentials]) -> Vec<LoginResult> {
+        let mut builder = self.clone();
+        if builder.client.is_none() {
+            match self.build_client(None) {
+                Ok(client) => builder.client = Some(client),
+                Err(error) => {
+                    return credentials
+                        .iter()
+                        .map(|_| Err(error.clone_like()))
+                        .collect();
+                }
+            }
+        }
+
+        let mut results = Vec::with_capacity(credentials.len());
+        for credentials in credentials {
+            results.push(builder.login(credentials).await);
+        }
+        results
+    }
+
+    /// Attempts to log in, also reporting the account states noticed on the way
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The user credentials to use for login
+    ///
+    /// # Returns
+    ///
+    /// Returns either:
+    /// * `Ok(LoginDetails)` containing the session token and any warnings
+    /// * `Err(LoginError)` containing the specific error that occurred
+    pub async fn login_details(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<LoginDetails, LoginError> {
+        let budget = BudgetTracker::start(&self.budget);
+        let idempotency_key = self.new_idempotency_key();
+        self.login_details_within(credentials, &budget, idempotency_key.as_ref())
+            .await
+    }
+
+    /// Attempts to log in, counting the used resources against a budget
+    ///
+    /// The idempotency key is sent with every request of the logical login.
+    pub(crate) async fn login_details_within(
+        &self,
+        credentials: &Credentials,
+        budget: &BudgetTracker,
+        idempotency_key: Option<&HeaderValue>,
+    ) -> Result<LoginDetails, LoginError> {
+        credentials.validate(self.max_credential_length)?;
+        if credentials.password.is_token() && !self.exchanges_tokens() {
+            return Err(LoginError::InvalidCredentialFormat(
+                "password is a token, which Niconico only accepts through a proxy that exchanges it"
+                    .to_string(),
+            ));
+        }
+
+        let details = self
+            .login_at_any(credentials, budget, idempotency_key)
+            .await?;
+        if self.verify_after_login {
+            let client = self.session_client()?;
+            budget
+                .within(verify_session(&client, &details.user_session))
+                .await?;
+        }
+        if let Some(expected) = self.expected_user_id {
+            let client = self.session_client()?;
+            let actual = budget
+                .within(fetch_user_id_with(&client, &details.user_session))
+                .await?;
+            if actual != expected {
+                return Err(LoginError::AccountMismatch { expected, actual });
+            }
+        }
+
+        Ok(details)
+    }
+
+    /// Logs in at the first endpoint that does not fail with a retryable error
+    async fn login_at_any(
+        &self,
+        credentials: &Credentials,
+        budget: &BudgetTracker,
+        idempotency_key: Option<&HeaderValue>,
+    ) -> Result<LoginDetails, LoginError> {
+        let mut login_urls = self.login_urls();
+        if login_urls.len() == 1 {
+            return self
+                .login_at(login_urls.remove(0), credentials, budget, idempotency_key)
+                .await;
+        }
+
+        let mut errors = Vec::new();
+        for login_url in login_urls {
+            match self
+                .login_at(login_url, credentials, budget, idempotency_key)
+                .await
+            {
+                Err(error) if error.is_retryable() => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(%error, "login endpoint failed, trying the next one");
+                    errors.push(error);
+                }
+                result => return result,
+            }
+        }
+
+        Err(LoginError::AllEndpointsFailed(errors))
+    }
+
+    /// Sends the login request to a single endpoint, reporting the attempt to the observer
+    async fn login_at(
+        &self,
+        login_url: Url,
+        credentials: &Credentials,
+        budget: &BudgetTracker,
+        idempotency_key: Option<&HeaderValue>,
+    ) -> Result<LoginDetails, LoginError> {
+        let Some(observer) = self.observer_ref() else {
+            return self
+                .attempt_at(login_url, credentials, budget, idempotency_key, &mut None)
+                .await;
+        };
+
+        let timestamp = self.clock.0.now();
+        let started = Instant::now();
+        let mut remote_addr = None;
+        let result = self
+            .attempt_at(
+                login_url.clone(),
+                credentials,
+                budget,
+                idempotency_key,
+                &mut remote_addr,
+            )
+            .await;
+        observer.on_attempt(&AuditRecord {
+            timestamp,
+            outcome: audit::outcome(&result),
+            endpoint: login_url,
+            account: audit::mask_account(&credentials.mail_tel),
+            latency: started.elapsed(),
+            address_family: remote_addr.map(AddressFamily::of),
+        });
+
+        result
+    }
+
+    /// Sends the login request to a single endpoint
+    ///
+    /// Responses without a session cookie are retried as configured by
+    /// [`session_not_found_retries`](Self::session_not_found_retries). The
+    /// body of the last such response is checked for a CAPTCHA challenge.
+    /// The address of the last connection is stored in `remote_addr`.
+    async fn attempt_at(
+        &self,
+        login_url: Url,
+        credentials: &Credentials,
+        budget: &BudgetTracker,
+        idempotency_key: Option<&HeaderValue>,
+        remote_addr: &mut Option<SocketAddr>,
+    ) -> Result<LoginDetails, LoginError> {
+        let _connection = match &self.connection_limit {
+            Some(connection_limit) => Some(
+                budget
+                    .within(async {
+                        Ok(connection_limit
+                            .clone()
+                            .acquire_owned()
+                            .await
+                            .expect("the connection limit is never closed"))
+                    })
+                    .await?,
+            ),
+            None => None,
+        };
+        let started = Instant::now();
+        let dns_timer = self.capture_timings.then(DnsTimer::default);
+        let mut retries = 0;
+        loop {
+            budget.check_deadline()?;
+            let replayed = self.replayed()?;
+            let is_replay = replayed.is_some();
+            let (status, headers, mut body, time_to_first_byte) = match replayed {
+                Some(captured) => (
+                    captured.status,
+                    captured.headers,
+                    Body::Read(captured.body),
+                    Duration::ZERO,
+                ),
+                None => {
+                    let mut request = self.build_request_to(login_url.clone(), credentials);
+                    if let Some(idempotency_key) = idempotency_key {
+                        request
+                            .headers_mut()
+                            .insert(IDEMPOTENCY_KEY_HEADER, idempotency_key.clone());
+                    }
+                    if let Some(hook) = &self.request_hook {
+                        request = (hook.0)(request)
+                            .await
+                            .map_err(LoginError::RequestHookError)?;
+                    }
+                    if let Some(rate_limiter) = &self.rate_limiter {
+                        budget
+                            .within(async {
+                                rate_limiter.0.until_ready().await;
+                                Ok(())
+                            })
+                            .await?;
+                    }
+                    budget.add_bytes(request_size(&request))?;
+                    let sent = Instant::now();
+                    let res = budget
+                        .within(self.send(request, dns_timer.as_ref()))
+                        .await?;
+                    let time_to_first_byte = sent.elapsed();
+                    *remote_addr = res.remote_addr();
+                    budget.add_bytes(headers_size(res.headers()))?;
+
+                    let headers = res.headers().clone();
+                    (res.status(), headers, Body::Unread(res), time_to_first_byte)
+                }
+            };
+            #[cfg(feature = "cassette")]
+            if let Some(cassette) = self.cassette.as_ref().filter(|_| !is_replay) {
+                let read = body.read(budget).await?;
+                cassette.record(&CapturedResponse::scrubbed(
+                    status,
+                    &headers,
+                    &read,
+                    credentials,
+                ))?;
+                body = Body::Read(read);
+            }
+            if let Some(path) = &self.capture_response_to {
+                let read = body.read(budget).await?;
+                let captured = CapturedResponse::scrubbed(status, &headers, &read, credentials);
+                if let Err(_error) = captured.save(path) {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %_error, "failed to write the captured response");
+                }
+                body = Body::Read(read);
+            }
+            let json_session = if self.reads_json_session(status, &headers) {
+                let read = body.read(budget).await?;
+                let user_session = json_session(&read)?;
+                body = Body::Read(read);
+                user_session
+            } else {
+                None
+            };
+
+            let parsed = match json_session {
+                Some(user_session) => Ok(self.json_details(user_session, &headers)),
+                None => self.parse_response(status, &headers),
+            };
+            let mut details = match parsed {
+                Err(LoginError::UserSessionNotFound(_))
+                    if retries < self.session_not_found_retries =>
+                {
+                    retries += 1;
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(retries, "user session cookie not found, retrying");
+                    continue;
+                }
+                Err(LoginError::UserSessionNotFound(mut diagnostics)) => {
+                    let body = body.read(budget).await?;
+                    if let Some(challenge) = captcha::detect(&body) {
+                        return Err(LoginError::CaptchaRequired(challenge));
+                    }
+                    let classified = self.response_classifier.as_ref().and_then(|classifier| {
+                        (classifier.0)(&UnrecognizedResponse {
+                            status,
+                            headers: headers.clone(),
+                            body: body_snippet(&body, credentials),
+                        })
+                    });
+                    match classified {
+                        Some(result) => self.details(result?, &headers),
+                        None => {
+                            diagnostics.body_snippet = self
+                                .capture_body_snippet
+                                .then(|| body_snippet(&body, credentials));
+                            return Err(unrecognized(
+                                status,
+                                &headers,
+                                &body,
+                                credentials,
+                                diagnostics,
+                            ));
+                        }
+                    }
+                }
+                Err(LoginError::Forbidden) => {
+                    let body = body.read(budget).await?;
+                    if region::detect(&body) {
+                        return Err(LoginError::RegionBlocked);
+                    }
+                    return Err(LoginError::Forbidden);
+                }
+                result => result?,
+            };
+
+            if !is_replay {
+                self.follow(
+                    login_url.clone(),
+                    status,
+                    headers,
+                    &mut details,
+                    budget,
+                    dns_timer.as_ref(),
+                )
+                .await?;
+            }
+            details.timings = dns_timer.map(|dns_timer| LoginTimings {
+                dns: dns_timer.get(),
+                time_to_first_byte,
+                total: started.elapsed(),
+            });
+            return Ok(details);
+        }
+    }
+
+    /// Returns the response recorded in the cassette if the login is replayed
+    fn replayed(&self) -> Result<Option<CapturedResponse>, LoginError> {
+        #[cfg(feature = "cassette")]
+        if let Some(cassette) = &self.cassette {
+            return cassette.replay();
+        }
+        Ok(None)
+    }
+
+    /// Returns whether the login goes through something that may exchange a token for the password
+    fn exchanges_tokens(&self) -> bool {
+        #[cfg(feature = "middleware")]
+        if self.middleware_client.is_some() {
+            return true;
+        }
+        self.proxy.is_some() || !self.base_urls.is_empty()
+    }
+
+    /// Follows the redirects of a successful login response, folding their cookies into the session
+    ///
+    /// `url` is the URL of the login response, against which its relative
+    /// redirect is resolved.
+    async fn follow(
+        &self,
+        mut url: Url,
+        mut status: StatusCode,
+        mut headers: HeaderMap,
+        details: &mut LoginDetails,
+        budget: &BudgetTracker,
+        dns_timer: Option<&DnsTimer>,
+    ) -> Result<(), LoginError> {
+        if self.max_redirects == 0 {
+            return Ok(());
+        }
+        for hop in 1..=self.max_redirects + 1 {
+            let Some(location) =
+                redirect::location_from(&headers, &url).filter(|_| status.is_redirection())
+            else {
+                return Ok(());
+            };
+            if hop > self.max_redirects {
+                details.warnings.push(LoginWarning::RedirectLimitReached {
+                    location: redirect::scrubbed(&location),
+                });
+                return Ok(());
+            }
+            if !redirect::host_allowed(&location, &self.redirect_hosts) {
+                let host = location.host_str().unwrap_or_default().to_string();
+                return Err(LoginError::UnexpectedRedirectHost(host));
+            }
+            if !redirect::is_secure(&location) {
+                return Err(LoginError::InsecureRedirect(redirect::scrubbed(&location)));
+            }
+            if let Some(callback) = &self.on_redirect {
+                let location = redirect::scrubbed(&location);
+                if (callback.0)(&RedirectHop {
+                    hop,
+                    status,
+                    location,
+                })
+                .is_break()
+                {
+                    return Ok(());
+                }
+            }
+
+            budget.check_deadline()?;
+            let mut request = Request::new(Method::GET, location.clone());
+            if let Some(cookie_header) = details.user_session.cookie_header_for(&location)? {
+                request.headers_mut().insert(header::COOKIE, cookie_header);
+            }
+            budget.add_bytes(request_size(&request))?;
+            let res = budget.within(self.send(request, dns_timer)).await?;
+            budget.add_bytes(headers_size(res.headers()))?;
+
+            details
+                .user_session
+                .merge_cookies_with(res.headers(), self.clock_ref());
+            url = location;
+            status = res.status();
+            headers = res.headers().clone();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the login endpoints to try, in order
+    fn login_urls(&self) -> Vec<Url> {
+        if self.base_urls.is_empty() {
+            return vec![Url::parse(LOGIN_URL).expect("LOGIN_URL is a valid URL")];
+        }
+
+        self.base_urls
+            .iter()
+            .map(|base_url| {
+                base_url
+                    .join("login/redirector")
+                    .expect("a relative path resolves against any base URL")
+            })
+            .collect()
+    }
+
+    /// Renders the login request as an equivalent `curl` command for debugging
+    ///
+    /// The password is replaced by `***`, so the command can be shared in bug
+    /// reports but needs the real password substituted before it is run.
+    pub fn to_curl(&self, credentials: &Credentials) -> String {
+        let redacted = Credentials {
+            mail_tel: credentials.mail_tel.clone(),
+            password: "***".into(),
+        };
+        let request = self.build_request(&redacted);
+
+        let mut curl = format!(
+            "curl -X {} {}",
+            request.method(),
+            shell_quote(request.url().as_str())
+        );
+        let user_agent = self
+            .default_headers
+            .then_some((&header::USER_AGENT, &self.user_agent));
+        let headers = request.headers().iter().chain(user_agent);
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            curl += &format!(" -H {}", shell_quote(&format!("{name}: {value}")));
+        }
+        if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+            curl += &format!(
+                " --data-raw {}",
+                shell_quote(&String::from_utf8_lossy(body))
+            );
+        }
+
+        curl
+    }
+
+    /// Returns the budget of a whole login operation
+    pub(crate) fn budget_ref(&self) -> &LoginBudget {
+        &self.budget
+    }
+
+    /// Returns the clock that the current time is read from
+    pub(crate) fn clock_ref(&self) -> &dyn Clock {
+        self.clock.0.as_ref()
+    }
+
+    /// Returns a handle to the clock, for tasks outliving the builder
+    pub(crate) fn shared_clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock.0)
+    }
+
+    /// Returns whether `login_full` fetches the profile
+    pub(crate) fn includes_profile(&self) -> bool {
+        self.include_profile
+    }
+
+    /// Returns whether `login_full` fetches the CSRF token
+    pub(crate) fn includes_csrf_token(&self) -> bool {
+        self.include_csrf_token
+    }
+
+    /// Returns the registered observer, if any
+    pub(crate) fn observer_ref(&self) -> Option<&dyn LoginObserver> {
+        self.observer.as_ref().map(|observer| &*observer.0)
+    }
+
+    /// Sends a request with the configured client, timing name resolution with the timer
+    async fn send(
+        &self,
+        request: Request,
+        dns_timer: Option<&DnsTimer>,
+    ) -> Result<Response, LoginError> {
+        #[cfg(feature = "middleware")]
+        if let Some(client) = &self.middleware_client {
+            return client.execute(request).await.map_err(middleware_error);
+        }
+
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => self.build_client(dns_timer)?,
+        };
+        client.execute(request).await.map_err(|error| {
+            if self.local_address.is_some() && is_bind_error(&error) {
+                LoginError::ClientError(error)
+            } else {
+                request_error(error)
+            }
+        })
+    }
+
+    /// Extracts the user session from the login response
+    ///
+    /// The session cookie is looked up regardless of the response status, but
+    /// a status outside of the expected ones is reported. Without the cookie,
+    /// a redirect naming the reason, `429 Too Many Requests` or a server error
+    /// is reported instead of the missing cookie.
+    pub(crate) fn parse_response(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Result<LoginDetails, LoginError> {
+        if status == StatusCode::FORBIDDEN {
+            return Err(LoginError::Forbidden);
+        }
+        if region::blocks(status) {
+            return Err(LoginError::RegionBlocked);
+        }
+        if redirect::to_email_verification(headers) {
+            return Err(LoginError::EmailNotVerified);
+        }
+        if redirect::to_password_reset(headers) {
+            return Err(LoginError::PasswordResetRequired(
+                redirect::password_reset_url(headers),
+            ));
+        }
+        let password_change_required = redirect::to_password_change(headers);
+
+        let user_session = match parse_response_header_in(
+            headers,
+            self.max_set_cookies,
+            &self.session_cookie_domain,
+        ) {
+            Err(LoginError::UserSessionNotFound(_)) if password_change_required => {
+                return Err(LoginError::PasswordChangeRequired);
+            }
+            Err(LoginError::UserSessionNotFound(_)) if redirect::to_bad_credentials(headers) => {
+                return Err(LoginError::InvalidCredentials);
+            }
+            Err(LoginError::UserSessionNotFound(_)) if redirect::to_mfa(headers) => {
+                return Err(LoginError::MfaRequired);
+            }
+            Err(LoginError::UserSessionNotFound(_))
+                if status == StatusCode::SERVICE_UNAVAILABLE =>
+            {
+                return Err(LoginError::ServiceUnavailable);
+            }
+            Err(LoginError::UserSessionNotFound(_))
+                if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() =>
+            {
+                return Err(LoginError::UnexpectedStatus(status));
+            }
+            result => result?,
+        };
+
+        self.check_status(status)?;
+        if self.require_secure_cookie {
+            check_cookie_flags(&user_session)?;
+        }
+
+        let mut details = self.details(user_session, headers);
+        if password_change_required {
+            details.warnings.push(LoginWarning::PasswordChangeRequired);
+        }
+        if redirect::invalidates_previous_sessions(headers) {
+            details
+                .warnings
+                .push(LoginWarning::PreviousSessionInvalidated);
+        }
+        if self.warn_on_missing_secure && !details.user_session.has_secure() {
+            details.warnings.push(LoginWarning::SecureSessionMissing);
+        }
+
+        Ok(details)
+    }
+
+    /// Returns whether the session token is looked up in the body of a response
+    fn reads_json_session(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        let labelled_json = || {
+            headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .and_then(|content_type| content_type.split(';').next())
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+        };
+
+        status.is_success()
+            && match self.session_source {
+                SessionSource::Cookie => false,
+                SessionSource::Json => true,
+                SessionSource::Auto => labelled_json(),
+            }
+    }
+
+    /// Describes a login whose session token was read from a JSON body
+    fn json_details(&self, user_session: UserSession, headers: &HeaderMap) -> LoginDetails {
+        let mut details = self.details(user_session, headers);
+        if self.warn_on_missing_secure && !details.user_session.has_secure() {
+            details.warnings.push(LoginWarning::SecureSessionMissing);
+        }
+        details
+    }
+
+    /// Describes a login that issued a session, without warnings or timings
+    fn details(&self, user_session: UserSession, headers: &HeaderMap) -> LoginDetails {
+        let server_date = headers
+            .get(header::DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date).ok());
+        let now = self.clock_ref().now();
+        let clock_skew = server_date.map(|server_date| ClockSkew::between(now, server_date));
+
+        LoginDetails {
+            user_session: user_session.received_with(self.clock_ref()),
+            warnings: LoginWarning::client_deprecated(headers)
+                .into_iter()
+                .collect(),
+            location: redirect::location(headers),
+            raw_set_cookies: self
+                .capture_raw_set_cookies
+                .then(|| RawSetCookies::from_headers(headers)),
+            timings: None,
+            server_date,
+            clock_skew,
+        }
+    }
+
+    /// Checks the status of a response that carried the session cookie
+    fn check_status(&self, status: StatusCode) -> Result<(), LoginError> {
+        if self.success_statuses.contains(&status) {
+            return Ok(());
+        }
+        if self.reject_unexpected_status {
+            return Err(LoginError::UnexpectedStatus(status));
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::warn!(%status, "user session cookie found on an unexpected status");
+
+        Ok(())
+    }
+
+    /// Builds the login request for the first endpoint
+    fn build_request(&self, credentials: &Credentials) -> Request {
+        let login_url = self.login_urls().remove(0);
+        self.build_request_to(login_url, credentials)
+    }
+
+    /// Builds the login request carrying the encoded credentials
+    fn build_request_to(&self, mut url: Url, credentials: &Credentials) -> Request {
+        let (content_type, body) = self.encode_body(credentials);
+
+        if let Some(site) = self.site.param() {
+            url.query_pairs_mut().append_pair("site", site);
+        }
+        if let Some(next_url) = self.next_url.as_deref().or(self.site.next_url()) {
+            url.query_pairs_mut().append_pair("next_url", next_url);
+        }
+        let mut request = Request::new(Method::POST, url);
+        *request.body_mut() = Some(body.into());
+        if !self.default_headers {
+            return request;
+        }
+
+        let headers = request.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        if let Some(origin) = &self.origin {
+            headers.insert(header::ORIGIN, origin.clone());
+        }
+        if let Some(referer) = &self.referer {
+            headers.insert(header::REFERER, referer.clone());
+        }
+
+        request
+    }
+
+    /// Builds the HTTP client used for the login request
+    fn build_client(&self, dns_timer: Option<&DnsTimer>) -> Result<reqwest::Client, LoginError> {
+        self.client_builder(dns_timer)?
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(LoginError::ClientError)
+    }
+
+    /// Builds a client for the requests made with the session after the login
+    ///
+    /// It goes through the same proxy, local address, IP family and resolver
+    /// as the login, with the same timeouts and user agent, and follows
+    /// redirects like a default client.
+    pub(crate) fn session_client(&self) -> Result<reqwest::Client, LoginError> {
+        self.client_builder(None)?
+            .build()
+            .map_err(LoginError::ClientError)
+    }
+
+    /// Returns a client builder carrying the network settings of the builder
+    pub(crate) fn client_builder(
+        &self,
+        dns_timer: Option<&DnsTimer>,
+    ) -> Result<reqwest::ClientBuilder, LoginError> {
+        let mut builder = reqwest::Client::builder();
+        if self.default_headers {
+            builder = builder.user_agent(self.user_agent.clone());
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(address) = self.local_address {
+            if address.is_multicast() {
+                return Err(LoginError::InvalidLocalAddress(address));
+            }
+            builder = builder.local_address(address);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        let mut resolver = self.dns_resolver.clone().map(|resolver| resolver.0);
+        if self.ip_family != IpFamily::DualStack {
+            resolver = Some(Arc::new(FamilyResolver::new(resolver, self.ip_family)));
+        }
+        match (dns_timer, resolver) {
+            // Keep the built-in Hickory resolver rather than timing the system one
+            #[cfg(feature = "hickory-dns")]
+            (Some(_), None) => {}
+            (Some(dns_timer), resolver) => {
+                builder = builder.dns_resolver(Arc::new(dns_timer.resolver(resolver)));
+            }
+            (None, Some(resolver)) => {
+                builder = builder.dns_resolver(Arc::new(DnsResolver(resolver)))
+            }
+            (None, None) => {}
+        }
+
+        Ok(builder)
+    }
+
+    /// Encodes the credentials according to the configured body format
+    ///
+    /// Returns the value of the `Content-Type` header together with the body.
+    /// Form bodies percent-encode the UTF-8 bytes of the credentials and
+    /// declare the charset, so non-ASCII passwords are transmitted faithfully.
+    fn encode_body(&self, credentials: &Credentials) -> (&'static str, String) {
+        match self.body_format {
+            BodyFormat::Form => (
+                "application/x-www-form-urlencoded; charset=UTF-8",
+                form_urlencoded::Serializer::new(String::new())
+                    .append_pair(&self.mail_tel_field, &credentials.mail_tel)
+                    .append_pair(
+                        &self.password_field,
+                        credentials.password_to_send(self.trim_password),
+                    )
+                    .finish(),
+            ),
+            BodyFormat::Json => {
+                let mut body = serde_json::Map::new();
+                body.insert(
+                    self.mail_tel_field.clone(),
+                    credentials.mail_tel.as_str().into(),
+                );
+                body.insert(
+                    self.password_field.clone(),
+                    credentials.password_to_send(self.trim_password).into(),
+                );
+                (
+                    "application/json",
+                    serde_json::Value::from(body).to_string(),
+                )
+            }
+        }
+    }
+}
+
+/// The body of a login response, read at most once
+enum Body {
+    Unread(Response),
+    Read(String),
+}
+
+impl Body {
+    /// Reads the beginning of the body within the budget, unless it was read already
+    async fn read(self, budget: &BudgetTracker) -> Result<String, LoginError> {
+        match self {
+            Body::Unread(res) => {
+                let body = budget
+                    .within(async { Ok(read_body_prefix(res).await) })
+                    .await?;
+                budget.add_bytes(body.len())?;
+                Ok(body)
+            }
+            Body::Read(body) => Ok(body),
+        }
+    }
+}
+
+/// Reads the beginning of a response body, ignoring read errors
+async fn read_body_prefix(mut res: Response) -> String {
+    let mut body = Vec::new();
+    while body.len() < BODY_READ_LIMIT {
+        match res.chunk().await {
+            Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+            _ => break,
+        }
+    }
+    body.truncate(BODY_READ_LIMIT);
+
+    String::from_utf8_lossy(&body).into_owned()
+}
+
+/// Checks that the session cookie is marked `Secure` and `HttpOnly`
+fn check_cookie_flags(user_session: &UserSession) -> Result<(), LoginError> {
+    let cookie_str = user_session.user_session.expose_secret();
+    let missing: Vec<_> = ["Secure", "HttpOnly"]
+        .into_iter()
+        .filter(|flag| !cookie::flag(cookie_str, flag))
+        .collect();
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(LoginError::InsecureCookie(missing.join(" and "))),
+    }
+}
+
+/// Returns the session whose token a JSON login response carries, if any
+///
+/// A token with characters outside of the cookie octets of RFC 6265 is
+/// reported as `LoginError::InvalidSessionToken`, as it would otherwise add
+/// attributes or further cookies to the stored `Set-Cookie` value.
+fn json_session(body: &str) -> Result<Option<UserSession>, LoginError> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(body) else {
+        return Ok(None);
+    };
+    let Some(token) = value
+        .get(JSON_SESSION_FIELD)
+        .and_then(serde_json::Value::as_str)
+        .filter(|token| token.starts_with("user_session_"))
+    else {
+        return Ok(None);
+    };
+    if !token.bytes().all(is_cookie_octet) {
+        return Err(LoginError::InvalidSessionToken);
+    }
+    Ok(Some(UserSession::new(
+        format!("user_session={token}; Path=/").into(),
+    )))
+}
+
+/// Returns whether the byte may appear in a cookie value, as `cookie-octet` of RFC 6265
+fn is_cookie_octet(byte: u8) -> bool {
+    matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+/// Returns the error for a response without a session that nothing recognized
+///
+/// An HTML page answering with `200 OK` is reported as such, and anything
+/// else as a missing session cookie.
+fn unrecognized(
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &str,
+    credentials: &Credentials,
+    diagnostics: CookieDiagnostics,
+) -> LoginError {
+    if status == StatusCode::OK && is_html(headers) {
+        return LoginError::UnexpectedHtmlResponse(HtmlResponse {
+            title: html_title(body).map(|title| body_snippet(&title, credentials)),
+            snippet: body_snippet(body, credentials),
+        });
+    }
+    LoginError::UserSessionNotFound(diagnostics)
+}
+
+/// Returns whether a response declares an HTML body
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("text/html")
+        })
+}
+
+/// Returns the contents of the `<title>` element of an HTML page, with whitespace collapsed
+fn html_title(body: &str) -> Option<String> {
+    let lowercase = body.to_ascii_lowercase();
+    let start = lowercase.find("<title")?;
+    let start = start + lowercase[start..].find('>')? + 1;
+    let end = start + lowercase[start..].find("</title")?;
+
+    let title = body[start..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+/// Scrubs the credentials and token-like strings from a body and shortens it
+pub(crate) fn body_snippet(body: &str, credentials: &Credentials) -> String {
+    scrub(body, credentials)
+        .chars()
+        .take(BODY_SNIPPET_LENGTH)
+        .collect()
+}
+
+/// Replaces the credentials and token-like strings in a text by `[REDACTED]`
+pub(crate) fn scrub(body: &str, credentials: &Credentials) -> String {
+    let mut body = body.to_string();
+    for secret in [
+        credentials.mail_tel.as_str(),
+        credentials.password.expose_secret(),
+    ] {
+        if !secret.is_empty() {
+            body = body.replace(secret, "[REDACTED]");
+        }
+    }
+
+    let mut snippet = String::new();
+    let mut word = String::new();
+    for c in body.chars().chain([' ']) {
+        if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '%') {
+            word.push(c);
+            continue;
+        }
+        if word.len() >= TOKEN_LIKE_LENGTH {
+            snippet += "[REDACTED]";
+        } else {
+            snippet += &word;
+        }
+        word.clear();
+        snippet.push(c);
+    }
+    snippet.pop();
+
+    snippet
+}
+
+/// Error returned by a request hook
+pub type HookError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Future returned by a request hook
+type HookFuture = Pin<Box<dyn Future<Output = Result<Request, HookError>> + Send>>;
+
+/// A response that the built-in detection did not recognize
+///
+/// Passed to the classifier registered with
+/// [`LoginBuilder::response_classifier`].
+#[derive(Debug, Clone)]
+pub struct UnrecognizedResponse {
+    /// The status of the response
+    pub status: StatusCode,
+    /// The headers of the response
+    pub headers: HeaderMap,
+    /// The beginning of the body, with the credentials and token-like strings scrubbed
+    pub body: String,
+}
+
+/// Classifier of unrecognized responses
+type ClassifierFn = dyn Fn(&UnrecognizedResponse) -> Option<LoginResult> + Send + Sync;
+
+/// A response classifier shared by the clones of a `LoginBuilder`
+#[derive(Clone)]
+struct ResponseClassifier(Arc<ClassifierFn>);
+
+impl fmt::Debug for ResponseClassifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseClassifier(..)")
+    }
+}
+
+/// Callback deciding whether to follow a redirect
+type RedirectFn = dyn Fn(&RedirectHop) -> ControlFlow<()> + Send + Sync;
+
+/// A redirect callback shared by the clones of a `LoginBuilder`
+#[derive(Clone)]
+struct RedirectCallback(Arc<RedirectFn>);
+
+impl fmt::Debug for RedirectCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RedirectCallback(..)")
+    }
+}
+
+/// A request hook shared by the clones of a `LoginBuilder`
+#[derive(Clone)]
+struct RequestHook(Arc<dyn Fn(Request) -> HookFuture + Send + Sync>);
+
+impl fmt::Debug for RequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestHook(..)")
+    }
+}
+
+/// A custom DNS resolver shared by the clients built from a `LoginBuilder`
+#[derive(Clone)]
+struct DnsResolver(Arc<dyn Resolve>);
+
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DnsResolver(..)")
+    }
+}
+
+impl Resolve for DnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        self.0.resolve(name)
+    }
+}
+
+/// Quotes a string for use as a single POSIX shell word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Returns whether a request failed because the local address could not be bound
+fn is_bind_error(error: &reqwest::Error) -> bool {
+    let mut source = error.source();
+    while let Some(cause) = source {
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io_error.kind(),
+                std::io::ErrorKind::AddrNotAvailable | std::io::ErrorKind::AddrInUse
+            );
+        }
+        source = cause.source();
+    }
+
+    false
+}
+
+/// Converts an error returned by a middleware client into a `LoginError`
+#[cfg(feature = "middleware")]
+fn middleware_error(error: reqwest_middleware::Error) -> LoginError {
+    match error {
+        reqwest_middleware::Error::Reqwest(error) => request_error(error),
+        reqwest_middleware::Error::Middleware(error) => LoginError::NetworkError(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        assert_session_token_eq, fixture, serve, serve_keep_alive, serve_once, LOGIN_OK_RESPONSE,
+    };
+
+    fn credentials() -> Credentials {
+        Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "p\"ss".into(),
+        }
+    }
+
+    /// Tests that the default body format matches the plain login form
+    #[test]
+    fn test_encode_body_form() {
+        let (content_type, body) = LoginBuilder::new().encode_body(&credentials());
+
+        assert_eq!(
+            content_type,
+            "application/x-www-form-urlencoded; charset=UTF-8"
+        );
+        assert_eq!(body, "mail_tel=user%40example.com&password=p%22ss");
+    }
+
+    /// Tests that a multibyte password is sent as percent-encoded UTF-8
+    #[test]
+    fn test_encode_body_form_multibyte() {
+        let credentials = Credentials {
+            mail_tel: "ユーザー@example.com".to_string(),
+            password: "パスワード&=🔑 ".into(),
+        };
+
+        let request = LoginBuilder::new().build_request(&credentials);
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap();
+
+        assert!(body.is_ascii());
+        let fields: Vec<_> = form_urlencoded::parse(body).collect();
+        assert_eq!(
+            fields,
+            [
+                ("mail_tel".into(), "ユーザー@example.com".into()),
+                ("password".into(), "パスワード&=🔑 ".into()),
+            ]
+        );
+    }
+
+    /// Tests that the JSON body format escapes the credentials correctly
+    #[test]
+    fn test_encode_body_json() {
+        let (content_type, body) = LoginBuilder::new()
+            .body_format(BodyFormat::Json)
+            .encode_body(&credentials());
+
+        assert_eq!(content_type, "application/json");
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["mail_tel"], "user@example.com");
+        assert_eq!(value["password"], "p\"ss");
+    }
+
+    /// Tests that the login request is a POST carrying the content type of the body
+    #[test]
+    fn test_build_request() {
+        let request = LoginBuilder::new().build_request(&credentials());
+
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.url().as_str(), LOGIN_URL);
+        assert_eq!(
+            request.headers()[header::CONTENT_TYPE],
+            "application/x-www-form-urlencoded; charset=UTF-8"
+        );
+    }
+
+    fn session_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=user_session_123"),
+        );
+        headers
+    }
+
+    /// Tests that a session cookie on an unexpected status is accepted by default
+    #[test]
+    fn test_parse_response_unexpected_status_accepted() {
+        let result = LoginBuilder::new().parse_response(StatusCode::OK, &session_headers());
+        assert!(result.is_ok());
+    }
+
+    /// Tests that a session cookie on an unexpected status can be rejected
+    #[test]
+    fn test_parse_response_unexpected_status_rejected() {
+        let builder = LoginBuilder::new().reject_unexpected_status(true);
+
+        let result = builder.parse_response(StatusCode::OK, &session_headers());
+        assert!(matches!(
+            result,
+            Err(LoginError::UnexpectedStatus(StatusCode::OK))
+        ));
+
+        let result = builder.parse_response(StatusCode::FOUND, &session_headers());
+        assert!(result.is_ok());
+    }
+
+    /// Tests that the expected statuses can be changed
+    #[test]
+    fn test_parse_response_custom_success_statuses() {
+        let builder = LoginBuilder::new()
+            .success_statuses([StatusCode::OK])
+            .reject_unexpected_status(true);
+
+        let result = builder.parse_response(StatusCode::OK, &session_headers());
+        assert!(result.is_ok());
+    }
+
+    /// Tests that a session issued with the password change interstitial carries a warning
+    #[test]
+    fn test_parse_response_password_change_warning() {
+        let mut headers = session_headers();
+        headers.insert(
+            header::LOCATION,
+            HeaderValue::from_static("https://account.nicovideo.jp/password/change"),
+        );
+
+        let details = LoginBuilder::new()
+            .parse_response(StatusCode::FOUND, &headers)
+            .unwrap();
+        assert_eq!(details.warnings, [LoginWarning::PasswordChangeRequired]);
+    }
+
+    /// Tests that redirects are followed within the allowed hosts, collecting their cookies
+    #[tokio::test]
+    async fn test_follow_redirects() {
+        let (hop_url, _) = serve_once(
+            "HTTP/1.1 200 OK\r\nSet-Cookie: nicohistory=hop\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let login_response: &'static str = Box::leak(
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {hop_url}/hop?token=secret\r\nSet-Cookie: user_session=user_session_123\r\nContent-Length: 0\r\n\r\n"
+            )
+            .into_boxed_str(),
+        );
+        let (url, _) = serve(vec![login_response, login_response, login_response]).await;
+        let builder = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .follow_redirects(3);
+
+        let result = builder.login(&credentials()).await;
+        assert!(matches!(
+            result,
+            Err(LoginError::UnexpectedRedirectHost(host)) if host == "127.0.0.1"
+        ));
+
+        let hops = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = hops.clone();
+        let stopping = builder
+            .clone()
+            .redirect_hosts(["127.0.0.1"])
+            .on_redirect(move |hop| {
+                recorded.lock().unwrap().push(hop.location.to_string());
+                ControlFlow::Break(())
+            });
+        let user_session = stopping.login(&credentials()).await.unwrap();
+        assert!(user_session.cookie("nicohistory").is_none());
+        assert_eq!(*hops.lock().unwrap(), [format!("{hop_url}/hop")]);
+
+        let user_session = builder
+            .redirect_hosts(["127.0.0.1"])
+            .login(&credentials())
+            .await
+            .unwrap();
+        assert_session_token_eq(&user_session, "user_session_123");
+        assert!(user_session.cookie("nicohistory").is_some());
+    }
+
+    /// Tests that a hop without TLS is rejected before the session is sent, even to an allowed host
+    #[tokio::test]
+    async fn test_follow_redirects_insecure() {
+        let (url, _) = serve_once(
+            "HTTP/1.1 302 Found\r\nLocation: http://www.nicovideo.jp/my?token=secret\r\nSet-Cookie: user_session=user_session_123; Domain=.nicovideo.jp\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .follow_redirects(3)
+            .login(&credentials())
+            .await;
+        assert!(matches!(
+            result,
+            Err(LoginError::InsecureRedirect(location)) if location.as_str() == "http://www.nicovideo.jp/my"
+        ));
+    }
+
+    /// Tests that later hops resolve relative redirects against the previous hop and report the limit
+    #[tokio::test]
+    async fn test_follow_redirects_relative_and_limit() {
+        let (hop_url, requests) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /second\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 302 Found\r\nLocation: /third?token=secret\r\nContent-Length: 0\r\n\r\n",
+        ])
+        .await;
+        let login_response: &'static str = Box::leak(
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {hop_url}/first\r\nSet-Cookie: user_session=user_session_123\r\nContent-Length: 0\r\n\r\n"
+            )
+            .into_boxed_str(),
+        );
+        let (url, _) = serve_once(login_response).await;
+
+        let details = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .redirect_hosts(["127.0.0.1"])
+            .follow_redirects(2)
+            .login_details(&credentials())
+            .await
+            .unwrap();
+        assert_eq!(
+            details.warnings,
+            [LoginWarning::RedirectLimitReached {
+                location: Url::parse(&format!("{hop_url}/third")).unwrap(),
+            }]
+        );
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].starts_with("GET /first "));
+        assert!(requests[1].starts_with("GET /second "));
+        // The session cookie is scoped to account.nicovideo.jp
+        assert!(requests
+            .iter()
+            .all(|request| !request.contains("user_session")));
+    }
+
+    /// Tests that a session of another account than the expected one is rejected
+    #[tokio::test]
+    async fn test_login_expected_user_id() {
+        let response = "HTTP/1.1 302 Found\r\nSet-Cookie: user_session=user_session_111_abc\r\nContent-Length: 0\r\n\r\n";
+        let (url, _) = serve(vec![response, response]).await;
+        let builder = LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]);
+
+        let result = builder
+            .clone()
+            .expected_user_id(UserId(222))
+            .login(&credentials())
+            .await;
+        assert!(matches!(
+            result,
+            Err(LoginError::AccountMismatch {
+                expected: UserId(222),
+                actual: UserId(111),
+            })
+        ));
+
+        assert!(builder
+            .expected_user_id(UserId(111))
+            .login(&credentials())
+            .await
+            .is_ok());
+    }
+
+    /// Tests that a token is only sent through a proxy or custom endpoint
+    #[tokio::test]
+    async fn test_login_with_token() {
+        let credentials = Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: crate::Secret::Token("vault-token".into()),
+        };
+
+        let result = LoginBuilder::new().login(&credentials).await;
+        assert!(matches!(
+            result,
+            Err(LoginError::InvalidCredentialFormat(_))
+        ));
+
+        let (url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+        LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .login(&credentials)
+            .await
+            .unwrap();
+        assert!(request.await.unwrap().ends_with("password=vault-token"));
+    }
+
+    /// Tests that a session cookie without the security flags is rejected when required
+    #[test]
+    fn test_parse_response_require_secure_cookie() {
+        let builder = LoginBuilder::new().require_secure_cookie(true);
+
+        let result = builder.parse_response(StatusCode::FOUND, &session_headers());
+        let Err(LoginError::InsecureCookie(missing)) = result else {
+            panic!("expected an insecure cookie error");
+        };
+        assert_eq!(missing, "Secure and HttpOnly");
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=user_session_123; Path=/; secure; HttpOnly"),
+        );
+        assert!(builder.parse_response(StatusCode::FOUND, &headers).is_ok());
+        assert!(LoginBuilder::new()
+            .parse_response(StatusCode::FOUND, &session_headers())
+            .is_ok());
+    }
+
+    /// Tests that a missing secure cookie is only reported when enabled
+    #[test]
+    fn test_parse_response_secure_session_missing_warning() {
+        let builder = LoginBuilder::new().warn_on_missing_secure(true);
+
+        let details = builder
+            .parse_response(StatusCode::FOUND, &session_headers())
+            .unwrap();
+        assert_eq!(details.warnings, [LoginWarning::SecureSessionMissing]);
+
+        let mut headers = session_headers();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session_secure=abc; Secure"),
+        );
+        let details = builder.parse_response(StatusCode::FOUND, &headers).unwrap();
+        assert!(details.warnings.is_empty());
+
+        let details = LoginBuilder::new()
+            .parse_response(StatusCode::FOUND, &session_headers())
+            .unwrap();
+        assert!(details.warnings.is_empty());
+    }
+
+    /// Tests that the password change interstitial without a session is an error
+    #[test]
+    fn test_parse_response_password_change_error() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LOCATION,
+            HeaderValue::from_static("https://account.nicovideo.jp/password/change"),
+        );
+
+        let result = LoginBuilder::new().parse_response(StatusCode::FOUND, &headers);
+        assert!(matches!(result, Err(LoginError::PasswordChangeRequired)));
+    }
+
+    /// Tests that a local address that cannot be bound is reported as a client error
+    #[tokio::test]
+    async fn test_send_unassigned_local_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let result = LoginBuilder::new()
+            .local_address("192.0.2.1".parse().unwrap())
+            .send(Request::new(Method::GET, url), None)
+            .await;
+        assert!(matches!(result, Err(LoginError::ClientError(_))));
+    }
+
+    /// Tests that a multicast local address is rejected
+    #[test]
+    fn test_build_client_multicast_local_address() {
+        let result = LoginBuilder::new()
+            .local_address("224.0.0.1".parse().unwrap())
+            .build_client(None);
+        assert!(matches!(result, Err(LoginError::InvalidLocalAddress(_))));
+    }
+
+    /// Tests that the curl command never contains the real password
+    #[test]
+    fn test_to_curl_redacts_password() {
+        let credentials = Credentials {
+            mail_tel: "o'neil@example.com".to_string(),
+            password: "hunter2".into(),
+        };
+
+        let curl = LoginBuilder::new().to_curl(&credentials);
+
+        assert!(!curl.contains("hunter2"));
+        assert_eq!(
+            curl,
+            "curl -X POST 'https://account.nicovideo.jp/login/redirector' \
+             -H 'content-type: application/x-www-form-urlencoded; charset=UTF-8' \
+             -H 'origin: https://account.nicovideo.jp' \
+             -H 'referer: https://account.nicovideo.jp/login' \
+             -H 'user-agent: toof-jp/niconico' \
+             --data-raw 'mail_tel=o%27neil%40example.com&password=***'"
+        );
+    }
+
+    /// Tests that the Origin and Referer headers default to the account domain
+    #[test]
+    fn test_build_request_default_origin_referer() {
+        let request = LoginBuilder::new().build_request(&credentials());
+
+        assert_eq!(request.headers()[header::ORIGIN], ORIGIN);
+        assert_eq!(request.headers()[header::REFERER], REFERER);
+    }
+
+    /// Tests that the Origin and Referer headers can be overridden or omitted
+    #[test]
+    fn test_build_request_custom_origin_referer() {
+        let request = LoginBuilder::new()
+            .origin(Some(HeaderValue::from_static("https://example.com")))
+            .referer(None)
+            .build_request(&credentials());
+
+        assert_eq!(request.headers()[header::ORIGIN], "https://example.com");
+        assert!(!request.headers().contains_key(header::REFERER));
+    }
+
+    /// Tests that an account with an unverified email is reported as such
+    #[test]
+    fn test_parse_response_email_not_verified() {
+        let (status, headers) = fixture(include_str!("../tests/fixtures/email_not_verified.http"));
+
+        let result = LoginBuilder::new().parse_response(status, &headers);
+        assert!(matches!(result, Err(LoginError::EmailNotVerified)));
+    }
+
+    /// Tests that a notice about ended sessions is reported as a warning
+    #[test]
+    fn test_parse_response_previous_session_invalidated() {
+        let (status, headers) = fixture(include_str!(
+            "../tests/fixtures/previous_session_invalidated.http"
+        ));
+
+        let details = LoginBuilder::new()
+            .parse_response(status, &headers)
+            .unwrap();
+        assert_eq!(details.warnings, [LoginWarning::PreviousSessionInvalidated]);
+        assert!(LoginBuilder::new()
+            .parse_response(StatusCode::FOUND, &session_headers())
+            .unwrap()
+            .warnings
+            .is_empty());
+    }
+
+    /// Tests that a forced password reset is reported with the reset URL
+    #[test]
+    fn test_parse_response_password_reset_required() {
+        let (status, headers) = fixture(include_str!(
+            "../tests/fixtures/password_reset_required.http"
+        ));
+
+        let result = LoginBuilder::new().parse_response(status, &headers);
+        let Err(LoginError::PasswordResetRequired(Some(reset_url))) = result else {
+            panic!("expected PasswordResetRequired with a reset URL");
+        };
+        assert_eq!(reset_url.path(), "/password/reset");
+    }
+
+    /// Tests that the field names can be overridden for both body formats
+    #[test]
+    fn test_encode_body_custom_field_names() {
+        let builder = LoginBuilder::new().field_names("email", "passphrase");
+
+        let (_, body) = builder.encode_body(&credentials());
+        assert_eq!(body, "email=user%40example.com&passphrase=p%22ss");
+
+        let (_, body) = builder
+            .body_format(BodyFormat::Json)
+            .encode_body(&credentials());
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["email"], "user@example.com");
+        assert_eq!(value["passphrase"], "p\"ss");
+    }
+
+    /// Resolves every name to localhost
+    struct LocalhostResolver;
+
+    impl Resolve for LocalhostResolver {
+        fn resolve(&self, _: Name) -> Resolving {
+            let addrs: reqwest::dns::Addrs = Box::new(std::iter::once(([127, 0, 0, 1], 0).into()));
+            Box::pin(async move { Ok(addrs) })
+        }
+    }
+
+    /// Tests that host names are resolved with the custom resolver
+    #[tokio::test]
+    async fn test_send_custom_dns_resolver() {
+        let (url, request) = serve_once("HTTP/1.1 204 No Content\r\n\r\n").await;
+        let port = Url::parse(&url).unwrap().port().unwrap();
+        let url = Url::parse(&format!("http://niconico.test:{port}/")).unwrap();
+
+        let res = LoginBuilder::new()
+            .dns_resolver(Arc::new(LocalhostResolver))
+            .send(Request::new(Method::GET, url), None)
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(request.await.unwrap().contains("niconico.test"));
+    }
+
+    /// Tests that a 403 response is reported as forbidden
+    #[test]
+    fn test_parse_response_forbidden() {
+        let result = LoginBuilder::new().parse_response(StatusCode::FORBIDDEN, &HeaderMap::new());
+        assert!(matches!(result, Err(LoginError::Forbidden)));
+    }
+
+    /// Tests that an HTML page answering with 200 is reported with its scrubbed title
+    #[tokio::test]
+    async fn test_login_unexpected_html_response() {
+        let (url, _) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 72\r\n\r\n<html><head><TITLE>\n  Hello user@example.com\n</TITLE></head>Sorry</html>",
+        )
+        .await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .login(&credentials())
+            .await;
+
+        let Err(LoginError::UnexpectedHtmlResponse(page)) = result else {
+            panic!("expected UnexpectedHtmlResponse");
+        };
+        assert_eq!(page.title.as_deref(), Some("Hello [REDACTED]"));
+        assert!(page.snippet.contains("Sorry"));
+        assert!(!page.snippet.contains("user@example.com"));
+    }
+
+    /// Tests that the title is only extracted from a complete title element
+    #[test]
+    fn test_html_title() {
+        assert_eq!(
+            html_title("<title lang=\"ja\">ニコニコ\t動画</title>").as_deref(),
+            Some("ニコニコ 動画")
+        );
+        assert_eq!(html_title("<title></title>"), None);
+        assert_eq!(html_title("<title>unterminated"), None);
+        assert_eq!(html_title("no title"), None);
+    }
+
+    /// Tests that a 451 response is reported as a region block
+    #[test]
+    fn test_parse_response_region_blocked() {
+        let result = LoginBuilder::new()
+            .parse_response(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS, &HeaderMap::new());
+        assert!(matches!(result, Err(LoginError::RegionBlocked)));
+    }
+
+    /// Tests that a batch reuses one connection where separate logins open one each
+    #[tokio::test]
+    async fn test_login_many_reuses_connection() {
+        let batch = [credentials(), credentials(), credentials()];
+
+        let (url, connections) = serve_keep_alive(LOGIN_OK_RESPONSE, 3).await;
+        let builder = LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]);
+        for credentials in &batch {
+            builder.login(credentials).await.unwrap();
+        }
+        assert_eq!(connections.await.unwrap(), 3);
+
+        let (url, connections) = serve_keep_alive(LOGIN_OK_RESPONSE, 3).await;
+        let results = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .login_many(&batch)
+            .await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(connections.await.unwrap(), 1);
+    }
+
+    /// Tests that a client that cannot be built fails every login without sending any
+    #[tokio::test]
+    async fn test_login_many_client_error() {
+        let results = LoginBuilder::new()
+            .local_address("224.0.0.1".parse().unwrap())
+            .login_many(&[credentials(), credentials()])
+            .await;
+
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(LoginError::InvalidLocalAddress(_)))));
+    }
+
+    /// Tests that the classifier maps unrecognized responses but not recognized ones
+    #[tokio::test]
+    async fn test_response_classifier() {
+        let builder = |url: &str| {
+            LoginBuilder::new()
+                .base_urls([Url::parse(url).unwrap()])
+                .response_classifier(|response| match response.headers.get("x-state")?.to_str() {
+                    Ok("maintenance") => Some(Err(LoginError::ServiceUnavailable)),
+                    Ok("issued") => Some(Ok(UserSession::new(
+                        "user_session=user_session_classified".into(),
+                    ))),
+                    _ => None,
+                })
+        };
+
+        let (url, _) =
+            serve_once("HTTP/1.1 200 OK\r\nX-State: maintenance\r\nContent-Length: 0\r\n\r\n")
+                .await;
+        let result = builder(&url).login(&credentials()).await;
+        assert!(matches!(result, Err(LoginError::ServiceUnavailable)));
+
+        let (url, _) =
+            serve_once("HTTP/1.1 200 OK\r\nX-State: issued\r\nContent-Length: 0\r\n\r\n").await;
+        let user_session = builder(&url).login(&credentials()).await.unwrap();
+        assert_session_token_eq(&user_session, "user_session_classified");
+
+        let (url, _) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let result = builder(&url).login(&credentials()).await;
+        assert!(matches!(result, Err(LoginError::UserSessionNotFound(_))));
+
+        let (url, _) =
+            serve_once("HTTP/1.1 403 Forbidden\r\nX-State: issued\r\nContent-Length: 0\r\n\r\n")
+                .await;
+        let result = builder(&url).login(&credentials()).await;
+        assert!(matches!(result, Err(LoginError::Forbidden)));
+    }
+
+    /// Counts the requests it lets through
+    #[derive(Default)]
+    struct CountingRateLimiter(std::sync::atomic::AtomicUsize);
+
+    impl RateLimiter for CountingRateLimiter {
+        fn until_ready(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    /// Tests that every login request, retries included, waits for the rate limiter
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 302 Found\r\nContent-Length: 0\r\n\r\n",
+            LOGIN_OK_RESPONSE,
+        ])
+        .await;
+        let rate_limiter = Arc::new(CountingRateLimiter::default());
+
+        LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .session_not_found_retries(1)
+            .rate_limiter(rate_limiter.clone())
+            .login(&credentials())
+            .await
+            .unwrap();
+
+        assert_eq!(rate_limiter.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    /// Records the audit records it observes
+    #[derive(Default)]
+    struct AuditObserver(std::sync::Mutex<Vec<AuditRecord>>);
+
+    impl LoginObserver for AuditObserver {
+        fn on_attempt(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    /// Tests that every endpoint attempt is reported with its outcome and a masked account
+    #[tokio::test]
+    async fn test_login_audit_records() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let failing_url = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        let (url, _) = serve_once(LOGIN_OK_RESPONSE).await;
+        let observer = Arc::new(AuditObserver::default());
+
+        LoginBuilder::new()
+            .base_urls([Url::parse(&failing_url).unwrap(), Url::parse(&url).unwrap()])
+            .observer(observer.clone())
+            .login(&credentials())
+            .await
+            .unwrap();
+
+        let records = observer.0.lock().unwrap();
+        let outcomes: Vec<_> = records.iter().map(|record| record.outcome).collect();
+        assert_eq!(outcomes, ["network_error", "success"]);
+        assert_eq!(
+            records[1].endpoint.as_str(),
+            format!("{url}/login/redirector")
+        );
+        assert_eq!(records[1].account, "u***@example.com");
+        assert_eq!(records[1].address_family, Some(AddressFamily::Ipv4));
+        assert!(!format!("{records:?}").contains("user_session_123"));
+    }
+
+    /// Tests that a forbidden response explaining a region block is reported as one
+    #[tokio::test]
+    async fn test_login_region_blocked() {
+        let (url, _) = serve_once(
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 45\r\n\r\nThis service is not available in your region.",
+        )
+        .await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .login(&credentials())
+            .await;
+
+        assert!(matches!(result, Err(LoginError::RegionBlocked)));
+    }
+
+    /// Tests that the login is sent through the configured proxy
+    #[tokio::test]
+    async fn test_login_through_proxy() {
+        let (proxy_url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+
+        LoginBuilder::new()
+            .base_urls([Url::parse("http://niconico.test/").unwrap()])
+            .proxy(reqwest::Proxy::http(proxy_url).unwrap())
+            .login(&credentials())
+            .await
+            .unwrap();
+
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("POST http://niconico.test/login/redirector"));
+    }
+
+    /// Tests that the login is sent through an authenticated SOCKS5 proxy
+    #[cfg(feature = "socks")]
+    #[tokio::test]
+    async fn test_login_through_socks5_proxy() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("socks5h://{}", listener.local_addr().unwrap());
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+
+            // Select username and password authentication
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket.write_all(&[5, 2]).await.unwrap();
+            let read = socket.read(&mut buffer).await.unwrap();
+            let auth = buffer[..read].to_vec();
+            socket.write_all(&[1, 0]).await.unwrap();
+
+            // Accept the connect request and answer it as the login endpoint
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket
+                .write_all(LOGIN_OK_RESPONSE.as_bytes())
+                .await
+                .unwrap();
+            auth
+        });
+
+        LoginBuilder::new()
+            .base_urls([Url::parse("http://niconico.test/").unwrap()])
+            .socks5_proxy(
+                Url::parse(&proxy_url).unwrap(),
+                Some(("tunnel".to_string(), "s3cret".into())),
+            )
+            .unwrap()
+            .login(&credentials())
+            .await
+            .unwrap();
+
+        assert_eq!(proxy.await.unwrap(), b"\x01\x06tunnel\x06s3cret");
+    }
+
+    /// Tests that the user agent can be changed
+    #[test]
+    fn test_to_curl_custom_user_agent() {
+        let curl = LoginBuilder::new()
+            .user_agent(HeaderValue::from_static("Mozilla/5.0"))
+            .to_curl(&credentials());
+
+        assert!(curl.contains("-H 'user-agent: Mozilla/5.0'"));
+    }
+
+    /// Tests that the next URL is sent and the redirect target is returned
+    #[test]
+    fn test_next_url() {
+        let builder = LoginBuilder::new().next_url("/watch/sm9");
+
+        let request = builder.build_request(&credentials());
+        assert_eq!(request.url().query(), Some("next_url=%2Fwatch%2Fsm9"));
+
+        let mut headers = session_headers();
+        headers.insert(header::LOCATION, HeaderValue::from_static("/watch/sm9"));
+        let details = builder.parse_response(StatusCode::FOUND, &headers).unwrap();
+        assert_eq!(
+            details.location.unwrap().as_str(),
+            "https://account.nicovideo.jp/watch/sm9"
+        );
+    }
+
+    /// Tests that the login fails over to the next endpoint on a network error
+    #[tokio::test]
+    async fn test_base_urls_failover() {
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+        let (url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+
+        let user_session = LoginBuilder::new()
+            .base_urls([Url::parse(&unreachable).unwrap(), Url::parse(&url).unwrap()])
+            .login(&credentials())
+            .await
+            .unwrap();
+
+        assert_session_token_eq(&user_session, "user_session_123");
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("POST /login/redirector "));
+    }
+
+    /// Tests that the errors of every endpoint are returned when all of them fail
+    #[tokio::test]
+    async fn test_base_urls_all_failed() {
+        let unreachable = || {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap()
+        };
+
+        let result = LoginBuilder::new()
+            .base_urls([unreachable(), unreachable()])
+            .login(&credentials())
+            .await;
+
+        let Err(LoginError::AllEndpointsFailed(errors)) = result else {
+            panic!("expected AllEndpointsFailed");
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(LoginError::is_retryable));
+    }
+
+    /// Tests that the raw Set-Cookie values are only captured when enabled
+    #[test]
+    fn test_capture_raw_set_cookies() {
+        let details = LoginBuilder::new()
+            .parse_response(StatusCode::FOUND, &session_headers())
+            .unwrap();
+        assert!(details.raw_set_cookies.is_none());
+
+        let details = LoginBuilder::new()
+            .capture_raw_set_cookies(true)
+            .parse_response(StatusCode::FOUND, &session_headers())
+            .unwrap();
+        assert_eq!(
+            details.raw_set_cookies.unwrap().expose(),
+            ["user_session=user_session_123"]
+        );
+    }
+
+    /// Tests that the session is read from a JSON body when selected or labelled
+    #[tokio::test]
+    async fn test_session_source_json() {
+        const JSON_RESPONSE: &str = concat!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\n",
+            "Content-Length: 36\r\n\r\n",
+            r#"{"user_session":"user_session_json"}"#
+        );
+
+        for (session_source, found) in [
+            (SessionSource::Cookie, false),
+            (SessionSource::Json, true),
+            (SessionSource::Auto, true),
+        ] {
+            let (url, _) = serve_once(JSON_RESPONSE).await;
+            let result = LoginBuilder::new()
+                .base_urls([Url::parse(&url).unwrap()])
+                .session_source(session_source)
+                .login(&credentials())
+                .await;
+            match found {
+                true => assert_session_token_eq(&result.unwrap(), "user_session_json"),
+                false => assert!(result.is_err(), "{session_source:?}"),
+            }
+        }
+
+        let (url, _) = serve_once(LOGIN_OK_RESPONSE).await;
+        let user_session = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .session_source(SessionSource::Json)
+            .login(&credentials())
+            .await
+            .unwrap();
+        assert_session_token_eq(&user_session, "user_session_123");
+    }
+
+    /// Tests that a JSON body without a valid token carries no session
+    #[test]
+    fn test_json_session() {
+        assert!(json_session(r#"{"user_session":"abc"}"#).unwrap().is_none());
+        assert!(json_session(r#"{"user_session":1}"#).unwrap().is_none());
+        assert!(json_session("<html></html>").unwrap().is_none());
+        assert_session_token_eq(
+            &json_session(r#"{"user_session":"user_session_1","id":1}"#)
+                .unwrap()
+                .unwrap(),
+            "user_session_1",
+        );
+    }
+
+    /// Tests that a JSON token that would inject cookie attributes is rejected
+    #[test]
+    fn test_json_session_rejects_non_cookie_octets() {
+        for body in [
+            r#"{"user_session":"user_session_1; Domain=.example.com"}"#,
+            r#"{"user_session":"user_session_1,other=1"}"#,
+            r#"{"user_session":"user_session_1\"x"}"#,
+            r#"{"user_session":"user_session_1\r\nSet-Cookie: a=b"}"#,
+            r#"{"user_session":"user_session_セッション"}"#,
+        ] {
+            assert!(
+                matches!(json_session(body), Err(LoginError::InvalidSessionToken)),
+                "{body}"
+            );
+        }
+    }
+
+    /// Tests that a trailing newline is removed from the password unless disabled
+    #[tokio::test]
+    async fn test_trim_password() {
+        let credentials = Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "password\n".into(),
+        };
+
+        for (trim, sent) in [(true, "password=password"), (false, "password=password%0A")] {
+            let (url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+            let result = LoginBuilder::new()
+                .base_urls([Url::parse(&url).unwrap()])
+                .trim_password(trim)
+                .login(&credentials)
+                .await;
+
+            assert!(result.is_ok());
+            assert!(request.await.unwrap().ends_with(sent), "{trim}");
+        }
+    }
+
+    /// Tests that the skew to the Date header is measured in both directions
+    #[test]
+    fn test_clock_skew() {
+        let server_date = httpdate::parse_http_date("Wed, 21 Oct 2037 07:28:00 GMT").unwrap();
+        let clock = Arc::new(crate::FakeClock::new(server_date + Duration::from_secs(90)));
+        let builder = LoginBuilder::new().clock(clock.clone());
+        let mut headers = session_headers();
+        headers.insert(
+            header::DATE,
+            HeaderValue::from_static("Wed, 21 Oct 2037 07:28:00 GMT"),
+        );
+
+        let details = builder.parse_response(StatusCode::FOUND, &headers).unwrap();
+        assert_eq!(details.server_date, Some(server_date));
+        assert_eq!(
+            details.clock_skew,
+            Some(ClockSkew::Ahead(Duration::from_secs(90)))
+        );
+
+        clock.set(server_date - Duration::from_secs(30));
+        let details = builder.parse_response(StatusCode::FOUND, &headers).unwrap();
+        assert_eq!(
+            details.clock_skew,
+            Some(ClockSkew::Behind(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            details.clock_skew.map(ClockSkew::duration),
+            Some(Duration::from_secs(30))
+        );
+
+        headers.insert(header::DATE, HeaderValue::from_static("yesterday"));
+        let details = builder.parse_response(StatusCode::FOUND, &headers).unwrap();
+        assert_eq!(details.server_date, None);
+        assert_eq!(details.clock_skew, None);
+    }
+
+    /// Tests that Max-Age counts from the time of the clock when the response arrived
+    #[test]
+    fn test_max_age_counts_from_clock() {
+        let received_at = httpdate::parse_http_date("Wed, 21 Oct 2037 07:28:00 GMT").unwrap();
+        let clock = Arc::new(crate::FakeClock::new(received_at));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=user_session_123; Max-Age=60; Path=/"),
+        );
+
+        let details = LoginBuilder::new()
+            .clock(clock.clone())
+            .parse_response(StatusCode::FOUND, &headers)
+            .unwrap();
+        let user_session = details.user_session;
+        assert_eq!(
+            user_session.expires_at(),
+            Some(received_at + Duration::from_secs(60))
+        );
+
+        clock.advance(Duration::from_secs(60));
+        assert!(user_session.is_expired_with(clock.as_ref()));
+    }
+
+    /// Tests that a session rotated by a followed redirect counts its Max-Age from the clock
+    #[tokio::test]
+    async fn test_follow_redirects_max_age_counts_from_clock() {
+        let (hop_url, _) = serve_once(
+            "HTTP/1.1 200 OK\r\nSet-Cookie: user_session=user_session_456; Max-Age=120\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let login_response: &'static str = Box::leak(
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {hop_url}/hop\r\nSet-Cookie: user_session=user_session_123; Max-Age=60\r\nContent-Length: 0\r\n\r\n"
+            )
+            .into_boxed_str(),
+        );
+        let (url, _) = serve_once(login_response).await;
+        let received_at = httpdate::parse_http_date("Wed, 21 Oct 2037 07:28:00 GMT").unwrap();
+
+        let user_session = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .redirect_hosts(["127.0.0.1"])
+            .follow_redirects(1)
+            .clock(Arc::new(crate::FakeClock::new(received_at)))
+            .login(&credentials())
+            .await
+            .unwrap();
+        assert_session_token_eq(&user_session, "user_session_456");
+        assert_eq!(
+            user_session.expires_at(),
+            Some(received_at + Duration::from_secs(120))
+        );
+    }
+
+    /// Tests that a response without a session cookie is retried when configured
+    #[tokio::test]
+    async fn test_session_not_found_retries() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 302 Found\r\nContent-Length: 0\r\n\r\n",
+            LOGIN_OK_RESPONSE,
+        ])
+        .await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .session_not_found_retries(1)
+            .login(&credentials())
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(requests.await.unwrap().len(), 2);
+    }
+
+    /// Tests that a response without a session cookie is not retried by default
+    #[tokio::test]
+    async fn test_session_not_found_not_retried_by_default() {
+        let (url, request) = serve_once("HTTP/1.1 302 Found\r\nContent-Length: 0\r\n\r\n").await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .login(&credentials())
+            .await;
+
+        assert!(matches!(result, Err(LoginError::UserSessionNotFound(_))));
+        request.await.unwrap();
+    }
+
+    /// Tests that the idempotency key is kept across retries and changes between logins
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 302 Found\r\nContent-Length: 0\r\n\r\n",
+            LOGIN_OK_RESPONSE,
+            LOGIN_OK_RESPONSE,
+        ])
+        .await;
+        let builder = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .session_not_found_retries(1)
+            .idempotency_key(IdempotencyKey::PerLogin);
+
+        assert!(builder.login(&credentials()).await.is_ok());
+        assert!(builder.login(&credentials()).await.is_ok());
+
+        let keys: Vec<_> = requests
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("idempotency-key: "))
+                    .expect("every request carries a key")
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    /// Tests that concurrent logins of clones share the connection cap
+    #[tokio::test]
+    async fn test_max_connections() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (url, _) = serve(vec![LOGIN_OK_RESPONSE; 3]).await;
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (hook_running, hook_max_running) = (running.clone(), max_running.clone());
+        let builder = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .max_connections(1)
+            .request_hook(move |request| {
+                let running = hook_running.clone();
+                let max_running = hook_max_running.clone();
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(request)
+                }
+            });
+
+        let logins: Vec<_> = (0..3)
+            .map(|_| {
+                let builder = builder.clone();
+                tokio::spawn(async move { builder.login(&credentials()).await })
+            })
+            .collect();
+        for login in logins {
+            assert!(login.await.unwrap().is_ok());
+        }
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+    }
+
+    /// Tests that the request hook runs after the crate's own headers are set
+    #[tokio::test]
+    async fn test_request_hook() {
+        let (url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .request_hook(|mut request| async move {
+                let headers = request.headers_mut();
+                headers.insert("x-gateway-signature", HeaderValue::from_static("signed"));
+                headers.insert(
+                    header::ORIGIN,
+                    HeaderValue::from_static("https://gateway.test"),
+                );
+                headers.insert(header::USER_AGENT, HeaderValue::from_static("gateway"));
+                Ok(request)
+            })
+            .login(&credentials())
+            .await;
+
+        assert!(result.is_ok());
+        let request = request.await.unwrap();
+        assert!(request.contains("x-gateway-signature: signed"));
+        assert!(request.contains("origin: https://gateway.test"));
+        assert!(request.contains("user-agent: gateway"));
+        assert!(!request.contains(USER_AGENT));
+    }
+
+    /// Tests that an error of the request hook aborts the login
+    #[tokio::test]
+    async fn test_request_hook_error() {
+        let result = LoginBuilder::new()
+            .request_hook(|_| async { Err("no gateway token".into()) })
+            .login(&credentials())
+            .await;
+
+        assert!(matches!(result, Err(LoginError::RequestHookError(_))));
+    }
+
+    /// Tests that the body snippet hides the credentials and token-like strings
+    #[test]
+    fn test_body_snippet_scrubs_secrets() {
+        let body = format!(
+            "<input value=\"user@example.com\"><input value='p\"ss'>token=user_session_0123456789abcdef0123 ok{}",
+            "x ".repeat(1000)
+        );
+
+        let snippet = body_snippet(&body, &credentials());
+
+        assert!(snippet.starts_with(
+            "<input value=\"[REDACTED]\"><input value='[REDACTED]'>token=[REDACTED] ok"
+        ));
+        assert!(!snippet.contains("user_session_"));
+        assert_eq!(snippet.chars().count(), BODY_SNIPPET_LENGTH);
+    }
+
+    /// Tests that the body snippet is only captured when enabled
+    #[tokio::test]
+    async fn test_capture_body_snippet() {
+        const RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Length: 21\r\n\r\n<h1>Interstitial</h1>";
+        let login = |builder: LoginBuilder| async move {
+            let (url, _) = serve_once(RESPONSE).await;
+            let result = builder
+                .base_urls([Url::parse(&url).unwrap()])
+                .login(&credentials())
+                .await;
+            let Err(LoginError::UserSessionNotFound(diagnostics)) = result else {
+                panic!("expected UserSessionNotFound");
+            };
+            diagnostics.body_snippet
+        };
+
+        assert_eq!(login(LoginBuilder::new()).await, None);
+        assert_eq!(
+            login(LoginBuilder::new().capture_body_snippet(true))
+                .await
+                .as_deref(),
+            Some("<h1>Interstitial</h1>")
+        );
+    }
+
+    /// Tests that the byte budget stops the login before sending an oversized request
+    #[tokio::test]
+    async fn test_budget_bytes_exceeded() {
+        let result = LoginBuilder::new()
+            .budget(LoginBudget {
+                max_bytes: Some(10),
+                max_duration: None,
+            })
+            .login(&credentials())
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(LoginError::BudgetExceeded(crate::BudgetLimit::Bytes))
+        ));
+    }
+
+    /// Tests that the time budget cuts off a login that does not complete in time
+    #[tokio::test]
+    async fn test_budget_duration_exceeded() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .budget(LoginBudget {
+                max_bytes: None,
+                max_duration: Some(Duration::from_millis(100)),
+            })
+            .login(&credentials())
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(LoginError::BudgetExceeded(crate::BudgetLimit::Duration))
+        ));
+    }
+
+    /// Tests that the site presets send their site parameter and next URL
+    #[test]
+    fn test_site() {
+        let request = LoginBuilder::new()
+            .site(Site::Live)
+            .build_request(&credentials());
+        assert_eq!(
+            request.url().query(),
+            Some("site=nicolive&next_url=https%3A%2F%2Flive.nicovideo.jp%2F")
+        );
+
+        let request = LoginBuilder::new()
+            .site(Site::Channel)
+            .next_url("/ch2525")
+            .build_request(&credentials());
+        assert_eq!(
+            request.url().query(),
+            Some("site=nicochannel&next_url=%2Fch2525")
+        );
+    }
+
+    /// Tests that a CAPTCHA page instead of the session cookie is reported as such
+    #[tokio::test]
+    async fn test_login_captcha_required() {
+        let (url, _) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Length: 52\r\n\r\n<div class=\"g-recaptcha\" data-sitekey=\"key\"></div>",
+        )
+        .await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .login(&credentials())
+            .await;
+
+        let Err(LoginError::CaptchaRequired(challenge)) = result else {
+            panic!("expected CaptchaRequired");
+        };
+        assert_eq!(challenge.provider, crate::CaptchaProvider::RecaptchaV2);
+        assert_eq!(challenge.site_key.as_deref(), Some("key"));
+    }
+
+    /// Tests that the timings are only measured when enabled
+    #[tokio::test]
+    async fn test_capture_timings() {
+        let login = |builder: LoginBuilder| async move {
+            let (url, _) = serve_once(LOGIN_OK_RESPONSE).await;
+            let port = Url::parse(&url).unwrap().port().unwrap();
+            let url = Url::parse(&format!("http://localhost:{port}/")).unwrap();
+            builder
+                .base_urls([url])
+                .dns_resolver(Arc::new(LocalhostResolver))
+                .login_details(&credentials())
+                .await
+                .unwrap()
+                .timings
+        };
+
+        assert_eq!(login(LoginBuilder::new()).await, None);
+
+        let timings = login(LoginBuilder::new().capture_timings(true))
+            .await
+            .unwrap();
+        assert!(timings.dns.is_some());
+        assert!(timings.time_to_first_byte <= timings.total);
+    }
+
+    /// Tests that the login reuses the connection established by prewarming
+    #[tokio::test]
+    async fn test_prewarm_reuses_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // Both requests must arrive on the only accepted connection
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut methods = Vec::new();
+            for response in [
+                "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+                LOGIN_OK_RESPONSE,
+            ] {
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                methods.push(request.split(' ').next().unwrap().to_string());
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            methods
+        });
+
+        let builder = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .prewarm()
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), builder.login(&credentials()))
+            .await
+            .unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(server.await.unwrap(), ["HEAD", "POST"]);
+    }
+
+    /// Tests that no header is set by the crate when the defaults are disabled
+    #[tokio::test]
+    async fn test_no_default_headers() {
+        let (url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .no_default_headers()
+            .login(&credentials())
+            .await;
+
+        assert!(result.is_ok());
+        let request = request.await.unwrap().to_lowercase();
+        for name in ["user-agent", "origin", "referer", "content-type"] {
+            assert!(
+                !request.contains(&format!("\r\n{name}:")),
+                "{name} in {request}"
+            );
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Process-wide session cache
+//!
+//! [`cached_login`] keeps the sessions it obtains in a cache shared by the
+//! whole process, so that repeated logins for the same account reuse a
+//! session that is still valid instead of hitting Niconico again.
+//!
+//! # Security
+//!
+//! Cached sessions live in memory until they expire, are replaced, or the
+//! cache is cleared with [`clear_session_cache`]. Any code in the process that
+//! calls [`cached_login`] with the same email address or telephone number gets
+//! the cached session back, without the password being checked again. Only
+//! use the cache when every caller in the process is trusted with every
+//! account it logs in to, and clear it when the sessions are no longer needed.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, OnceLock};
+
+use crate::{Clock, Credentials, LoginBuilder, LoginResult, UserSession};
+
+/// Sessions keyed by a hash of the email address or telephone number
+///
+/// Each entry keeps the identifier it was stored for, so that two accounts
+/// whose identifiers hash to the same key never get each other's session.
+static SESSION_CACHE: OnceLock<Mutex<HashMap<u64, CacheEntry>>> = OnceLock::new();
+
+/// A cached session together with the account identifier it belongs to
+struct CacheEntry {
+    mail_tel: String,
+    user_session: UserSession,
+}
+
+/// Logs in to Niconico, reusing a cached session for the same account
+///
+/// The cache is keyed by a hash of `mail_tel`, and a session is only reused
+/// for the exact `mail_tel` it was obtained for; the password is never part
+/// of the key. Expired sessions are discarded and replaced by a fresh login.
+/// Failed logins are not cached.
+///
+/// # Arguments
+///
+/// * `credentials` - The user credentials to use for login
+///
+/// # Returns
+///
+/// Returns a `LoginResult` which is either:
+/// * `Ok(UserSession)` containing the cached or freshly obtained session
+/// * `Err(LoginError)` containing the specific error that occurred
+pub async fn cached_login(credentials: Credentials) -> LoginResult {
+    LoginBuilder::new().cached_login(&credentials).await
+}
+
+impl LoginBuilder {
+    /// Logs in with the builder, reusing a session of the process-wide cache
+    ///
+    /// This shares the cache of [`cached_login`], and reads whether a cached
+    /// session has expired from the [`clock`](Self::clock) of the builder.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The user credentials to use for login
+    ///
+    /// # Returns
+    ///
+    /// Returns a `LoginResult` which is either:
+    /// * `Ok(UserSession)` containing the cached or freshly obtained session
+    /// * `Err(LoginError)` containing the specific error that occurred
+    pub async fn cached_login(&self, credentials: &Credentials) -> LoginResult {
+        if let Some(user_session) = cached(&credentials.mail_tel, self.clock_ref()) {
+            return Ok(user_session);
+        }
+
+        let user_session = self.login(credentials).await?;
+        store(&credentials.mail_tel, user_session.clone());
+
+        Ok(user_session)
+    }
+}
+
+/// Removes every session from the process-wide cache
+pub fn clear_session_cache() {
+    if let Some(cache) = SESSION_CACHE.get() {
+        lock(cache).clear();
+    }
+}
+
+/// Returns the cached session of an account, unless it has expired according to the clock
+fn cached(mail_tel: &str, clock: &dyn Clock) -> Option<UserSession> {
+    let key = cache_key(mail_tel);
+    let mut cache = lock(SESSION_CACHE.get()?);
+    match cache.get(&key) {
+        Some(entry) if entry.mail_tel != mail_tel => None,
+        Some(entry) if !entry.user_session.is_expired_with(clock) => {
+            Some(entry.user_session.clone())
+        }
+        Some(_) => {
+            cache.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Stores the session of an account, replacing any previous one for its key
+fn store(mail_tel: &str, user_session: UserSession) {
+    store_at(cache_key(mail_tel), mail_tel, user_session);
+}
+
+/// Stores the session of an account under a key
+fn store_at(key: u64, mail_tel: &str, user_session: UserSession) {
+    let cache = SESSION_CACHE.get_or_init(Default::default);
+    lock(cache).insert(
+        key,
+        CacheEntry {
+            mail_tel: mail_tel.to_string(),
+            user_session,
+        },
+    );
+}
+
+/// Locks the cache, recovering it if a previous holder panicked
+fn lock(
+    cache: &Mutex<HashMap<u64, CacheEntry>>,
+) -> std::sync::MutexGuard<'_, HashMap<u64, CacheEntry>> {
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Hashes the account identifier into a cache key
+fn cache_key(mail_tel: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    mail_tel.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_session_token_eq, serve_once, LOGIN_OK_RESPONSE};
+    use crate::SystemClock;
+
+    /// Tests that a stored session is returned until it expires
+    #[test]
+    fn test_cached_respects_expiry() {
+        let mail_tel = "cache-test@example.com";
+
+        store(
+            mail_tel,
+            UserSession::new("user_session=user_session_123".into()),
+        );
+        assert!(cached(mail_tel, &SystemClock).is_some());
+
+        store(
+            mail_tel,
+            UserSession::new(
+                "user_session=user_session_123; Expires=Wed, 21 Oct 2015 07:28:00 GMT".into(),
+            ),
+        );
+        assert!(cached(mail_tel, &SystemClock).is_none());
+    }
+
+    /// Tests that a session is not handed to another account whose key collides
+    #[test]
+    fn test_cached_ignores_colliding_account() {
+        let mail_tel = "cache-collision-test@example.com";
+        let other = "cache-collision-other@example.com";
+        store_at(
+            cache_key(other),
+            mail_tel,
+            UserSession::new("user_session=user_session_123".into()),
+        );
+
+        assert!(cached(other, &SystemClock).is_none());
+    }
+
+    /// Tests that a session is refreshed once a fake clock passes its expiry
+    #[test]
+    fn test_cached_refreshes_after_fake_clock_passes_expiry() {
+        let mail_tel = "cache-clock-test@example.com";
+        let user_session = UserSession::new(
+            "user_session=user_session_123; Expires=Wed, 21 Oct 2037 07:28:00 GMT".into(),
+        );
+        let clock = crate::FakeClock::new(user_session.expires_at().unwrap());
+        clock.set(clock.now() - std::time::Duration::from_secs(1));
+        store(mail_tel, user_session);
+
+        assert!(cached(mail_tel, &clock).is_some());
+        clock.advance(std::time::Duration::from_secs(1));
+        assert!(cached(mail_tel, &clock).is_none());
+        assert!(cached(mail_tel, &SystemClock).is_none());
+    }
+
+    /// Tests that the builder logs in again once its fake clock passes the expiry
+    #[tokio::test]
+    async fn test_builder_cached_login_follows_clock() {
+        let credentials = Credentials {
+            mail_tel: "cache-builder-clock-test@example.com".to_string(),
+            password: "password".into(),
+        };
+        let user_session = UserSession::new(
+            "user_session=user_session_cached; Expires=Wed, 21 Oct 2037 07:28:00 GMT".into(),
+        );
+        let clock = std::sync::Arc::new(crate::FakeClock::new(
+            user_session.expires_at().unwrap() - std::time::Duration::from_secs(1),
+        ));
+        store(&credentials.mail_tel, user_session);
+        let (url, _) = serve_once(LOGIN_OK_RESPONSE).await;
+        let builder = LoginBuilder::new()
+            .base_urls([reqwest::Url::parse(&url).unwrap()])
+            .clock(clock.clone());
+
+        let user_session = builder.cached_login(&credentials).await.unwrap();
+        assert_session_token_eq(&user_session, "user_session_cached");
+
+        clock.advance(std::time::Duration::from_secs(1));
+        let user_session = builder.cached_login(&credentials).await.unwrap();
+        assert_session_token_eq(&user_session, "user_session_123");
+    }
+}
@@ -0,0 +1,157 @@
+//! Detection of CAPTCHA challenges in the login response
+
+use std::fmt;
+
+/// Provider of a CAPTCHA challenge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaProvider {
+    /// Google reCAPTCHA v2, the checkbox or image challenge
+    RecaptchaV2,
+    /// Google reCAPTCHA v3, the invisible score-based challenge
+    RecaptchaV3,
+    /// hCaptcha
+    HCaptcha,
+}
+
+impl fmt::Display for CaptchaProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CaptchaProvider::RecaptchaV2 => "reCAPTCHA v2",
+            CaptchaProvider::RecaptchaV3 => "reCAPTCHA v3",
+            CaptchaProvider::HCaptcha => "hCaptcha",
+        })
+    }
+}
+
+/// A CAPTCHA challenge that Niconico answered the login with
+///
+/// This carries what a solving service needs to produce a response token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptchaChallenge {
+    /// Provider of the challenge
+    pub provider: CaptchaProvider,
+    /// The public site key of the challenge, if found in the page
+    pub site_key: Option<String>,
+    /// The action name of a reCAPTCHA v3 challenge, if found in the page
+    pub action: Option<String>,
+}
+
+impl fmt::Display for CaptchaChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.provider.fmt(f)?;
+        if let Some(action) = &self.action {
+            write!(f, ", action {action}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Detects a CAPTCHA challenge in the body of a login response
+///
+/// hCaptcha is recognized by its `h-captcha` widget or script, reCAPTCHA v3
+/// by its script loaded with a `render` site key or by `grecaptcha.execute`,
+/// and reCAPTCHA v2 by its `g-recaptcha` widget or script. The site key is
+/// read from the `data-sitekey` attribute or the `render` parameter.
+pub(crate) fn detect(body: &str) -> Option<CaptchaChallenge> {
+    if body.contains("h-captcha") || body.contains("hcaptcha.com") {
+        return Some(CaptchaChallenge {
+            provider: CaptchaProvider::HCaptcha,
+            site_key: quoted_after(body, "data-sitekey="),
+            action: None,
+        });
+    }
+
+    let render_key = value_after(body, "recaptcha/api.js?render=")
+        .filter(|render| render != "explicit" && render != "onload");
+    if render_key.is_some() || body.contains("grecaptcha.execute") {
+        return Some(CaptchaChallenge {
+            provider: CaptchaProvider::RecaptchaV3,
+            site_key: render_key.or_else(|| quoted_after(body, "data-sitekey=")),
+            action: quoted_after(body, "action:").or_else(|| quoted_after(body, "data-action=")),
+        });
+    }
+
+    if body.contains("g-recaptcha") || body.contains("recaptcha/api.js") {
+        return Some(CaptchaChallenge {
+            provider: CaptchaProvider::RecaptchaV2,
+            site_key: quoted_after(body, "data-sitekey="),
+            action: None,
+        });
+    }
+
+    None
+}
+
+/// Returns the quoted string following the first occurrence of `marker`
+fn quoted_after(body: &str, marker: &str) -> Option<String> {
+    let rest = body[body.find(marker)? + marker.len()..].trim_start();
+    let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    let rest = &rest[1..];
+
+    Some(rest[..rest.find(quote)?].to_string()).filter(|value| !value.is_empty())
+}
+
+/// Returns the URL parameter value following the first occurrence of `marker`
+fn value_after(body: &str, marker: &str) -> Option<String> {
+    let rest = &body[body.find(marker)? + marker.len()..];
+    let end = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-')))
+        .unwrap_or(rest.len());
+
+    Some(rest[..end].to_string()).filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a reCAPTCHA v2 widget is detected with its site key
+    #[test]
+    fn test_detect_recaptcha_v2() {
+        let body = r#"<script src="https://www.google.com/recaptcha/api.js" async defer></script>
+            <div class="g-recaptcha" data-sitekey="6Lc_v2key"></div>"#;
+
+        assert_eq!(
+            detect(body),
+            Some(CaptchaChallenge {
+                provider: CaptchaProvider::RecaptchaV2,
+                site_key: Some("6Lc_v2key".to_string()),
+                action: None,
+            })
+        );
+    }
+
+    /// Tests that a reCAPTCHA v3 script is detected with its site key and action
+    #[test]
+    fn test_detect_recaptcha_v3() {
+        let body = r#"<script src="https://www.google.com/recaptcha/api.js?render=6Lc_v3key"></script>
+            <script>grecaptcha.execute('6Lc_v3key', {action: 'login'})</script>"#;
+
+        assert_eq!(
+            detect(body),
+            Some(CaptchaChallenge {
+                provider: CaptchaProvider::RecaptchaV3,
+                site_key: Some("6Lc_v3key".to_string()),
+                action: Some("login".to_string()),
+            })
+        );
+    }
+
+    /// Tests that an hCaptcha widget is detected with its site key
+    #[test]
+    fn test_detect_hcaptcha() {
+        let body = r#"<script src="https://js.hcaptcha.com/1/api.js"></script>
+            <div class="h-captcha" data-sitekey='10000000-ffff'></div>"#;
+
+        let challenge = detect(body).unwrap();
+        assert_eq!(challenge.provider, CaptchaProvider::HCaptcha);
+        assert_eq!(challenge.site_key.as_deref(), Some("10000000-ffff"));
+    }
+
+    /// Tests that a page without a CAPTCHA is not reported as one
+    #[test]
+    fn test_detect_none() {
+        assert_eq!(detect("<h1>Log in</h1>"), None);
+        assert_eq!(detect(""), None);
+    }
+}
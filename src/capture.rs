@@ -0,0 +1,250 @@
+//! Capturing login responses for replaying them in tests
+
+use std::io;
+use std::path::Path;
+
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+
+use crate::builder::{body_snippet, scrub};
+use crate::{cookie, Credentials, LoginBuilder, LoginResult};
+
+/// Placeholder that replaces the value of a captured cookie
+const REDACTED: &str = "[REDACTED]";
+
+/// A login response with its secrets scrubbed, as captured by
+/// [`LoginBuilder::capture_response_to`]
+///
+/// The artifact is a text file holding the status line, one header per line,
+/// an empty line and the beginning of the body, so it can be reviewed before
+/// it is committed as a test fixture. Cookie values are replaced by
+/// `[REDACTED]`, keeping the `user_session_` prefix of the session token, and
+/// the credentials and anything resembling a token are scrubbed from the
+/// other headers and the body.
+///
+/// # Examples
+///
+/// ```no_run
+/// use niconico::CapturedResponse;
+///
+/// let response = CapturedResponse::load("tests/fixtures/captured.http").unwrap();
+/// assert!(response.replay().is_ok());
+/// ```
+#[derive(Debug, Clone)]
+pub struct CapturedResponse {
+    /// The status of the response
+    pub status: StatusCode,
+    /// The headers of the response, scrubbed
+    pub headers: HeaderMap,
+    /// The beginning of the body, scrubbed
+    pub body: String,
+}
+
+impl CapturedResponse {
+    /// Captures a response, scrubbing the credentials and tokens from it
+    pub(crate) fn scrubbed(
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+        credentials: &Credentials,
+    ) -> Self {
+        let mut scrubbed = HeaderMap::new();
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = if name == header::SET_COOKIE {
+                cookie::split_folded(&value)
+                    .into_iter()
+                    .map(redact_cookie)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            } else {
+                scrub(&value, credentials)
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                scrubbed.append(name, value);
+            }
+        }
+
+        Self {
+            status,
+            headers: scrubbed,
+            body: body_snippet(body, credentials),
+        }
+    }
+
+    /// Parses a captured response from the text of its artifact
+    ///
+    /// # Returns
+    ///
+    /// Returns the response, or an error of kind `InvalidData` if the text
+    /// is not a valid artifact.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let (head, body) = text
+            .split_once("\n\n")
+            .unwrap_or((text.trim_end_matches('\n'), ""));
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(|status_line| status_line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid_data("missing or invalid status line"))?;
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_data("header line without a colon"))?;
+            headers.append(
+                HeaderName::from_bytes(name.trim().as_bytes()).map_err(invalid_data)?,
+                HeaderValue::from_str(value.trim()).map_err(invalid_data)?,
+            );
+        }
+
+        Ok(Self {
+            status,
+            headers,
+            body: body.to_string(),
+        })
+    }
+
+    /// Reads a captured response from a file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Writes the response to a file, replacing its contents
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    /// Returns the text of the artifact
+    pub fn to_text(&self) -> String {
+        let reason = self.status.canonical_reason().unwrap_or_default();
+        let mut text = format!("HTTP/1.1 {} {reason}\n", self.status.as_u16());
+        for (name, value) in &self.headers {
+            text += &format!("{name}: {}\n", String::from_utf8_lossy(value.as_bytes()));
+        }
+        text + "\n" + &self.body
+    }
+
+    /// Feeds the response through the checks of a default [`LoginBuilder`]
+    ///
+    /// # Returns
+    ///
+    /// Returns the session, whose token is `user_session_[REDACTED]`, or the
+    /// error that the login would have failed with based on the status and
+    /// headers.
+    pub fn replay(&self) -> LoginResult {
+        LoginBuilder::new()
+            .parse_response(self.status, &self.headers)
+            .map(|details| details.user_session)
+    }
+}
+
+/// Replaces the value of a cookie, keeping the prefix of a session token
+fn redact_cookie(cookie_str: &str) -> String {
+    let name = cookie::name(cookie_str);
+    let value = if cookie::value(cookie_str).starts_with("user_session_") {
+        format!("user_session_{REDACTED}")
+    } else {
+        REDACTED.to_string()
+    };
+    let attributes = cookie_str
+        .find(';')
+        .map_or("", |start| &cookie_str[start..]);
+
+    format!("{name}={value}{attributes}")
+}
+
+/// Returns an error for an invalid artifact
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_session_token_eq, serve_once};
+    use crate::LoginError;
+    use reqwest::Url;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "hunter2".into(),
+        }
+    }
+
+    /// Tests that a captured response keeps no secret and replays to the same outcome
+    #[test]
+    fn test_scrubbed_round_trip() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=user_session_12345; Path=/; Secure"),
+        );
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("nicosid=1700000000.123456789; Path=/"),
+        );
+        headers.append(
+            header::LOCATION,
+            HeaderValue::from_static("https://www.nicovideo.jp/?mail=user@example.com"),
+        );
+        let captured = CapturedResponse::scrubbed(
+            StatusCode::FOUND,
+            &headers,
+            "<p>user@example.com / hunter2</p>",
+            &credentials(),
+        );
+
+        let text = captured.to_text();
+        for secret in ["12345", "1700000000", "user@example.com", "hunter2"] {
+            assert!(!text.contains(secret), "{secret} in {text}");
+        }
+        assert!(text.starts_with("HTTP/1.1 302 Found\n"));
+        assert!(text.contains("set-cookie: user_session=user_session_[REDACTED]; Path=/; Secure\n"));
+
+        let loaded = CapturedResponse::parse(&text).unwrap();
+        assert_eq!(loaded.status, StatusCode::FOUND);
+        assert_eq!(loaded.body, "<p>[REDACTED] / [REDACTED]</p>");
+        assert_session_token_eq(&loaded.replay().unwrap(), "user_session_[REDACTED]");
+    }
+
+    /// Tests that text without a valid status line is rejected
+    #[test]
+    fn test_parse_invalid() {
+        let error = CapturedResponse::parse("not a response\n").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Tests that a login writes the artifact of its response
+    #[tokio::test]
+    async fn test_capture_response_to() {
+        let (url, _) = serve_once(concat!(
+            "HTTP/1.1 302 Found\r\n",
+            "Location: https://account.nicovideo.jp/login?message=email_not_verified\r\n",
+            "Content-Length: 7\r\n\r\n",
+            "hunter2"
+        ))
+        .await;
+        let path =
+            std::env::temp_dir().join(format!("niconico-capture-{}.http", std::process::id()));
+
+        let result = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .capture_response_to(&path)
+            .login(&credentials())
+            .await;
+        let captured = CapturedResponse::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(LoginError::EmailNotVerified)));
+        assert_eq!(captured.body, "[REDACTED]");
+        assert!(matches!(
+            captured.replay(),
+            Err(LoginError::EmailNotVerified)
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+//! Recording the login response to a cassette and replaying it offline
+
+use std::path::PathBuf;
+
+use crate::{CapturedResponse, LoginError};
+
+/// Whether [`LoginBuilder::cassette`](crate::LoginBuilder::cassette) records or replays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Sends the login request and writes its response to the cassette,
+    /// replacing a previous recording
+    Record,
+    /// Reads the response from the cassette without sending anything,
+    /// failing with `LoginError::CassetteError` if it cannot be read
+    Replay,
+    /// Replays the cassette if the file exists, and records it otherwise
+    Auto,
+}
+
+/// A cassette file holding the response of one login
+#[derive(Debug, Clone)]
+pub(crate) struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+}
+
+impl Cassette {
+    /// Creates a cassette stored at the path
+    pub(crate) fn new(path: PathBuf, mode: CassetteMode) -> Self {
+        Self { path, mode }
+    }
+
+    /// Returns the recorded response if the login is replayed from the cassette
+    pub(crate) fn replay(&self) -> Result<Option<CapturedResponse>, LoginError> {
+        let replays = match self.mode {
+            CassetteMode::Record => false,
+            CassetteMode::Replay => true,
+            CassetteMode::Auto => self.path.exists(),
+        };
+        if !replays {
+            return Ok(None);
+        }
+
+        CapturedResponse::load(&self.path)
+            .map(Some)
+            .map_err(LoginError::CassetteError)
+    }
+
+    /// Writes a response to the cassette
+    pub(crate) fn record(&self, captured: &CapturedResponse) -> Result<(), LoginError> {
+        captured.save(&self.path).map_err(LoginError::CassetteError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{assert_session_token_eq, serve_once};
+    use crate::{CassetteMode, Credentials, LoginBuilder, LoginError};
+    use reqwest::Url;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "hunter2".into(),
+        }
+    }
+
+    /// Tests that a recorded login is replayed without a server and keeps no secret
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("niconico-cassette-{}.http", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (url, _) = serve_once(
+            "HTTP/1.1 302 Found\r\nSet-Cookie: user_session=user_session_12345; Path=/\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let builder = LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]);
+
+        let result = builder
+            .clone()
+            .cassette(&path, CassetteMode::Replay)
+            .login(&credentials())
+            .await;
+        assert!(matches!(result, Err(LoginError::CassetteError(_))));
+
+        let recorded = builder
+            .clone()
+            .cassette(&path, CassetteMode::Auto)
+            .login(&credentials())
+            .await;
+        assert_session_token_eq(&recorded.unwrap(), "user_session_12345");
+        let cassette = std::fs::read_to_string(&path).unwrap();
+        assert!(!cassette.contains("12345"));
+        assert!(!cassette.contains("hunter2"));
+
+        // The server answered once and is gone, so these can only be replays
+        for mode in [CassetteMode::Auto, CassetteMode::Replay] {
+            let replayed = builder
+                .clone()
+                .cassette(&path, mode)
+                .login(&credentials())
+                .await;
+            assert_session_token_eq(&replayed.unwrap(), "user_session_[REDACTED]");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+//! The source of the current time
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A source of the current wall-clock time
+///
+/// Expiry checks, such as [`UserSession::is_expired_with`](crate::UserSession::is_expired_with),
+/// and the timestamps reported to observers read the time from a clock.
+/// [`SystemClock`] is used unless another clock is attached with
+/// [`LoginBuilder::clock`](crate::LoginBuilder::clock), and [`FakeClock`]
+/// lets tests move the time across an expiry without sleeping. Timeouts,
+/// budgets and the backoff of retries measure elapsed time with the monotonic
+/// clock of Tokio instead, which tests can control with `tokio::time::pause`.
+pub trait Clock: Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> SystemTime;
+}
+
+/// The clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, SystemTime};
+/// use niconico::{FakeClock, UserSession};
+///
+/// let session = UserSession::new(
+///     "user_session=user_session_123; Expires=Wed, 21 Oct 2037 07:28:00 GMT".into(),
+/// );
+/// let clock = FakeClock::new(session.expires_at().unwrap() - Duration::from_secs(60));
+/// assert!(!session.is_expired_with(&clock));
+///
+/// clock.advance(Duration::from_secs(60));
+/// assert!(session.is_expired_with(&clock));
+/// ```
+#[derive(Debug)]
+pub struct FakeClock(Mutex<SystemTime>);
+
+impl FakeClock {
+    /// Creates a clock standing at the given time
+    pub fn new(now: SystemTime) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    /// Moves the clock forward
+    pub fn advance(&self, duration: Duration) {
+        *self.lock() += duration;
+    }
+
+    /// Moves the clock to the given time, which may lie in the past
+    pub fn set(&self, now: SystemTime) {
+        *self.lock() = now;
+    }
+
+    /// Locks the time, recovering it if a previous holder panicked
+    fn lock(&self) -> std::sync::MutexGuard<'_, SystemTime> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        *self.lock()
+    }
+}
+
+/// A clock shared by the clones of a `LoginBuilder`
+#[derive(Clone)]
+pub(crate) struct SharedClock(pub(crate) Arc<dyn Clock>);
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedClock(..)")
+    }
+}
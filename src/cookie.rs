@@ -0,0 +1,171 @@
+//! Helpers for reading `Set-Cookie` header values
+
+use std::cmp::Reverse;
+
+/// Splits a `Set-Cookie` header value that a proxy folded from several headers
+///
+/// Folded headers join the cookies with commas, but commas also appear in
+/// `Expires` dates. A comma only starts a new cookie when it is followed by
+/// a `name=` pair, which a date never is. An unfolded value is returned as
+/// the only element.
+pub(crate) fn split_folded(header_str: &str) -> Vec<&str> {
+    let mut cookies = Vec::new();
+    let mut start = 0;
+    for (index, _) in header_str.match_indices(',') {
+        if starts_cookie(&header_str[index + 1..]) {
+            cookies.push(header_str[start..index].trim());
+            start = index + 1;
+        }
+    }
+    cookies.push(header_str[start..].trim());
+
+    cookies
+}
+
+/// Returns whether the text following a comma starts with a `name=` pair
+fn starts_cookie(rest: &str) -> bool {
+    let segment = rest.split([';', ',']).next().unwrap_or_default();
+    segment.split_once('=').is_some_and(|(name, _)| {
+        let name = name.trim_start();
+        !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || c == '"')
+    })
+}
+
+/// Returns the name of the cookie set by a `Set-Cookie` header value
+pub(crate) fn name(cookie_str: &str) -> &str {
+    pair(cookie_str)
+        .split('=')
+        .next()
+        .unwrap_or_default()
+        .trim()
+}
+
+/// Returns the value of the cookie set by a `Set-Cookie` header value
+pub(crate) fn value(cookie_str: &str) -> &str {
+    pair(cookie_str)
+        .split_once('=')
+        .map_or("", |(_, value)| value.trim())
+}
+
+/// Returns the value of an attribute of a `Set-Cookie` header value
+///
+/// Attribute names are matched case-insensitively, as cookie attributes are.
+pub(crate) fn attribute<'a>(cookie_str: &'a str, name: &str) -> Option<&'a str> {
+    cookie_str.split(';').skip(1).find_map(|attribute| {
+        let (key, value) = attribute.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Returns whether a `Set-Cookie` header value carries a flag attribute, such as `Secure`
+pub(crate) fn flag(cookie_str: &str, name: &str) -> bool {
+    cookie_str
+        .split(';')
+        .skip(1)
+        .any(|attribute| attribute.trim().eq_ignore_ascii_case(name))
+}
+
+/// Returns whether a host, or a `Domain` attribute, is a domain or one of its subdomains
+///
+/// A leading dot of the host is ignored and the comparison is
+/// case-insensitive.
+pub(crate) fn in_domain(host: &str, domain: &str) -> bool {
+    let host = host.trim_start_matches('.').to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+    host == domain
+        || host
+            .strip_suffix(&domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+/// Returns whether a cookie is scoped to a domain or one of its subdomains
+///
+/// A cookie without a `Domain` attribute belongs to the host that set it,
+/// which is the host the request was sent to, so it is accepted as well.
+pub(crate) fn scoped_to(cookie_str: &str, domain: &str) -> bool {
+    attribute(cookie_str, "Domain").is_none_or(|cookie_domain| in_domain(cookie_domain, domain))
+}
+
+/// Ranks how broadly a cookie is scoped, with broader cookies ranking higher
+///
+/// A `Domain` attribute widens the cookie from the host that set it to the
+/// domain and its subdomains, a domain with fewer labels covers more hosts,
+/// and a shorter `Path` covers more paths.
+pub(crate) fn scope_rank(cookie_str: &str) -> (bool, Reverse<usize>, Reverse<usize>) {
+    let domain = attribute(cookie_str, "Domain").map(|domain| domain.trim_start_matches('.'));
+    let labels = domain.map_or(usize::MAX, |domain| domain.split('.').count());
+    let path = attribute(cookie_str, "Path").unwrap_or("/");
+
+    (domain.is_some(), Reverse(labels), Reverse(path.len()))
+}
+
+/// Returns the `name=value` pair of a `Set-Cookie` header value
+fn pair(cookie_str: &str) -> &str {
+    cookie_str.split(';').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that only the domain and its subdomains are in the domain
+    #[test]
+    fn test_scoped_to() {
+        assert!(scoped_to("a=1", "nicovideo.jp"));
+        assert!(scoped_to("a=1; Domain=.NicoVideo.jp", "nicovideo.jp"));
+        assert!(scoped_to(
+            "a=1; Domain=account.nicovideo.jp",
+            "nicovideo.jp"
+        ));
+        assert!(!scoped_to("a=1; Domain=evilnicovideo.jp", "nicovideo.jp"));
+        assert!(!scoped_to("a=1; Domain=evil.example", "nicovideo.jp"));
+    }
+
+    /// Tests that the name and value are split from the attributes
+    #[test]
+    fn test_name_and_value() {
+        let cookie_str = "nicosid=123.456; Max-Age=60; Path=/";
+
+        assert_eq!(name(cookie_str), "nicosid");
+        assert_eq!(value(cookie_str), "123.456");
+    }
+
+    /// Tests that attributes are found regardless of their case
+    #[test]
+    fn test_attribute() {
+        let cookie_str = "nicosid=123.456; max-age=60; Path=/";
+
+        assert_eq!(attribute(cookie_str, "Max-Age"), Some("60"));
+        assert_eq!(attribute(cookie_str, "path"), Some("/"));
+        assert_eq!(attribute(cookie_str, "Domain"), None);
+    }
+
+    /// Tests that flag attributes are found regardless of their case
+    #[test]
+    fn test_flag() {
+        let cookie_str = "nicosid=secure; secure; HttpOnly";
+
+        assert!(flag(cookie_str, "Secure"));
+        assert!(flag(cookie_str, "httponly"));
+        assert!(!flag("secure=1; Path=/", "Secure"));
+    }
+
+    /// Tests that folded headers are split on cookie boundaries but not on dates
+    #[test]
+    fn test_split_folded() {
+        let folded = "nicosid=123; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/, user_session=user_session_123; Expires=Thu, 22 Oct 2015 07:28:00 GMT, other=1";
+
+        assert_eq!(
+            split_folded(folded),
+            [
+                "nicosid=123; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/",
+                "user_session=user_session_123; Expires=Thu, 22 Oct 2015 07:28:00 GMT",
+                "other=1",
+            ]
+        );
+        assert_eq!(
+            split_folded("nicosid=123; Expires=Wed, 21 Oct 2015 07:28:00 GMT"),
+            ["nicosid=123; Expires=Wed, 21 Oct 2015 07:28:00 GMT"]
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! Credentials used to log in
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Deserializer};
+use zeroize::Zeroize;
+
+use crate::LoginError;
+
+/// Credentials required for Niconico login
+#[derive(Debug, Clone, Deserialize)]
+pub struct Credentials {
+    /// Email address or telephone number associated with the account
+    pub mail_tel: String,
+    /// Account password, or a token standing in for it, stored securely using SecretString
+    pub password: Secret,
+}
+
+/// The secret sent in the password field of the login form
+///
+/// Niconico's login form only accepts the raw password of the account; it has
+/// no pre-hashed or token form. [`Token`](Self::Token) is for secrets issued
+/// by a password vault that a credential-injecting proxy or gateway in front
+/// of the login endpoint swaps for the password, so this crate never sees the
+/// plaintext. Such a login must go through that proxy, configured with
+/// [`LoginBuilder::proxy`](crate::LoginBuilder::proxy) or
+/// [`LoginBuilder::base_urls`](crate::LoginBuilder::base_urls); sending a token
+/// straight to Niconico fails with `LoginError::InvalidCredentialFormat`
+/// before any request is made.
+///
+/// Strings convert to [`Password`](Self::Password), which is also what
+/// credentials files and environment variables deserialize to.
+#[derive(Clone)]
+pub enum Secret {
+    /// The raw password of the account
+    Password(SecretString),
+    /// A vault-issued token that a proxy exchanges for the password
+    Token(SecretString),
+    /// A password or token kept in a container of the application, created
+    /// with [`Secret::custom`]
+    Custom(Arc<dyn SecretLike>),
+}
+
+impl Secret {
+    /// Wraps a secret kept in a container of the application
+    ///
+    /// Whether it is sent as a password or a token is decided by
+    /// [`SecretLike::is_token`].
+    pub fn custom(secret: impl SecretLike + 'static) -> Self {
+        Secret::Custom(Arc::new(secret))
+    }
+
+    /// Returns whether the secret is a token rather than the raw password
+    pub fn is_token(&self) -> bool {
+        match self {
+            Secret::Password(_) => false,
+            Secret::Token(_) => true,
+            Secret::Custom(secret) => secret.is_token(),
+        }
+    }
+}
+
+impl ExposeSecret<str> for Secret {
+    fn expose_secret(&self) -> &str {
+        match self {
+            Secret::Password(secret) | Secret::Token(secret) => secret.expose_secret(),
+            Secret::Custom(secret) => secret.expose(),
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Password(secret) => f.debug_tuple("Password").field(secret).finish(),
+            Secret::Token(secret) => f.debug_tuple("Token").field(secret).finish(),
+            Secret::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// A container that keeps a password or token out of sight until it is sent
+///
+/// Organizations that standardize on their own secret type, e.g. one backed
+/// by an HSM or with custom zeroization, implement this for it and keep the
+/// password in it with [`Secret::custom`]. [`SecretString`] implements it as
+/// well.
+///
+/// The trait only covers the password. The crate borrows the string while it
+/// builds the login request, but the encoded request body is a plain buffer
+/// handed to the HTTP client, which is not zeroized, and response bodies
+/// scrubbed of the password for diagnostics are plain strings as well. The
+/// session token obtained by the login is always held in a [`SecretString`],
+/// whatever type holds the password.
+///
+/// # Examples
+///
+/// ```
+/// use niconico::{Credentials, Secret, SecretLike};
+///
+/// struct VaultSecret(String);
+///
+/// impl SecretLike for VaultSecret {
+///     fn expose(&self) -> &str {
+///         &self.0
+///     }
+/// }
+///
+/// let credentials = Credentials {
+///     mail_tel: "user@example.com".to_string(),
+///     password: Secret::custom(VaultSecret("password".to_string())),
+/// };
+/// assert!(!credentials.password.is_token());
+/// ```
+pub trait SecretLike: Send + Sync {
+    /// Returns the secret string
+    fn expose(&self) -> &str;
+
+    /// Returns whether the secret is a token that a proxy exchanges for the
+    /// password, as described for [`Secret::Token`]
+    ///
+    /// Defaults to `false`.
+    fn is_token(&self) -> bool {
+        false
+    }
+}
+
+impl SecretLike for SecretString {
+    fn expose(&self) -> &str {
+        self.expose_secret()
+    }
+}
+
+impl From<SecretString> for Secret {
+    fn from(password: SecretString) -> Self {
+        Secret::Password(password)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(password: &str) -> Self {
+        Secret::Password(password.into())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(password: String) -> Self {
+        Secret::Password(password.into())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SecretString::deserialize(deserializer).map(Secret::Password)
+    }
+}
+
+/// Default limit on the length of the email address and the password, in bytes
+pub const DEFAULT_MAX_CREDENTIAL_LENGTH: usize = 1024;
+
+impl Credentials {
+    /// Loads credentials from a JSON file
+    ///
+    /// The file holds an object with `mail_tel` and `password` fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the JSON file
+    ///
+    /// # Returns
+    ///
+    /// Returns the credentials, `LoginError::CredentialsIoError` if the file
+    /// could not be read, or `LoginError::CredentialsParseError` if it is not
+    /// valid.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LoginError> {
+        let contents = std::fs::read_to_string(path).map_err(LoginError::CredentialsIoError)?;
+        serde_json::from_str(&contents).map_err(LoginError::CredentialsParseError)
+    }
+
+    /// Checks that the credentials are plausible before sending them
+    ///
+    /// This catches programmer errors, such as passing the contents of a file
+    /// as the password, before a pathological request is made. The error names
+    /// the offending field but never contains its value.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_length` - The maximum length of each field in bytes
+    pub fn validate(&self, max_length: usize) -> Result<(), LoginError> {
+        if self.mail_tel.len() > max_length {
+            return Err(LoginError::InvalidCredentialFormat(format!(
+                "mail_tel exceeds {max_length} bytes"
+            )));
+        }
+        if self.password.expose_secret().len() > max_length {
+            return Err(LoginError::InvalidCredentialFormat(format!(
+                "password exceeds {max_length} bytes"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the password as it is sent, without a trailing line ending when trimming
+    ///
+    /// Only a single `\r\n` or `\n` is removed, so other trailing whitespace
+    /// is kept.
+    pub(crate) fn password_to_send(&self, trim: bool) -> &str {
+        let password = self.password.expose_secret();
+        if !trim {
+            return password;
+        }
+        password
+            .strip_suffix("\r\n")
+            .or_else(|| password.strip_suffix('\n'))
+            .unwrap_or(password)
+    }
+
+    /// Wipes the credentials from memory, leaving both fields empty
+    ///
+    /// The email address is zeroized in place and the password is replaced,
+    /// which zeroizes the previous one. Use this to shorten the time the
+    /// credentials live in memory instead of waiting for them to be dropped.
+    pub fn clear(&mut self) {
+        self.mail_tel.zeroize();
+        self.password = Secret::from("");
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl Credentials {
+    /// Loads credentials from the operating system's keyring
+    ///
+    /// The password is read from the keyring entry identified by `service`
+    /// and `account`, and `account` itself is used as the email address or
+    /// telephone number.
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The service name the password is stored under
+    /// * `account` - The email address or telephone number of the account
+    ///
+    /// # Returns
+    ///
+    /// Returns the credentials, or `LoginError::KeyringError` if the entry
+    /// could not be read.
+    pub fn from_keyring(service: &str, account: &str) -> Result<Self, LoginError> {
+        let password = keyring::Entry::new(service, account)?.get_password()?;
+
+        Ok(Self {
+            mail_tel: account.to_string(),
+            password: password.into(),
+        })
+    }
+}
+
+#[cfg(feature = "prompt")]
+impl Credentials {
+    /// Prompts for credentials on the terminal
+    ///
+    /// The email address or telephone number is read from standard input and
+    /// the password is read without echoing it.
+    ///
+    /// # Returns
+    ///
+    /// Returns the credentials, or `LoginError::PromptError` if the terminal
+    /// could not be read.
+    pub fn prompt_interactive() -> Result<Self, LoginError> {
+        use std::io::Write;
+
+        eprint!("Email address or telephone number: ");
+        std::io::stderr().flush().map_err(LoginError::PromptError)?;
+        let mut mail_tel = String::new();
+        std::io::stdin()
+            .read_line(&mut mail_tel)
+            .map_err(LoginError::PromptError)?;
+
+        let password = rpassword::prompt_password("Password: ").map_err(LoginError::PromptError)?;
+
+        Ok(Self {
+            mail_tel: mail_tel.trim().to_string(),
+            password: password.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that overly long credentials are rejected without revealing them
+    #[test]
+    fn test_validate_length() {
+        let credentials = Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "x".repeat(DEFAULT_MAX_CREDENTIAL_LENGTH + 1).into(),
+        };
+
+        let Err(LoginError::InvalidCredentialFormat(reason)) =
+            credentials.validate(DEFAULT_MAX_CREDENTIAL_LENGTH)
+        else {
+            panic!("expected InvalidCredentialFormat");
+        };
+        assert_eq!(reason, "password exceeds 1024 bytes");
+        assert!(credentials.validate(2048).is_ok());
+    }
+
+    /// Tests that exactly one trailing line ending is trimmed
+    #[test]
+    fn test_password_to_send() {
+        for (password, sent) in [
+            ("password\n", "password"),
+            ("password\r\n", "password"),
+            ("password\n\n", "password\n"),
+            ("password ", "password "),
+        ] {
+            let credentials = Credentials {
+                mail_tel: "user@example.com".to_string(),
+                password: password.into(),
+            };
+            assert_eq!(credentials.password_to_send(true), sent, "{password:?}");
+            assert_eq!(credentials.password_to_send(false), password);
+        }
+    }
+
+    /// Tests that clearing leaves both fields empty
+    #[test]
+    fn test_clear() {
+        let mut credentials = Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "password".into(),
+        };
+
+        credentials.clear();
+
+        assert!(credentials.mail_tel.is_empty());
+        assert!(credentials.password.expose_secret().is_empty());
+    }
+
+    /// Tests that credentials are loaded from a JSON file
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join(format!("niconico-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"mail_tel": "user@example.com", "password": "password"}"#,
+        )
+        .unwrap();
+
+        let credentials = Credentials::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let credentials = credentials.unwrap();
+        assert_eq!(credentials.mail_tel, "user@example.com");
+        assert_eq!(credentials.password.expose_secret(), "password");
+        assert!(!credentials.password.is_token());
+    }
+
+    /// Tests that the Debug output names the kind of secret but not its value
+    #[test]
+    fn test_secret_debug() {
+        let password = Secret::from("hunter2");
+        let token = Secret::Token("vault-token".into());
+
+        assert!(format!("{password:?}").starts_with("Password("));
+        assert!(format!("{token:?}").starts_with("Token("));
+        assert!(!format!("{password:?}{token:?}").contains("hunter2"));
+        assert!(!format!("{token:?}").contains("vault-token"));
+        assert_eq!(token.expose_secret(), "vault-token");
+    }
+
+    /// Tests that a custom container is exposed and classified through its trait
+    #[test]
+    fn test_secret_custom() {
+        struct VaultToken(&'static str);
+
+        impl SecretLike for VaultToken {
+            fn expose(&self) -> &str {
+                self.0
+            }
+
+            fn is_token(&self) -> bool {
+                true
+            }
+        }
+
+        let secret = Secret::custom(VaultToken("vault-token"));
+
+        assert!(secret.is_token());
+        assert_eq!(secret.expose_secret(), "vault-token");
+        assert_eq!(format!("{secret:?}"), "Custom(..)");
+        assert!(!Secret::custom(SecretString::from("hunter2")).is_token());
+    }
+
+    /// Tests that failures to load credentials are mapped to typed errors
+    #[test]
+    fn test_from_file_errors() {
+        let result = Credentials::from_file("/nonexistent/niconico-credentials.json");
+        assert!(matches!(result, Err(LoginError::CredentialsIoError(_))));
+
+        let path = std::env::temp_dir().join(format!("niconico-{}-bad.json", std::process::id()));
+        std::fs::write(&path, "not json").unwrap();
+        let result = Credentials::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(LoginError::CredentialsParseError(_))));
+    }
+
+    /// Tests that a missing keyring entry is reported as a keyring error
+    #[cfg(feature = "keyring")]
+    #[test]
+    fn test_from_keyring_missing_entry() {
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+
+        let result = Credentials::from_keyring("niconico-test", "user@example.com");
+        assert!(matches!(
+            result,
+            Err(LoginError::KeyringError(keyring::Error::NoEntry))
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+//! The CSRF token expected by state-changing Niconico APIs
+
+use reqwest::{IntoUrl, Method, StatusCode};
+use secrecy::SecretString;
+
+use crate::request::{authenticated_request, default_client};
+use crate::{request_error, LoginError, UserSession};
+
+/// Page of the logged-in account that embeds the CSRF token
+const CSRF_PAGE_URL: &str = "https://www.nicovideo.jp/my";
+
+/// Header in which [`UserSession::apply_to`] sends the CSRF token
+pub(crate) const CSRF_TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/// Markers that precede the token on the page, each followed by a quote
+const CSRF_TOKEN_MARKERS: [&str; 3] = [
+    r#"name="csrf-token" content="#,
+    "data-csrf-token=",
+    r#""csrfToken":"#,
+];
+
+/// Fetches the CSRF token of a session
+///
+/// The token is read from the account page, where Niconico embeds it for its
+/// own scripts. Read-only endpoints, such as `nvapi.nicovideo.jp/v1/users/me`,
+/// do not need it; endpoints of `www.nicovideo.jp` that change state, such as
+/// editing mylists or posting comments, reject requests without it. Store it
+/// with [`UserSession::with_csrf_token`] so that [`UserSession::apply_to`]
+/// attaches it, or let [`LoginBuilder::include_csrf_token`](crate::LoginBuilder::include_csrf_token)
+/// do both as part of the login.
+///
+/// # Arguments
+///
+/// * `user_session` - The session of the account
+///
+/// # Returns
+///
+/// * `Ok(SecretString)` with the token
+/// * `Err(LoginError::UnexpectedStatus)` if Niconico rejected the session
+/// * `Err(LoginError::CsrfTokenNotFound)` if the page did not embed a token
+pub async fn fetch_csrf_token(user_session: &UserSession) -> Result<SecretString, LoginError> {
+    fetch_csrf_token_with(&default_client()?, user_session).await
+}
+
+/// Fetches the CSRF token of a session with a client
+pub(crate) async fn fetch_csrf_token_with(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+) -> Result<SecretString, LoginError> {
+    fetch_csrf_token_from(client, user_session, CSRF_PAGE_URL).await
+}
+
+/// Fetches the CSRF token of a session from a page
+async fn fetch_csrf_token_from(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+    url: impl IntoUrl,
+) -> Result<SecretString, LoginError> {
+    let res = authenticated_request(client, user_session, Method::GET, url)?
+        .send()
+        .await
+        .map_err(request_error)?;
+    if res.status() != StatusCode::OK {
+        return Err(LoginError::UnexpectedStatus(res.status()));
+    }
+
+    let body = res.text().await.map_err(request_error)?;
+    find_csrf_token(&body)
+        .map(SecretString::from)
+        .ok_or(LoginError::CsrfTokenNotFound)
+}
+
+/// Returns the CSRF token embedded in a page, if any
+fn find_csrf_token(body: &str) -> Option<&str> {
+    CSRF_TOKEN_MARKERS.iter().find_map(|marker| {
+        let start = body.find(marker)? + marker.len();
+        let quote = body[start..]
+            .chars()
+            .next()
+            .filter(|c| matches!(c, '"' | '\''))?;
+        let rest = &body[start + 1..];
+        let token = &rest[..rest.find(quote)?];
+        (!token.is_empty()).then_some(token)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve_once;
+    use secrecy::ExposeSecret;
+
+    /// Tests that the token is found in the markups Niconico uses
+    #[test]
+    fn test_find_csrf_token() {
+        for body in [
+            r#"<meta name="csrf-token" content="abc-123">"#,
+            r#"<div id="js-initial-userpage-data" data-csrf-token='abc-123'>"#,
+            r#"<script>var data = {"csrfToken":"abc-123"};</script>"#,
+        ] {
+            assert_eq!(find_csrf_token(body), Some("abc-123"), "{body}");
+        }
+        assert_eq!(find_csrf_token(r#"{"csrfToken":""}"#), None);
+        assert_eq!(find_csrf_token("<html></html>"), None);
+    }
+
+    /// Tests that the token is fetched with the session and a page without one is an error
+    #[tokio::test]
+    async fn test_fetch_csrf_token_from() {
+        let (url, request) = serve_once(concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 40\r\n\r\n",
+            r#"<meta name="csrf-token" content="abc-1">"#
+        ))
+        .await;
+        let user_session = UserSession::new("user_session=user_session_123".into());
+
+        let client = default_client().unwrap();
+        let token = fetch_csrf_token_from(&client, &user_session, url)
+            .await
+            .unwrap();
+        assert_eq!(token.expose_secret(), "abc-1");
+        assert!(request
+            .await
+            .unwrap()
+            .to_lowercase()
+            .contains("cookie: user_session=user_session_123\r\n"));
+
+        let (url, _) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let result = fetch_csrf_token_from(&client, &user_session, url).await;
+        assert!(matches!(result, Err(LoginError::CsrfTokenNotFound)));
+    }
+}
@@ -0,0 +1,564 @@
+//! Errors reported by the login process
+
+use std::error::Error as _;
+use std::fmt;
+
+use thiserror::Error;
+
+/// Possible errors that can occur during the login process
+#[derive(Debug, Error)]
+pub enum LoginError {
+    /// Error occurred while creating the HTTP client
+    #[error("Failed to create HTTP client: {0}")]
+    ClientError(#[from] reqwest::Error),
+
+    /// Error occurred while parsing HTTP headers
+    #[error("Failed to parse cookie header: {0}")]
+    HeaderParseError(#[from] reqwest::header::ToStrError),
+
+    /// Required user session cookie was not found in the response
+    #[error("User session cookie not found in response ({0})")]
+    UserSessionNotFound(CookieDiagnostics),
+
+    /// Niconico answered with an HTML page instead of the redirect that issues the session
+    #[error("Unexpected HTML response ({0})")]
+    UnexpectedHtmlResponse(HtmlResponse),
+
+    /// Niconico asked to solve a CAPTCHA instead of issuing a session
+    #[error("CAPTCHA required ({0})")]
+    CaptchaRequired(crate::CaptchaChallenge),
+
+    /// The session cookie lacks the flags required by
+    /// [`LoginBuilder::require_secure_cookie`](crate::LoginBuilder::require_secure_cookie),
+    /// holding the names of the missing flags
+    #[error("Session cookie lacks the {0} flag")]
+    InsecureCookie(String),
+
+    /// The login issued a session for another account than the expected one
+    #[error("Logged in as account {actual}, expected account {expected}")]
+    AccountMismatch {
+        /// The account set with [`LoginBuilder::expected_user_id`](crate::LoginBuilder::expected_user_id)
+        expected: crate::UserId,
+        /// The account the session belongs to
+        actual: crate::UserId,
+    },
+
+    /// The login issued a session that Niconico then did not accept, as
+    /// checked by [`LoginBuilder::verify_after_login`](crate::LoginBuilder::verify_after_login)
+    #[error("Session was issued but not accepted by Niconico")]
+    VerificationFailed,
+
+    /// A followed redirect led to a host outside of the allowed ones, holding the host
+    #[error("Redirect to unexpected host {0}")]
+    UnexpectedRedirectHost(String),
+
+    /// A followed redirect would have sent the session without TLS, holding
+    /// the target without its query and fragment
+    #[error("Redirect to insecure URL {0}")]
+    InsecureRedirect(reqwest::Url),
+
+    /// The session token contains characters that cannot be sent in a cookie
+    #[error("Session token cannot be sent as a cookie")]
+    InvalidSessionToken,
+
+    /// The credentials were rejected before sending them
+    #[error("Invalid credentials: {0}")]
+    InvalidCredentialFormat(String),
+
+    /// Error occurred while reading a credentials file
+    #[error("Failed to read credentials file: {0}")]
+    CredentialsIoError(std::io::Error),
+
+    /// Error occurred while parsing a credentials file
+    #[error("Failed to parse credentials file: {0}")]
+    CredentialsParseError(serde_json::Error),
+
+    /// A response of a Niconico API could not be parsed
+    #[error("Failed to parse response: {0}")]
+    ResponseParseError(serde_json::Error),
+
+    /// Network-related errors during the login request
+    #[error("Network error occurred: {0}")]
+    NetworkError(String),
+
+    /// The page that embeds the CSRF token of the session did not contain one
+    #[error("CSRF token not found on the account page")]
+    CsrfTokenNotFound,
+
+    /// Niconico is unavailable, typically because of scheduled maintenance
+    #[error("Niconico is currently unavailable (maintenance)")]
+    ServiceUnavailable,
+
+    /// Error occurred while reading credentials from the keyring
+    #[cfg(feature = "keyring")]
+    #[error("Failed to read credentials from the keyring: {0}")]
+    KeyringError(#[from] keyring::Error),
+
+    /// The cassette of a login could not be read or written
+    #[cfg(feature = "cassette")]
+    #[error("Failed to access the login cassette: {0}")]
+    CassetteError(std::io::Error),
+
+    /// Error occurred while prompting for credentials on the terminal
+    #[cfg(feature = "prompt")]
+    #[error("Failed to read credentials from the terminal: {0}")]
+    PromptError(std::io::Error),
+
+    /// Niconico refused the request, typically because of anti-bot measures
+    #[error(
+        "Request forbidden by Niconico; the user agent may be blocked, try setting a different one with LoginBuilder::user_agent"
+    )]
+    Forbidden,
+
+    /// Niconico requires a password change before issuing a session
+    #[error("Password change required before a session is issued")]
+    PasswordChangeRequired,
+
+    /// Niconico withholds the session until the expired password is reset,
+    /// holding the reset page to send the user to, if known
+    #[error("Password reset required before a session is issued")]
+    PasswordResetRequired(Option<reqwest::Url>),
+
+    /// Niconico refuses access from the region of the client
+    #[error(
+        "Access blocked in this region; log in through a proxy in Japan with LoginBuilder::proxy"
+    )]
+    RegionBlocked,
+
+    /// The account's email address has not been verified yet
+    #[error("Email address not verified; check the inbox for the confirmation email")]
+    EmailNotVerified,
+
+    /// Niconico sent the login back to the form, rejecting the email address
+    /// or telephone number and password
+    #[error("Login rejected; check the email address or telephone number and the password")]
+    InvalidCredentials,
+
+    /// Niconico asks for the code of multi-factor authentication before
+    /// issuing a session, which this crate cannot provide
+    #[error("Multi-factor authentication required before a session is issued")]
+    MfaRequired,
+
+    /// The login response had a status outside of the expected ones
+    #[error("Unexpected response status: {0}")]
+    UnexpectedStatus(reqwest::StatusCode),
+
+    /// The configured local address cannot be used to bind connections
+    #[error("Invalid local address: {0}")]
+    InvalidLocalAddress(std::net::IpAddr),
+
+    /// The login request did not complete within the configured timeout
+    #[error("Request timed out during {0}")]
+    Timeout(TimeoutPhase),
+
+    /// The request hook registered on the builder failed
+    #[error("Request hook failed: {0}")]
+    RequestHookError(crate::HookError),
+
+    /// The login operation exceeded a limit of its budget
+    #[error("Login exceeded its {0} budget")]
+    BudgetExceeded(crate::BudgetLimit),
+
+    /// Every configured login endpoint failed, holding the error of each one in order
+    #[error("All {} login endpoints failed: {}", .0.len(), join_errors(.0))]
+    AllEndpointsFailed(Vec<LoginError>),
+}
+
+impl LoginError {
+    /// Returns whether the error is transient, so that retrying may succeed
+    ///
+    /// Network failures, timeouts, maintenance and server errors are
+    /// retryable. Errors caused by the credentials or the response content,
+    /// such as `UserSessionNotFound`, are not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LoginError::NetworkError(_)
+            | LoginError::Timeout(_)
+            | LoginError::ServiceUnavailable => true,
+            LoginError::UnexpectedStatus(status) => status.is_server_error(),
+            LoginError::AllEndpointsFailed(errors) => errors.iter().all(LoginError::is_retryable),
+            _ => false,
+        }
+    }
+
+    /// Returns whether two errors are of the same kind, ignoring what they hold
+    ///
+    /// Two `NetworkError`s are of the same kind whatever their messages, as
+    /// are two `UserSessionNotFound` errors with different diagnostics. This
+    /// lets monitoring collapse a flood of similar failures into one alert.
+    pub fn same_class(&self, other: &LoginError) -> bool {
+        self.code() == other.code()
+    }
+
+    /// Returns the names of the cookies a response without a session set, never their values
+    ///
+    /// This is the [`CookieDiagnostics::cookie_names`] of a
+    /// `UserSessionNotFound` error, and empty for other errors.
+    pub fn cookie_names(&self) -> Vec<String> {
+        match self {
+            LoginError::UserSessionNotFound(diagnostics) => diagnostics.cookie_names.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl LoginError {
+    /// Returns a copy of the error, for reporting one failure more than once
+    ///
+    /// Errors holding a source that cannot be cloned, such as a
+    /// `reqwest::Error`, are copied as `NetworkError` with the message of the
+    /// original, and I/O errors keep their kind.
+    pub(crate) fn clone_like(&self) -> LoginError {
+        let io_error =
+            |error: &std::io::Error| std::io::Error::new(error.kind(), error.to_string());
+        match self {
+            LoginError::UserSessionNotFound(diagnostics) => {
+                LoginError::UserSessionNotFound(diagnostics.clone())
+            }
+            LoginError::UnexpectedHtmlResponse(response) => {
+                LoginError::UnexpectedHtmlResponse(response.clone())
+            }
+            LoginError::CaptchaRequired(challenge) => {
+                LoginError::CaptchaRequired(challenge.clone())
+            }
+            LoginError::InsecureCookie(flags) => LoginError::InsecureCookie(flags.clone()),
+            LoginError::AccountMismatch { expected, actual } => LoginError::AccountMismatch {
+                expected: *expected,
+                actual: *actual,
+            },
+            LoginError::VerificationFailed => LoginError::VerificationFailed,
+            LoginError::UnexpectedRedirectHost(host) => {
+                LoginError::UnexpectedRedirectHost(host.clone())
+            }
+            LoginError::InsecureRedirect(url) => LoginError::InsecureRedirect(url.clone()),
+            LoginError::InvalidSessionToken => LoginError::InvalidSessionToken,
+            LoginError::InvalidCredentialFormat(field) => {
+                LoginError::InvalidCredentialFormat(field.clone())
+            }
+            LoginError::CredentialsIoError(error) => {
+                LoginError::CredentialsIoError(io_error(error))
+            }
+            LoginError::NetworkError(message) => LoginError::NetworkError(message.clone()),
+            LoginError::CsrfTokenNotFound => LoginError::CsrfTokenNotFound,
+            LoginError::ServiceUnavailable => LoginError::ServiceUnavailable,
+            #[cfg(feature = "cassette")]
+            LoginError::CassetteError(error) => LoginError::CassetteError(io_error(error)),
+            #[cfg(feature = "prompt")]
+            LoginError::PromptError(error) => LoginError::PromptError(io_error(error)),
+            LoginError::Forbidden => LoginError::Forbidden,
+            LoginError::PasswordChangeRequired => LoginError::PasswordChangeRequired,
+            LoginError::PasswordResetRequired(url) => {
+                LoginError::PasswordResetRequired(url.clone())
+            }
+            LoginError::RegionBlocked => LoginError::RegionBlocked,
+            LoginError::EmailNotVerified => LoginError::EmailNotVerified,
+            LoginError::InvalidCredentials => LoginError::InvalidCredentials,
+            LoginError::MfaRequired => LoginError::MfaRequired,
+            LoginError::UnexpectedStatus(status) => LoginError::UnexpectedStatus(*status),
+            LoginError::InvalidLocalAddress(address) => LoginError::InvalidLocalAddress(*address),
+            LoginError::Timeout(phase) => LoginError::Timeout(*phase),
+            LoginError::BudgetExceeded(limit) => LoginError::BudgetExceeded(*limit),
+            LoginError::AllEndpointsFailed(errors) => {
+                LoginError::AllEndpointsFailed(errors.iter().map(LoginError::clone_like).collect())
+            }
+            error => LoginError::NetworkError(error.to_string()),
+        }
+    }
+
+    /// Returns a short snake_case code naming the kind of error
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            LoginError::ClientError(_) => "client_error",
+            LoginError::HeaderParseError(_) => "header_parse_error",
+            LoginError::UserSessionNotFound(_) => "user_session_not_found",
+            LoginError::UnexpectedHtmlResponse(_) => "unexpected_html_response",
+            LoginError::CaptchaRequired(_) => "captcha_required",
+            LoginError::InsecureCookie(_) => "insecure_cookie",
+            LoginError::AccountMismatch { .. } => "account_mismatch",
+            LoginError::VerificationFailed => "verification_failed",
+            LoginError::UnexpectedRedirectHost(_) => "unexpected_redirect_host",
+            LoginError::InsecureRedirect(_) => "insecure_redirect",
+            LoginError::InvalidSessionToken => "invalid_session_token",
+            LoginError::InvalidCredentialFormat(_) => "invalid_credential_format",
+            LoginError::CredentialsIoError(_) => "credentials_io_error",
+            LoginError::CredentialsParseError(_) => "credentials_parse_error",
+            LoginError::ResponseParseError(_) => "response_parse_error",
+            LoginError::NetworkError(_) => "network_error",
+            LoginError::CsrfTokenNotFound => "csrf_token_not_found",
+            LoginError::ServiceUnavailable => "service_unavailable",
+            #[cfg(feature = "keyring")]
+            LoginError::KeyringError(_) => "keyring_error",
+            #[cfg(feature = "cassette")]
+            LoginError::CassetteError(_) => "cassette_error",
+            #[cfg(feature = "prompt")]
+            LoginError::PromptError(_) => "prompt_error",
+            LoginError::Forbidden => "forbidden",
+            LoginError::PasswordChangeRequired => "password_change_required",
+            LoginError::PasswordResetRequired(_) => "password_reset_required",
+            LoginError::RegionBlocked => "region_blocked",
+            LoginError::EmailNotVerified => "email_not_verified",
+            LoginError::InvalidCredentials => "invalid_credentials",
+            LoginError::MfaRequired => "mfa_required",
+            LoginError::UnexpectedStatus(_) => "unexpected_status",
+            LoginError::InvalidLocalAddress(_) => "invalid_local_address",
+            LoginError::Timeout(_) => "timeout",
+            LoginError::RequestHookError(_) => "request_hook_error",
+            LoginError::BudgetExceeded(_) => "budget_exceeded",
+            LoginError::AllEndpointsFailed(_) => "all_endpoints_failed",
+        }
+    }
+}
+
+/// Joins the messages of several errors into one line
+fn join_errors(errors: &[LoginError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl From<LoginError> for std::io::Error {
+    /// Converts the error for APIs that report `std::io::Error`
+    ///
+    /// I/O errors are unwrapped, timeouts map to `TimedOut`, and every other
+    /// error is wrapped with the `Other` kind.
+    fn from(error: LoginError) -> Self {
+        match error {
+            LoginError::CredentialsIoError(error) => error,
+            LoginError::Timeout(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, error),
+            error => std::io::Error::other(error),
+        }
+    }
+}
+
+/// Phase of the request in which a timeout occurred
+///
+/// The attribution is best effort: reqwest performs the TCP connect and the
+/// TLS handshake inside the same connector, so both are bounded by
+/// [`LoginBuilder::connect_timeout`](crate::LoginBuilder::connect_timeout).
+/// A connect timeout is reported as [`TimeoutPhase::Tls`] only when the
+/// underlying error mentions TLS or certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// Establishing the TCP connection
+    Connect,
+    /// Performing the TLS handshake
+    Tls,
+    /// Waiting for or reading the response
+    Read,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutPhase::Connect => "connect",
+            TimeoutPhase::Tls => "tls",
+            TimeoutPhase::Read => "read",
+        })
+    }
+}
+
+/// Converts an error returned while sending a request into a `LoginError`
+pub(crate) fn request_error(error: reqwest::Error) -> LoginError {
+    if !error.is_timeout() {
+        return LoginError::NetworkError(error.to_string());
+    }
+    if !error.is_connect() {
+        return LoginError::Timeout(TimeoutPhase::Read);
+    }
+
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let message = cause.to_string().to_lowercase();
+        if message.contains("tls") || message.contains("certificate") {
+            return LoginError::Timeout(TimeoutPhase::Tls);
+        }
+        source = cause.source();
+    }
+
+    LoginError::Timeout(TimeoutPhase::Connect)
+}
+
+/// Cookies seen in a response that lacked the user session cookie
+///
+/// Only cookie names are recorded, never their values, so this is safe to
+/// include in logs and bug reports. The body snippet is scrubbed of anything
+/// resembling a secret, but should still be reviewed before sharing it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieDiagnostics {
+    /// Number of `Set-Cookie` headers in the response
+    pub set_cookie_count: usize,
+    /// Names of the cookies set by the response, in order
+    pub cookie_names: Vec<String>,
+    /// Whether scanning stopped at the limit set with
+    /// [`LoginBuilder::max_set_cookies`](crate::LoginBuilder::max_set_cookies)
+    /// before the session cookie was found
+    pub scan_limit_reached: bool,
+    /// The beginning of the response body with secrets scrubbed, when
+    /// enabled with
+    /// [`LoginBuilder::capture_body_snippet`](crate::LoginBuilder::capture_body_snippet)
+    pub body_snippet: Option<String>,
+}
+
+impl fmt::Display for CookieDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Set-Cookie header(s)", self.set_cookie_count)?;
+        if !self.cookie_names.is_empty() {
+            write!(f, ": {}", self.cookie_names.join(", "))?;
+        }
+        if self.scan_limit_reached {
+            f.write_str(" (scan limit reached)")?;
+        }
+        Ok(())
+    }
+}
+
+/// An HTML page that Niconico answered the login with
+///
+/// Error, challenge and maintenance pages are served this way. The title and
+/// snippet are scrubbed like
+/// [`CookieDiagnostics::body_snippet`](CookieDiagnostics::body_snippet).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtmlResponse {
+    /// The title of the page, if it has one
+    pub title: Option<String>,
+    /// The beginning of the page with secrets scrubbed
+    pub snippet: String,
+}
+
+impl fmt::Display for HtmlResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.title {
+            Some(title) => write!(f, "page titled {title:?}"),
+            None => f.write_str("untitled page"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a timeout while waiting for the response is attributed to the read phase
+    #[tokio::test]
+    async fn test_request_error_read_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let error = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap()
+            .get(format!("http://{addr}"))
+            .send()
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            request_error(error),
+            LoginError::Timeout(TimeoutPhase::Read)
+        ));
+    }
+
+    /// Tests which errors are considered transient
+    #[test]
+    fn test_is_retryable() {
+        assert!(LoginError::NetworkError("connection reset".to_string()).is_retryable());
+        assert!(LoginError::Timeout(TimeoutPhase::Read).is_retryable());
+        assert!(LoginError::ServiceUnavailable.is_retryable());
+        assert!(LoginError::UnexpectedStatus(reqwest::StatusCode::BAD_GATEWAY).is_retryable());
+
+        assert!(!LoginError::UnexpectedStatus(reqwest::StatusCode::OK).is_retryable());
+        assert!(!LoginError::UserSessionNotFound(CookieDiagnostics::default()).is_retryable());
+        assert!(!LoginError::PasswordChangeRequired.is_retryable());
+    }
+
+    fn assert_error<E: std::error::Error + Send + Sync + 'static>() {}
+
+    /// Tests that a copied error keeps its kind and what it holds
+    #[test]
+    fn test_clone_like() {
+        let error = LoginError::AllEndpointsFailed(vec![
+            LoginError::Timeout(TimeoutPhase::Connect),
+            LoginError::CredentialsIoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "missing",
+            )),
+        ]);
+
+        let copy = error.clone_like();
+        assert_eq!(copy.to_string(), error.to_string());
+        let LoginError::AllEndpointsFailed(errors) = copy else {
+            panic!("{copy:?}");
+        };
+        assert!(matches!(
+            errors[0],
+            LoginError::Timeout(TimeoutPhase::Connect)
+        ));
+        assert!(matches!(
+            &errors[1],
+            LoginError::CredentialsIoError(error) if error.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+
+    /// Tests that errors are grouped by their kind and not by what they hold
+    #[test]
+    fn test_same_class() {
+        let reset = LoginError::NetworkError("connection reset".to_string());
+        let dns = LoginError::NetworkError("dns failure".to_string());
+        let without_snippet = LoginError::UserSessionNotFound(CookieDiagnostics::default());
+        let with_snippet = LoginError::UserSessionNotFound(CookieDiagnostics {
+            body_snippet: Some("<html>".to_string()),
+            ..CookieDiagnostics::default()
+        });
+
+        assert!(reset.same_class(&dns));
+        assert!(without_snippet.same_class(&with_snippet));
+        assert!(
+            LoginError::UnexpectedStatus(reqwest::StatusCode::BAD_GATEWAY).same_class(
+                &LoginError::UnexpectedStatus(reqwest::StatusCode::NOT_FOUND)
+            )
+        );
+        assert!(!reset.same_class(&without_snippet));
+        assert!(!LoginError::Forbidden.same_class(&LoginError::RegionBlocked));
+    }
+
+    /// Tests that only a missing session reports the cookie names
+    #[test]
+    fn test_cookie_names() {
+        let error = LoginError::UserSessionNotFound(CookieDiagnostics {
+            set_cookie_count: 1,
+            cookie_names: vec!["nicosid".to_string()],
+            ..CookieDiagnostics::default()
+        });
+
+        assert_eq!(error.cookie_names(), ["nicosid"]);
+        assert!(LoginError::Forbidden.cookie_names().is_empty());
+    }
+
+    /// Tests that the error works with error-boxing libraries such as anyhow
+    #[test]
+    fn test_login_error_is_error_send_sync() {
+        assert_error::<LoginError>();
+    }
+
+    /// Tests the conversion into an I/O error
+    #[test]
+    fn test_into_io_error() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let error: std::io::Error = LoginError::CredentialsIoError(io_error).into();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        let error: std::io::Error = LoginError::Timeout(TimeoutPhase::Read).into();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+        let error: std::io::Error = LoginError::ServiceUnavailable.into();
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
+        assert!(error.into_inner().unwrap().is::<LoginError>());
+    }
+}
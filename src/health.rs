@@ -0,0 +1,243 @@
+//! Reachability checks for the login endpoint
+
+use reqwest::{header, StatusCode};
+
+use crate::builder::USER_AGENT;
+use crate::{request_error, LoginError};
+
+/// Default host checked by [`check_endpoint_health`]
+const ACCOUNT_URL: &str = "https://account.nicovideo.jp";
+
+/// Checks whether the Niconico login host is reachable and not in maintenance
+///
+/// Issues a single `HEAD` request without following redirects, so it is cheap
+/// enough to run before every batch of logins.
+///
+/// # Arguments
+///
+/// * `base_url` - The host to check, defaulting to `https://account.nicovideo.jp`
+///
+/// # Returns
+///
+/// * `Ok(())` if the host answered normally
+/// * `Err(LoginError::ServiceUnavailable)` if the host reported maintenance
+/// * `Err(LoginError::NetworkError)` if the host could not be reached
+pub async fn check_endpoint_health(base_url: Option<&str>) -> Result<(), LoginError> {
+    let res = health_request(base_url)?
+        .send()
+        .await
+        .map_err(request_error)?;
+
+    classify_health(res.status(), res.headers())
+}
+
+/// State of the login host reported by [`check_endpoint_health_conditional`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// The host answered normally
+    Healthy,
+    /// The host reported maintenance
+    Maintenance,
+    /// The host answered `304 Not Modified`, so the state is the same as at
+    /// the previous check
+    Unchanged,
+}
+
+/// Result of a conditional health check
+///
+/// Pass it to the next call of [`check_endpoint_health_conditional`], which
+/// sends its validators back so that an unchanged state is confirmed cheaply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// State of the host
+    pub status: HealthStatus,
+    /// The `ETag` header of the response, if any
+    pub etag: Option<String>,
+    /// The `Last-Modified` header of the response, if any
+    pub last_modified: Option<String>,
+}
+
+/// Checks the state of the Niconico login host with a conditional request
+///
+/// Behaves like [`check_endpoint_health`], but reports maintenance as
+/// [`HealthStatus::Maintenance`] instead of an error, and sends the `ETag`
+/// and `Last-Modified` validators of the previous check as `If-None-Match`
+/// and `If-Modified-Since`. A `304 Not Modified` response is reported as
+/// [`HealthStatus::Unchanged`] and keeps the previous validators, which is
+/// cheap enough for frequent polling.
+///
+/// # Arguments
+///
+/// * `base_url` - The host to check, defaulting to `https://account.nicovideo.jp`
+/// * `previous` - The result of the previous check, if any
+///
+/// # Returns
+///
+/// * `Ok(HealthCheck)` with the state of the host and its validators
+/// * `Err(LoginError::NetworkError)` if the host could not be reached
+pub async fn check_endpoint_health_conditional(
+    base_url: Option<&str>,
+    previous: Option<&HealthCheck>,
+) -> Result<HealthCheck, LoginError> {
+    let mut request = health_request(base_url)?;
+    if let Some(etag) = previous.and_then(|previous| previous.etag.as_deref()) {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = previous.and_then(|previous| previous.last_modified.as_deref()) {
+        request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let res = request.send().await.map_err(request_error)?;
+
+    Ok(conditional_health(res.status(), res.headers(), previous))
+}
+
+/// Prepares the `HEAD` request of a health check
+fn health_request(base_url: Option<&str>) -> Result<reqwest::RequestBuilder, LoginError> {
+    Ok(reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent(USER_AGENT)
+        .build()
+        .map_err(LoginError::ClientError)?
+        .head(base_url.unwrap_or(ACCOUNT_URL)))
+}
+
+/// Builds the result of a conditional health check from its response
+fn conditional_health(
+    status: StatusCode,
+    headers: &header::HeaderMap,
+    previous: Option<&HealthCheck>,
+) -> HealthCheck {
+    let validator = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    if status == StatusCode::NOT_MODIFIED {
+        return HealthCheck {
+            status: HealthStatus::Unchanged,
+            etag: validator(header::ETAG).or_else(|| previous.and_then(|p| p.etag.clone())),
+            last_modified: validator(header::LAST_MODIFIED)
+                .or_else(|| previous.and_then(|p| p.last_modified.clone())),
+        };
+    }
+
+    HealthCheck {
+        status: if in_maintenance(status, headers) {
+            HealthStatus::Maintenance
+        } else {
+            HealthStatus::Healthy
+        },
+        etag: validator(header::ETAG),
+        last_modified: validator(header::LAST_MODIFIED),
+    }
+}
+
+/// Decides whether a response indicates that Niconico is in maintenance
+fn classify_health(status: StatusCode, headers: &header::HeaderMap) -> Result<(), LoginError> {
+    if in_maintenance(status, headers) {
+        return Err(LoginError::ServiceUnavailable);
+    }
+
+    Ok(())
+}
+
+/// Returns whether a response reports maintenance
+///
+/// Maintenance is reported either as `503 Service Unavailable` or as a
+/// redirect to a maintenance page.
+fn in_maintenance(status: StatusCode, headers: &header::HeaderMap) -> bool {
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        return true;
+    }
+
+    let to_maintenance = headers
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .is_some_and(|location| location.contains("maintenance"));
+    status.is_redirection() && to_maintenance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve_once;
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    /// Tests that a normal response is reported as healthy
+    #[test]
+    fn test_classify_health_ok() {
+        let result = classify_health(StatusCode::OK, &HeaderMap::new());
+        assert!(result.is_ok());
+    }
+
+    /// Tests that a 503 response is reported as maintenance
+    #[test]
+    fn test_classify_health_service_unavailable() {
+        let result = classify_health(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new());
+        assert!(matches!(result, Err(LoginError::ServiceUnavailable)));
+    }
+
+    /// Tests that a redirect to the maintenance page is reported as maintenance
+    #[test]
+    fn test_classify_health_maintenance_redirect() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LOCATION,
+            HeaderValue::from_static("https://www.nicovideo.jp/maintenance"),
+        );
+
+        let result = classify_health(StatusCode::FOUND, &headers);
+        assert!(matches!(result, Err(LoginError::ServiceUnavailable)));
+    }
+
+    /// Tests that the validators of a response are captured along with its state
+    #[test]
+    fn test_conditional_health_captures_validators() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+
+        let check = conditional_health(StatusCode::OK, &headers, None);
+        assert_eq!(check.status, HealthStatus::Healthy);
+        assert_eq!(check.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(check.last_modified, None);
+
+        let check = conditional_health(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new(), None);
+        assert_eq!(check.status, HealthStatus::Maintenance);
+    }
+
+    /// Tests that a 304 response keeps the previous validators
+    #[test]
+    fn test_conditional_health_unchanged() {
+        let previous = HealthCheck {
+            status: HealthStatus::Healthy,
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+
+        let check =
+            conditional_health(StatusCode::NOT_MODIFIED, &HeaderMap::new(), Some(&previous));
+        assert_eq!(check.status, HealthStatus::Unchanged);
+        assert_eq!(check.etag, previous.etag);
+        assert_eq!(check.last_modified, previous.last_modified);
+    }
+
+    /// Tests that the previous validators are sent back as conditional headers
+    #[tokio::test]
+    async fn test_check_endpoint_health_conditional_sends_validators() {
+        let (url, request) = serve_once("HTTP/1.1 304 Not Modified\r\n\r\n").await;
+        let previous = HealthCheck {
+            status: HealthStatus::Healthy,
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+
+        let check = check_endpoint_health_conditional(Some(&url), Some(&previous))
+            .await
+            .unwrap();
+
+        assert_eq!(check.status, HealthStatus::Unchanged);
+        assert!(request.await.unwrap().contains("if-none-match: \"v1\"\r\n"));
+    }
+}
@@ -0,0 +1,57 @@
+//! Parsing sessions from the types of the `http` crate
+
+use crate::{parse_response_header, LoginResult, UserSession, DEFAULT_MAX_SET_COOKIES};
+
+impl UserSession {
+    /// Extracts the session from the headers of a login response
+    ///
+    /// This accepts the `HeaderMap` of the `http` crate, so responses received
+    /// with another HTTP client can be parsed. Requires the `http` feature.
+    /// The other cookies set by the response are kept in the session, and at
+    /// most [`DEFAULT_MAX_SET_COOKIES`] cookies are scanned.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers` - The headers of the login response
+    ///
+    /// # Returns
+    ///
+    /// Returns the session, or `LoginError::UserSessionNotFound` if the
+    /// response did not set one.
+    pub fn from_http_headers(headers: &http::HeaderMap) -> LoginResult {
+        parse_response_header(headers, DEFAULT_MAX_SET_COOKIES)
+    }
+
+    /// Extracts the session from a login response of the `http` crate
+    ///
+    /// Only the headers are read, so the body type does not matter. See
+    /// [`from_http_headers`](Self::from_http_headers).
+    pub fn from_http_response<B>(response: &http::Response<B>) -> LoginResult {
+        Self::from_http_headers(response.headers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_session_token_eq;
+
+    /// Tests that the session is extracted from an `http` response
+    #[test]
+    fn test_from_http_response() {
+        let response = http::Response::builder()
+            .status(http::StatusCode::FOUND)
+            .header(http::header::SET_COOKIE, "nicosid=123")
+            .header(
+                http::header::SET_COOKIE,
+                "user_session=user_session_123; Path=/",
+            )
+            .body(())
+            .unwrap();
+
+        let user_session = UserSession::from_http_response(&response).unwrap();
+
+        assert_session_token_eq(&user_session, "user_session_123");
+        assert!(user_session.cookie("nicosid").is_some());
+    }
+}
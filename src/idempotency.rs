@@ -0,0 +1,70 @@
+//! Keys that let a server recognize retries of the same login
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use reqwest::header::HeaderValue;
+
+/// Header carrying the idempotency key of a login request
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The `Idempotency-Key` sent with the login request
+///
+/// A key stays the same across the retries of one logical login, which are
+/// the retries of [`LoginBuilder::login_with_retry`](crate::LoginBuilder::login_with_retry),
+/// [`session_not_found_retries`](crate::LoginBuilder::session_not_found_retries)
+/// and the fallback to other endpoints, so a server honoring it can tell a
+/// retry from a new login and avoid issuing a second session.
+///
+/// Niconico does not document support for idempotency keys, and as far as
+/// is known it ignores the header. The key is for gateways or proxies in
+/// front of the login endpoint that deduplicate requests, and is otherwise a
+/// placeholder in case Niconico starts honoring it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyKey {
+    /// Sends the same key with every login of the builder
+    Fixed(HeaderValue),
+    /// Generates a new key for every logical login
+    PerLogin,
+}
+
+impl IdempotencyKey {
+    /// Returns the key to send with the requests of a new logical login
+    pub(crate) fn for_login(&self) -> HeaderValue {
+        match self {
+            IdempotencyKey::Fixed(key) => key.clone(),
+            IdempotencyKey::PerLogin => {
+                HeaderValue::from_str(&generate()).expect("a generated key is a valid header value")
+            }
+        }
+    }
+}
+
+/// Generates a key that is unique within and across processes of the host
+///
+/// The key is not secret; it only has to differ between logins.
+fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{nanos:x}-{:x}-{count:x}", std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a fixed key is kept and generated keys differ
+    #[test]
+    fn test_for_login() {
+        let fixed = IdempotencyKey::Fixed(HeaderValue::from_static("login-1"));
+        assert_eq!(fixed.for_login(), "login-1");
+
+        let per_login = IdempotencyKey::PerLogin;
+        assert_ne!(per_login.for_login(), per_login.for_login());
+    }
+}
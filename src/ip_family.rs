@@ -0,0 +1,154 @@
+//! Choosing the IP address family of the login connection
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use reqwest::dns::{Name, Resolve, Resolving};
+
+use crate::timing::system_resolve;
+
+/// Which IP address families the login connection uses
+///
+/// reqwest connects with the happy eyeballs algorithm: it tries the addresses
+/// of the family of the first resolved address, and when no connection is
+/// established within 300 milliseconds, races them with the addresses of the
+/// other family. A dead IPv6 path therefore only delays a dual-stack login
+/// instead of stalling it. The
+/// [`connect_timeout`](crate::LoginBuilder::connect_timeout) is split evenly
+/// between the addresses of each family, so one unresponsive address cannot
+/// use up the time of the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpFamily {
+    /// Uses the addresses in the order returned by the resolver
+    #[default]
+    DualStack,
+    /// Tries IPv4 addresses first, falling back to IPv6
+    PreferV4,
+    /// Tries IPv6 addresses first, falling back to IPv4
+    PreferV6,
+    /// Uses IPv4 addresses only
+    V4Only,
+    /// Uses IPv6 addresses only
+    V6Only,
+}
+
+impl IpFamily {
+    /// Orders and filters resolved addresses according to the family
+    ///
+    /// The relative order of the addresses within each family is kept.
+    fn apply(self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            IpFamily::DualStack => {}
+            IpFamily::PreferV4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            IpFamily::PreferV6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+            IpFamily::V4Only => addrs.retain(SocketAddr::is_ipv4),
+            IpFamily::V6Only => addrs.retain(SocketAddr::is_ipv6),
+        }
+        addrs
+    }
+}
+
+/// A resolver that orders and filters the addresses of another one by family
+pub(crate) struct FamilyResolver {
+    inner: Option<Arc<dyn Resolve>>,
+    family: IpFamily,
+}
+
+impl FamilyResolver {
+    /// Wraps a resolver, or the system resolver with `None`
+    pub(crate) fn new(inner: Option<Arc<dyn Resolve>>, family: IpFamily) -> Self {
+        Self { inner, family }
+    }
+}
+
+impl Resolve for FamilyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let inner = self.inner.clone();
+        let family = self.family;
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = match inner {
+                Some(inner) => inner.resolve(name).await?.collect(),
+                None => system_resolve(name).await?.collect(),
+            };
+            let addrs = family.apply(addrs);
+            if addrs.is_empty() {
+                return Err(format!("no address of the {family:?} family").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as _)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs() -> Vec<SocketAddr> {
+        [
+            "[2001:db8::1]:0",
+            "192.0.2.1:0",
+            "[2001:db8::2]:0",
+            "192.0.2.2:0",
+        ]
+        .into_iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect()
+    }
+
+    fn render(addrs: Vec<SocketAddr>) -> Vec<String> {
+        addrs
+            .into_iter()
+            .map(|addr| addr.ip().to_string())
+            .collect()
+    }
+
+    /// Tests that the addresses are ordered and filtered by family
+    #[test]
+    fn test_apply() {
+        assert_eq!(
+            render(IpFamily::DualStack.apply(addrs())),
+            ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]
+        );
+        assert_eq!(
+            render(IpFamily::PreferV4.apply(addrs())),
+            ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]
+        );
+        assert_eq!(
+            render(IpFamily::PreferV6.apply(addrs())),
+            ["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"]
+        );
+        assert_eq!(
+            render(IpFamily::V4Only.apply(addrs())),
+            ["192.0.2.1", "192.0.2.2"]
+        );
+        assert_eq!(
+            render(IpFamily::V6Only.apply(addrs())),
+            ["2001:db8::1", "2001:db8::2"]
+        );
+    }
+
+    /// Resolves every name to the test addresses
+    struct StaticResolver;
+
+    impl Resolve for StaticResolver {
+        fn resolve(&self, _: Name) -> Resolving {
+            Box::pin(async { Ok(Box::new(addrs().into_iter()) as _) })
+        }
+    }
+
+    /// Tests that the resolver wraps another one and fails without a matching address
+    #[tokio::test]
+    async fn test_family_resolver() {
+        let v4_only = FamilyResolver::new(Some(Arc::new(StaticResolver)), IpFamily::V4Only);
+        let resolved: Vec<_> = v4_only
+            .resolve("example.com".parse().unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(render(resolved), ["192.0.2.1", "192.0.2.2"]);
+
+        let v6_only = FamilyResolver::new(None, IpFamily::V6Only);
+        assert!(v6_only.resolve("127.0.0.1".parse().unwrap()).await.is_err());
+    }
+}
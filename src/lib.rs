@@ -5,7 +5,7 @@
 //!
 //! # Examples
 //!
-//! ```
+//! ```no_run
 //! use niconico::{login, Credentials};
 //! use secrecy::ExposeSecret;
 //!
@@ -16,47 +16,119 @@
 //!
 //!     let user_session = login(credentials).await.unwrap();
 //!
-//!     println!("{:?}", user_session.0.expose_secret());
+//!     println!("{:?}", user_session.token().expose_secret());
 //! }
 //! ```
+//!
+//! # Secrets
+//!
+//! Passwords and session tokens are always held in [`SecretString`](secrecy::SecretString), which
+//! zeroizes its contents on drop and is redacted from `Debug` output, so
+//! printing any type of this crate with `{:?}` never reveals a secret.
+//!
+//! The same holds for panics: this crate never formats a secret into a panic
+//! message, and a panic payload built from the `Debug` output of its types,
+//! for example by `Result::unwrap`, is redacted as well. Backtraces only
+//! contain function names and never values.
+//!
+//! # Thread safety
+//!
+//! Every public type of this crate is `Send + Sync + 'static`, and the futures
+//! returned by the login functions are `Send`, so they can be used from
+//! multi-threaded runtimes and web framework handlers.
+//!
+//! The public traits require `Send + Sync`, so trait objects such as
+//! `Arc<dyn Clock>`, `Arc<dyn RateLimiter>`, `Arc<dyn LoginObserver>` and
+//! `Arc<dyn SecretLike>` are as well. [`CredentialProvider`] returns
+//! `impl Future` and cannot be made into a trait object; it is used through
+//! generics instead, and [`CachedCredentialProvider`] is `Send + Sync`
+//! whenever the provider it wraps is.
 
 use reqwest::header;
-use secrecy::{ExposeSecret, SecretString};
-use serde::Deserialize;
-use thiserror::Error;
-
-/// Credentials required for Niconico login
-#[derive(Debug, Deserialize)]
-pub struct Credentials {
-    /// Email address or telephone number associated with the account
-    pub mail_tel: String,
-    /// Account password, stored securely using SecretString
-    pub password: SecretString,
-}
 
-/// Represents a successful login session
-#[derive(Debug)]
-pub struct UserSession(pub SecretString);
-
-/// Possible errors that can occur during the login process
-#[derive(Debug, Error)]
-pub enum LoginError {
-    /// Error occurred while creating the HTTP client
-    #[error("Failed to create HTTP client: {0}")]
-    ClientError(#[from] reqwest::Error),
+mod audit;
+mod budget;
+mod builder;
+mod cache;
+mod captcha;
+mod capture;
+#[cfg(feature = "cassette")]
+mod cassette;
+mod clock;
+mod cookie;
+mod credentials;
+mod csrf;
+mod error;
+mod health;
+#[cfg(feature = "http")]
+mod http_types;
+mod idempotency;
+mod ip_family;
+mod login_session;
+mod logout;
+mod manager;
+mod observer;
+pub mod prelude;
+mod profile;
+mod provider;
+mod rate_limit;
+mod redirect;
+mod region;
+mod request;
+mod retry;
+#[cfg(feature = "tower")]
+mod service;
+mod session;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod timing;
+mod user_id;
+mod validation;
+mod warning;
 
-    /// Error occurred while parsing HTTP headers
-    #[error("Failed to parse cookie header: {0}")]
-    HeaderParseError(#[from] reqwest::header::ToStrError),
+pub use audit::{AddressFamily, AuditRecord};
+pub use budget::{BudgetLimit, LoginBudget};
+pub use builder::{BodyFormat, HookError, LoginBuilder, SessionSource, Site, UnrecognizedResponse};
+pub use cache::{cached_login, clear_session_cache};
+pub use captcha::{CaptchaChallenge, CaptchaProvider};
+pub use capture::CapturedResponse;
+#[cfg(feature = "cassette")]
+pub use cassette::CassetteMode;
+pub use clock::{Clock, FakeClock, SystemClock};
+pub use credentials::{Credentials, Secret, SecretLike, DEFAULT_MAX_CREDENTIAL_LENGTH};
+pub use csrf::fetch_csrf_token;
+pub use error::{CookieDiagnostics, HtmlResponse, LoginError, TimeoutPhase};
+pub use health::{
+    check_endpoint_health, check_endpoint_health_conditional, HealthCheck, HealthStatus,
+};
+pub use idempotency::IdempotencyKey;
+pub use ip_family::IpFamily;
+pub use login_session::LoginSession;
+pub use logout::{logout, logout_many};
+pub use manager::SessionManager;
+pub use observer::LoginObserver;
+pub use profile::{fetch_profile, fetch_user_id, UserProfile};
+pub use provider::{CachedCredentialProvider, CredentialProvider};
+pub use rate_limit::RateLimiter;
+pub use redirect::RedirectHop;
+pub use request::{get, post};
+pub use retry::{login_robust, RetryPolicy};
+#[cfg(feature = "tower")]
+pub use service::LoginService;
+pub use session::{ClockSkew, LoginDetails, RawSetCookies, UserSession};
+pub use timing::LoginTimings;
+pub use user_id::UserId;
+pub use validation::{ensure_session, validate_many, validate_session, SessionOutcome};
+pub use warning::LoginWarning;
 
-    /// Required user session cookie was not found in the response
-    #[error("User session cookie not found in response")]
-    UserSessionNotFound,
+use error::request_error;
 
-    /// Network-related errors during the login request
-    #[error("Network error occurred: {0}")]
-    NetworkError(String),
-}
+/// Compiles the example of the README as a doctest
+#[cfg(doctest)]
+#[doc = include_str!("../README.md")]
+struct ReadmeDoctests;
 
 /// Type alias for the Result of a login attempt
 pub type LoginResult = Result<UserSession, LoginError>;
@@ -73,35 +145,18 @@ pub type LoginResult = Result<UserSession, LoginError>;
 /// * `Ok(UserSession)` containing the session token on successful login
 /// * `Err(LoginError)` containing the specific error that occurred
 pub async fn login(credentials: Credentials) -> LoginResult {
-    let login_url = "https://account.nicovideo.jp/login/redirector";
-
-    let res = reqwest::Client::builder()
-        .redirect(reqwest::redirect::Policy::none())
-        .user_agent("toof-jp/niconico")
-        .build()
-        .map_err(LoginError::ClientError)?
-        .post(login_url)
-        .header(
-            reqwest::header::CONTENT_TYPE,
-            "application/x-www-form-urlencoded",
-        )
-        .body(format!(
-            "mail_tel={}&password={}",
-            credentials.mail_tel,
-            credentials.password.expose_secret()
-        ))
-        .send()
-        .await
-        .map_err(|e| LoginError::NetworkError(e.to_string()))?;
-
-    parse_response_header(res.headers())
+    LoginBuilder::new().login(&credentials).await
 }
 
+/// Default limit on the number of cookies scanned for the session in a response
+pub const DEFAULT_MAX_SET_COOKIES: usize = 256;
+
 /// Parses the response headers to extract the user session token
 ///
 /// # Arguments
 ///
 /// * `response_header` - HTTP response headers containing the Set-Cookie header
+/// * `max_cookies` - The number of cookies after which scanning stops
 ///
 /// # Returns
 ///
@@ -109,26 +164,89 @@ pub async fn login(credentials: Credentials) -> LoginResult {
 /// * `Ok(UserSession)` with the parsed session token
 /// * `Err(LoginError)` if the session token couldn't be found or parsed
 ///
+/// The other cookies set by the response are kept in the session. When the
+/// session token is missing, the error lists the names of the cookies that
+/// were set instead, and notes whether scanning stopped at `max_cookies`.
+///
 /// # Note
 ///
 /// The function specifically looks for cookies that start with "user_session=user_session_"
-/// as these contain the authentication token.
-fn parse_response_header(response_header: &header::HeaderMap) -> LoginResult {
+/// as these contain the authentication token. When several are set, the most
+/// broadly scoped one by `Domain` and `Path` is selected, the first one on a
+/// tie, and the others are kept as
+/// [`alternate_session_cookies`](UserSession::alternate_session_cookies).
+/// Session cookies scoped to a domain other than `nicovideo.jp` are ignored.
+#[cfg(any(test, feature = "http"))]
+fn parse_response_header(response_header: &header::HeaderMap, max_cookies: usize) -> LoginResult {
+    parse_response_header_in(response_header, max_cookies, session::COOKIE_DOMAIN)
+}
+
+/// Parses the response headers like [`parse_response_header`], accepting session cookies of a domain
+///
+/// A `user_session` cookie whose `Domain` attribute lies outside of
+/// `domain` and its subdomains is ignored, so a proxy or an injected
+/// response cannot plant a session for another site.
+fn parse_response_header_in(
+    response_header: &header::HeaderMap,
+    max_cookies: usize,
+    domain: &str,
+) -> LoginResult {
+    let mut diagnostics = CookieDiagnostics::default();
+    let mut session_cookies = Vec::new();
+    let mut other_cookies = Vec::new();
+
     // There are multiple Set-Cookie headers with the cookie_name 'user_session`
-    for header_value in response_header.get_all(header::SET_COOKIE) {
-        let cookie_str = header_value.to_str()?;
-        if cookie_str.find("user_session=user_session_") == Some(0) {
-            return Ok(UserSession(cookie_str.into()));
+    'headers: for header_value in response_header.get_all(header::SET_COOKIE) {
+        diagnostics.set_cookie_count += 1;
+
+        // A proxy may have folded several Set-Cookie headers into one
+        for cookie_str in cookie::split_folded(header_value.to_str()?) {
+            if diagnostics.cookie_names.len() == max_cookies {
+                diagnostics.scan_limit_reached = true;
+                break 'headers;
+            }
+            diagnostics
+                .cookie_names
+                .push(cookie::name(cookie_str).to_string());
+
+            if cookie_str.find("user_session=user_session_") == Some(0) {
+                if cookie::scoped_to(cookie_str, domain) {
+                    session_cookies.push(cookie_str);
+                }
+            } else if cookie::name(cookie_str) != "user_session" {
+                other_cookies.push(cookie_str);
+            }
+        }
+    }
+
+    // A load-balanced backend may scope several sessions differently
+    let Some(selected) = (0..session_cookies.len()).reduce(|selected, index| {
+        if cookie::scope_rank(session_cookies[index])
+            > cookie::scope_rank(session_cookies[selected])
+        {
+            index
+        } else {
+            selected
         }
+    }) else {
+        return Err(LoginError::UserSessionNotFound(diagnostics));
+    };
+    let mut user_session = UserSession::new(session_cookies.remove(selected).into());
+    for cookie_str in session_cookies {
+        user_session.add_alternate_session(cookie_str);
+    }
+    for cookie_str in other_cookies {
+        user_session.set_cookie(cookie_str);
     }
 
-    Err(LoginError::UserSessionNotFound)
+    Ok(user_session)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use reqwest::header::{HeaderMap, HeaderValue};
+    use secrecy::ExposeSecret;
 
     /// Tests successful parsing of a valid user session cookie
     #[test]
@@ -139,7 +257,7 @@ mod tests {
             HeaderValue::from_static("user_session=user_session_123"),
         );
 
-        let result = parse_response_header(&headers);
+        let result = parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES);
         assert!(result.is_ok());
     }
 
@@ -152,8 +270,111 @@ mod tests {
             HeaderValue::from_static("different_cookie=value"),
         );
 
-        let result = parse_response_header(&headers);
-        assert!(matches!(result, Err(LoginError::UserSessionNotFound)));
+        let result = parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES);
+        assert!(matches!(result, Err(LoginError::UserSessionNotFound(_))));
+    }
+
+    /// Tests that a missing session cookie reports the names of the other cookies
+    #[test]
+    fn test_parse_response_header_not_found_diagnostics() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("nicosid=secret_value; Path=/"),
+        );
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=deleted; Max-Age=0"),
+        );
+
+        let Err(LoginError::UserSessionNotFound(diagnostics)) =
+            parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES)
+        else {
+            panic!("expected UserSessionNotFound");
+        };
+        assert_eq!(diagnostics.set_cookie_count, 2);
+        assert_eq!(diagnostics.cookie_names, ["nicosid", "user_session"]);
+        assert!(!diagnostics.to_string().contains("secret_value"));
+    }
+
+    /// Tests that the session is extracted from a Set-Cookie header folded by a proxy
+    #[test]
+    fn test_parse_response_header_folded() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static(
+                "nicosid=123; Expires=Wed, 21 Oct 2015 07:28:00 GMT, user_session=user_session_123; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/",
+            ),
+        );
+
+        let user_session = parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES).unwrap();
+        test_support::assert_session_token_eq(&user_session, "user_session_123");
+        assert!(user_session.expires_at().is_some());
+        assert!(user_session.cookie("nicosid").is_some());
+    }
+
+    /// Tests that the most broadly scoped of several session cookies is selected
+    #[test]
+    fn test_parse_response_header_multiple_sessions() {
+        let mut headers = HeaderMap::new();
+        for cookie_str in [
+            "user_session=user_session_host; Path=/",
+            "user_session=user_session_account; Domain=account.nicovideo.jp; Path=/",
+            "user_session=user_session_deep; Domain=.nicovideo.jp; Path=/my",
+            "user_session=user_session_broad; Domain=.nicovideo.jp; Path=/",
+        ] {
+            headers.append(header::SET_COOKIE, HeaderValue::from_static(cookie_str));
+        }
+
+        let mut user_session = parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES).unwrap();
+        test_support::assert_session_token_eq(&user_session, "user_session_broad");
+        let alternates: Vec<_> = user_session
+            .alternate_session_cookies()
+            .iter()
+            .map(|cookie_str| cookie::value(cookie_str.expose_secret()).to_string())
+            .collect();
+        assert_eq!(
+            alternates,
+            [
+                "user_session_host",
+                "user_session_account",
+                "user_session_deep"
+            ]
+        );
+
+        assert!(user_session.select_alternate_session(1));
+        test_support::assert_session_token_eq(&user_session, "user_session_account");
+        assert!(!user_session.select_alternate_session(3));
+    }
+
+    /// Tests that a session cookie for an unrelated domain is ignored
+    #[test]
+    fn test_parse_response_header_ignores_foreign_domain() {
+        let mut headers = HeaderMap::new();
+        for cookie_str in [
+            "user_session=user_session_decoy; Domain=evil.example; Path=/",
+            "user_session=user_session_123; Domain=.nicovideo.jp; Path=/",
+        ] {
+            headers.append(header::SET_COOKIE, HeaderValue::from_static(cookie_str));
+        }
+
+        let user_session = parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES).unwrap();
+        test_support::assert_session_token_eq(&user_session, "user_session_123");
+        assert!(user_session.alternate_session_cookies().is_empty());
+
+        headers.remove(header::SET_COOKIE);
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=user_session_decoy; Domain=evil.example"),
+        );
+        assert!(matches!(
+            parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES),
+            Err(LoginError::UserSessionNotFound(_))
+        ));
+        let user_session =
+            parse_response_header_in(&headers, DEFAULT_MAX_SET_COOKIES, "evil.example").unwrap();
+        test_support::assert_session_token_eq(&user_session, "user_session_decoy");
     }
 
     /// Tests error handling for invalid header values
@@ -165,7 +386,332 @@ mod tests {
             HeaderValue::from_bytes(&[0xff]).unwrap(),
         );
 
-        let result = parse_response_header(&headers);
+        let result = parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES);
         assert!(matches!(result, Err(LoginError::HeaderParseError(_))));
     }
+
+    /// Tests that scanning stops at the cookie limit and says so
+    #[test]
+    fn test_parse_response_header_scan_limit() {
+        let mut headers = header::HeaderMap::new();
+        for _ in 0..3 {
+            headers.append(
+                header::SET_COOKIE,
+                header::HeaderValue::from_static("nicosid=1"),
+            );
+        }
+        headers.append(
+            header::SET_COOKIE,
+            header::HeaderValue::from_static("user_session=user_session_123"),
+        );
+
+        let Err(LoginError::UserSessionNotFound(diagnostics)) = parse_response_header(&headers, 3)
+        else {
+            panic!("expected UserSessionNotFound");
+        };
+        assert!(diagnostics.scan_limit_reached);
+        assert_eq!(diagnostics.cookie_names.len(), 3);
+        assert!(diagnostics.to_string().ends_with("(scan limit reached)"));
+
+        assert!(parse_response_header(&headers, 4).is_ok());
+    }
+
+    /// Tests that multiple Set-Cookie headers received over HTTP/2 stay separate
+    #[tokio::test]
+    async fn test_parse_response_header_http2() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(socket).await.unwrap();
+            let (_, mut respond) = connection.accept().await.unwrap().unwrap();
+            let response = http::Response::builder()
+                .status(302)
+                .header(
+                    "set-cookie",
+                    "nicosid=123; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+                )
+                .header("set-cookie", "user_session=user_session_123; Path=/")
+                .header("set-cookie", "user_session_secure=secure_123; Secure")
+                .body(())
+                .unwrap();
+            respond.send_response(response, true).unwrap();
+            // Keep the connection alive until the client has read the response
+            while connection.accept().await.is_some() {}
+        });
+
+        let res = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap()
+            .get(url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.version(), reqwest::Version::HTTP_2);
+        assert_eq!(res.headers().get_all(header::SET_COOKIE).iter().count(), 3);
+
+        let user_session = parse_response_header(res.headers(), DEFAULT_MAX_SET_COOKIES).unwrap();
+        test_support::assert_session_token_eq(&user_session, "user_session_123");
+        assert!(user_session.cookie("nicosid").is_some());
+        assert!(user_session.cookie("user_session_secure").is_some());
+    }
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    /// Tests that the public types can be shared across threads
+    #[test]
+    fn test_public_types_are_send_sync() {
+        assert_send_sync::<Credentials>();
+        assert_send_sync::<UserSession>();
+        assert_send_sync::<LoginDetails>();
+        assert_send_sync::<ClockSkew>();
+        assert_send_sync::<LoginWarning>();
+        assert_send_sync::<LoginError>();
+        assert_send_sync::<CookieDiagnostics>();
+        assert_send_sync::<TimeoutPhase>();
+        assert_send_sync::<LoginBuilder>();
+        assert_send_sync::<CapturedResponse>();
+        assert_send_sync::<FakeClock>();
+        assert_send_sync::<Secret>();
+        assert_send_sync::<RedirectHop>();
+        #[cfg(feature = "tower")]
+        assert_send_sync::<LoginService>();
+        #[cfg(feature = "testing")]
+        assert_send_sync::<testing::MockNiconico>();
+        #[cfg(feature = "cassette")]
+        assert_send_sync::<CassetteMode>();
+        assert_send_sync::<IdempotencyKey>();
+        assert_send_sync::<BodyFormat>();
+        assert_send_sync::<SessionSource>();
+        assert_send_sync::<SessionOutcome>();
+        assert_send_sync::<RetryPolicy>();
+        assert_send_sync::<RawSetCookies>();
+        assert_send_sync::<UserId>();
+        assert_send_sync::<SessionManager>();
+        assert_send_sync::<HealthCheck>();
+        assert_send_sync::<LoginBudget>();
+        assert_send_sync::<Site>();
+        assert_send_sync::<CaptchaChallenge>();
+        assert_send_sync::<LoginTimings>();
+        assert_send_sync::<UserProfile>();
+        assert_send_sync::<IpFamily>();
+        assert_send_sync::<LoginSession>();
+        assert_send_sync::<HtmlResponse>();
+        assert_send_sync::<AuditRecord>();
+        assert_send_sync::<UnrecognizedResponse>();
+        assert_send_sync::<AddressFamily>();
+        assert_send_sync::<BudgetLimit>();
+        assert_send_sync::<HookError>();
+        assert_send_sync::<SystemClock>();
+        assert_send_sync::<HealthStatus>();
+        assert_send_sync::<CaptchaProvider>();
+        assert_send_sync::<CachedCredentialProvider<StaticProvider>>();
+    }
+
+    /// Hands out the same credentials every time
+    struct StaticProvider;
+
+    impl CredentialProvider for StaticProvider {
+        async fn credentials(&self) -> Result<Credentials, LoginError> {
+            Ok(Credentials {
+                mail_tel: "user@example.com".to_string(),
+                password: "password".into(),
+            })
+        }
+    }
+
+    /// Tests that the trait objects accepted by the public API can be shared across threads
+    #[test]
+    fn test_trait_objects_are_send_sync() {
+        use std::sync::Arc;
+
+        assert_send_sync::<Arc<dyn Clock>>();
+        assert_send_sync::<Arc<dyn RateLimiter>>();
+        assert_send_sync::<Arc<dyn LoginObserver>>();
+        assert_send_sync::<Arc<dyn SecretLike>>();
+        assert_send(&StaticProvider.credentials());
+        assert_send(
+            &CachedCredentialProvider::new(StaticProvider, std::time::Duration::MAX).credentials(),
+        );
+    }
+
+    /// Tests that the login futures can be spawned on a multi-threaded runtime
+    #[test]
+    fn test_login_futures_are_send() {
+        let credentials = || Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "password".into(),
+        };
+        let builder = LoginBuilder::new();
+        let borrowed = credentials();
+
+        assert_send(&login(credentials()));
+        assert_send(&cached_login(credentials()));
+        assert_send(&builder.login(&borrowed));
+        assert_send(&check_endpoint_health(None));
+        assert_send(&check_endpoint_health_conditional(None, None));
+        assert_send(&ensure_session(None, &borrowed));
+        assert_send(&login_robust(credentials()));
+        assert_send(&SessionManager::default().switch_to(&borrowed));
+        assert_send(&validate_many(&[], 1));
+        assert_send(&logout_many(&[], 1));
+        assert_send(&builder.login_many(&[]));
+        assert_send(&builder.login_full(&borrowed));
+    }
+
+    /// Tests that the Debug output of every secret-bearing type redacts the secrets
+    #[test]
+    fn test_debug_redacts_secrets() {
+        const PASSWORD: &str = "password_secret_value";
+        const TOKEN: &str = "user_session_token_secret_value";
+
+        let credentials = Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: PASSWORD.into(),
+        };
+        let user_session = UserSession::new(format!("user_session={TOKEN}; Path=/").into())
+            .with_csrf_token(TOKEN.into());
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_str(&format!("user_session={TOKEN}")).unwrap(),
+        );
+        let details = LoginDetails {
+            user_session: user_session.clone(),
+            warnings: vec![LoginWarning::PasswordChangeRequired],
+            location: None,
+            raw_set_cookies: Some(RawSetCookies::from_headers(&headers)),
+            timings: None,
+            server_date: None,
+            clock_skew: None,
+        };
+
+        let outputs = [
+            format!("{credentials:?}"),
+            format!("{user_session:?}"),
+            format!("{details:?}"),
+            format!("{:?}", SessionOutcome::Reused(user_session.clone())),
+            format!("{:?}", SessionOutcome::Refreshed(user_session)),
+        ];
+        for output in outputs {
+            assert!(!output.contains(PASSWORD));
+            assert!(!output.contains(TOKEN));
+        }
+    }
+
+    /// Tests that panics with secret-bearing types in scope do not leak the secrets
+    #[test]
+    fn test_panic_messages_redact_secrets() {
+        const PASSWORD: &str = "password_secret_value";
+        const TOKEN: &str = "user_session_token_secret_value";
+
+        let panic_message = |f: Box<dyn FnOnce() + std::panic::UnwindSafe>| {
+            let payload = std::panic::catch_unwind(f).unwrap_err();
+            payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap()
+        };
+
+        let messages = [
+            panic_message(Box::new(|| {
+                let credentials = Credentials {
+                    mail_tel: "user@example.com".to_string(),
+                    password: PASSWORD.into(),
+                };
+                panic!("login failed for {credentials:?}");
+            })),
+            panic_message(Box::new(|| {
+                let user_session = UserSession::new(format!("user_session={TOKEN}").into());
+                let result: Result<(), _> = Err(user_session);
+                std::hint::black_box(result).unwrap();
+            })),
+            panic_message(Box::new(|| {
+                let user_session = UserSession::new(format!("user_session={TOKEN}").into());
+                let result: Result<UserSession, LoginError> = Ok(user_session);
+                std::hint::black_box(result).unwrap_err();
+            })),
+        ];
+
+        for message in messages {
+            assert!(!message.contains(PASSWORD), "{message}");
+            assert!(!message.contains(TOKEN), "{message}");
+        }
+    }
+
+    /// Tests that adversarial Set-Cookie headers never make the parser panic
+    #[test]
+    fn test_parse_response_header_adversarial() {
+        let corpus: &[&[u8]] = &[
+            b"",
+            b";",
+            b"=",
+            b"==;;==",
+            b"user_session",
+            b"user_session=",
+            b"user_session=user_session_",
+            b"USER_SESSION=user_session_123",
+            b"User_Session=user_session_123",
+            b"user_session =user_session_123",
+            b" user_session=user_session_123",
+            b"user_session=user_session_123; user_session=user_session_456",
+            b"user_session=user_session_123; Expires=",
+            b"user_session=user_session_123; Expires=not a date; Max-Age=-1",
+            b"user_session=user_session_\xe3\x81\x82; Path=/",
+            b"user_session=user_session_123, nicosid=456; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+            b"user_session=user_session_123\t;\t;",
+            b"\xff\xfe",
+            b"user_session=user_session_\x80",
+        ];
+
+        // Mutate the corpus with a small deterministic generator to cover more shapes
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut inputs: Vec<Vec<u8>> = corpus.iter().map(|input| input.to_vec()).collect();
+        for _ in 0..2000 {
+            let mut input = corpus[next() as usize % corpus.len()].to_vec();
+            match next() % 3 {
+                0 => input.truncate(next() as usize % (input.len() + 1)),
+                1 => input.insert(
+                    next() as usize % (input.len() + 1),
+                    b"=; ,\"user_session_"[next() as usize % 18],
+                ),
+                _ => input.extend_from_within(..),
+            }
+            inputs.push(input);
+        }
+
+        for chunk in inputs.chunks(3) {
+            let mut headers = HeaderMap::new();
+            for input in chunk {
+                if let Ok(value) = HeaderValue::from_bytes(input) {
+                    headers.append(header::SET_COOKIE, value.clone());
+                    // Duplicated headers must be handled as well
+                    headers.append(header::SET_COOKIE, value);
+                }
+            }
+
+            match parse_response_header(&headers, DEFAULT_MAX_SET_COOKIES) {
+                Ok(user_session) => {
+                    assert!(user_session
+                        .user_session
+                        .expose_secret()
+                        .starts_with("user_session=user_session_"));
+                    user_session.masked_preview();
+                    user_session.expires_at();
+                }
+                Err(LoginError::UserSessionNotFound(_) | LoginError::HeaderParseError(_)) => {}
+                Err(error) => panic!("unexpected error: {error}"),
+            }
+        }
+    }
 }
@@ -0,0 +1,175 @@
+//! Logging in to an application-ready client
+
+use std::sync::Arc;
+
+use reqwest::cookie::Jar;
+use reqwest::Url;
+
+use crate::csrf::fetch_csrf_token_with;
+use crate::profile::fetch_profile_with;
+use crate::{
+    Credentials, LoginBuilder, LoginError, LoginTimings, LoginWarning, UserProfile, UserSession,
+};
+
+/// Everything an application needs after logging in
+///
+/// Returned by [`LoginBuilder::login_full`]. The optional pieces are only
+/// filled in when enabled on the builder, so the default stays as cheap as
+/// [`LoginBuilder::login`] plus building a client.
+#[derive(Debug, Clone)]
+pub struct LoginSession {
+    /// A client whose cookie jar holds the session and its other cookies
+    ///
+    /// The client goes through the same proxy, local address, IP family and
+    /// resolver as the login, with the same timeouts and user agent, and so
+    /// do the requests fetching the CSRF token and the profile. Unlike the
+    /// login request, it follows redirects. Cookies set by later responses are stored in the jar as well, but are
+    /// not folded back into [`user_session`](Self::user_session).
+    pub client: reqwest::Client,
+    /// The session obtained by logging in, carrying its CSRF token when
+    /// enabled with [`LoginBuilder::include_csrf_token`]
+    pub user_session: UserSession,
+    /// The profile of the account, when enabled with
+    /// [`LoginBuilder::include_profile`]
+    pub profile: Option<UserProfile>,
+    /// Where the time of the login was spent, when enabled with
+    /// [`LoginBuilder::capture_timings`]
+    pub timings: Option<LoginTimings>,
+    /// Account states that did not prevent the login but deserve attention
+    pub warnings: Vec<LoginWarning>,
+}
+
+impl LoginBuilder {
+    /// Logs in and returns an authenticated client together with the session
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The user credentials to use for login
+    ///
+    /// # Returns
+    ///
+    /// Returns a `LoginSession` on success, or the `LoginError` of the login,
+    /// of fetching the CSRF token or of fetching the profile.
+    pub async fn login_full(&self, credentials: &Credentials) -> Result<LoginSession, LoginError> {
+        let mut details = self.login_details(credentials).await?;
+        let mut profile = None;
+        if self.includes_csrf_token() || self.includes_profile() {
+            let client = self.session_client()?;
+            if self.includes_csrf_token() {
+                let csrf_token = fetch_csrf_token_with(&client, &details.user_session).await?;
+                details.user_session = details.user_session.with_csrf_token(csrf_token);
+            }
+            if self.includes_profile() {
+                profile = Some(fetch_profile_with(&client, &details.user_session).await?);
+            }
+        }
+
+        let client = self
+            .client_builder(None)?
+            .cookie_provider(session_jar(&details.user_session))
+            .build()
+            .map_err(LoginError::ClientError)?;
+
+        Ok(LoginSession {
+            client,
+            user_session: details.user_session,
+            profile,
+            timings: details.timings,
+            warnings: details.warnings,
+        })
+    }
+}
+
+/// Returns a cookie jar holding the cookies of a session
+fn session_jar(user_session: &UserSession) -> Arc<Jar> {
+    let url = Url::parse(crate::session::SESSION_URL).expect("the session URL is valid");
+    let jar = Jar::default();
+    for cookie_str in user_session.cookie_strs() {
+        jar.add_cookie_str(cookie_str, &url);
+    }
+    Arc::new(jar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve, serve_once, LOGIN_OK_RESPONSE};
+    use reqwest::cookie::CookieStore;
+
+    /// Tests that the jar sends the session cookies where the cookies apply
+    #[test]
+    fn test_session_jar() {
+        let mut user_session =
+            UserSession::new("user_session=user_session_123; Domain=.nicovideo.jp; Path=/".into());
+        user_session.set_cookie("nicosid=456; Path=/");
+        let jar = session_jar(&user_session);
+
+        let cookies = |url: &str| {
+            let header = jar.cookies(&Url::parse(url).unwrap());
+            let mut cookies: Vec<String> = header
+                .map(|header| header.to_str().unwrap().to_string())
+                .iter()
+                .flat_map(|header| header.split("; ").map(str::to_string))
+                .collect();
+            cookies.sort();
+            cookies
+        };
+        assert_eq!(
+            cookies("https://account.nicovideo.jp/my"),
+            ["nicosid=456", "user_session=user_session_123"]
+        );
+        assert_eq!(
+            cookies("https://www.nicovideo.jp/"),
+            ["user_session=user_session_123"]
+        );
+        assert!(cookies("https://example.com/").is_empty());
+    }
+
+    /// Tests that the returned client goes through the proxy of the builder
+    #[tokio::test]
+    async fn test_login_full_client_uses_proxy() {
+        let (proxy_url, requests) =
+            serve(vec![LOGIN_OK_RESPONSE, "HTTP/1.1 204 No Content\r\n\r\n"]).await;
+
+        let login_session = LoginBuilder::new()
+            .base_urls([Url::parse("http://login.example.invalid/").unwrap()])
+            .proxy(reqwest::Proxy::all(&proxy_url).unwrap())
+            .login_full(&Credentials {
+                mail_tel: "user@example.com".to_string(),
+                password: "password".into(),
+            })
+            .await
+            .unwrap();
+        let res = login_session
+            .client
+            .get("http://api.example.invalid/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].starts_with("POST http://login.example.invalid/login/redirector "));
+        assert!(requests[1].starts_with("GET http://api.example.invalid/ "));
+    }
+
+    /// Tests that the optional pieces stay empty unless enabled
+    #[tokio::test]
+    async fn test_login_full() {
+        let (url, _) = serve_once(LOGIN_OK_RESPONSE).await;
+
+        let login_session = LoginBuilder::new()
+            .base_urls([Url::parse(&url).unwrap()])
+            .capture_timings(true)
+            .login_full(&Credentials {
+                mail_tel: "user@example.com".to_string(),
+                password: "password".into(),
+            })
+            .await
+            .unwrap();
+
+        assert!(login_session.profile.is_none());
+        assert!(login_session.timings.is_some());
+        assert!(login_session.warnings.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+//! Ending a session
+
+use reqwest::{Method, StatusCode};
+
+use crate::request::{authenticated_request, default_client};
+use crate::validation::run_bounded;
+use crate::{request_error, LoginError, UserSession};
+
+/// Endpoint that ends the session sent with the request
+const LOGOUT_URL: &str = "https://account.nicovideo.jp/logout";
+
+/// Logs out, so that Niconico no longer accepts the session
+///
+/// # Arguments
+///
+/// * `user_session` - The session to end
+///
+/// # Returns
+///
+/// Returns `Ok(())` once Niconico acknowledged the logout, or the
+/// `LoginError` that occurred.
+pub async fn logout(user_session: &UserSession) -> Result<(), LoginError> {
+    let res = authenticated_request(&default_client()?, user_session, Method::GET, LOGOUT_URL)?
+        .send()
+        .await
+        .map_err(request_error)?;
+
+    classify_logout(res.status())
+}
+
+/// Logs out many sessions concurrently, e.g. to clean up after tests
+///
+/// At most `concurrency` logouts run at the same time, and a `concurrency` of
+/// 0 is treated as 1. A failed logout does not affect the others. The
+/// logouts are spawned on the Tokio runtime this is called from.
+///
+/// # Arguments
+///
+/// * `sessions` - The sessions to end
+/// * `concurrency` - The maximum number of logouts running at the same time
+///
+/// # Returns
+///
+/// Returns the result of [`logout`] for every session, in the order of the
+/// sessions.
+pub async fn logout_many(
+    sessions: &[UserSession],
+    concurrency: usize,
+) -> Vec<Result<(), LoginError>> {
+    run_bounded(sessions, concurrency, |user_session| async move {
+        logout(&user_session).await
+    })
+    .await
+}
+
+/// Interprets the status of the logout request
+fn classify_logout(status: StatusCode) -> Result<(), LoginError> {
+    if status.is_success() || status.is_redirection() {
+        Ok(())
+    } else {
+        Err(LoginError::UnexpectedStatus(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests how the statuses of the logout request are interpreted
+    #[test]
+    fn test_classify_logout() {
+        assert!(classify_logout(StatusCode::OK).is_ok());
+        assert!(classify_logout(StatusCode::FOUND).is_ok());
+        assert!(matches!(
+            classify_logout(StatusCode::INTERNAL_SERVER_ERROR),
+            Err(LoginError::UnexpectedStatus(_))
+        ));
+    }
+
+    /// Tests that every session gets its own result, before any request is sent
+    #[tokio::test]
+    async fn test_logout_many_reports_per_session() {
+        let sessions = [
+            UserSession::new("user_session=user_session_\n1".into()),
+            UserSession::new("user_session=user_session_\n2".into()),
+        ];
+
+        let results = logout_many(&sessions, 0).await;
+
+        assert!(matches!(
+            results[..],
+            [
+                Err(LoginError::InvalidSessionToken),
+                Err(LoginError::InvalidSessionToken)
+            ]
+        ));
+        assert!(logout_many(&[], 4).await.is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+//! Managing the session of the active account
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::AbortHandle;
+
+use crate::{logout, Credentials, LoginBuilder, LoginError, UserSession};
+
+/// Holds the session of the active account of a multi-account tool
+///
+/// Only one account is active at a time. [`switch_to`](Self::switch_to)
+/// logs in to another account and replaces the active session with the new
+/// one, so callers do not need one manager per account.
+///
+/// # Examples
+///
+/// ```no_run
+/// use niconico::{Credentials, LoginBuilder, SessionManager};
+///
+/// # async fn run(a: Credentials, b: Credentials) -> Result<(), niconico::LoginError> {
+/// let mut manager = SessionManager::new(LoginBuilder::new()).logout_on_switch(true);
+///
+/// manager.switch_to(&a).await?;
+/// // Work as account A
+/// manager.switch_to(&b).await?;
+/// // Work as account B
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SessionManager {
+    builder: LoginBuilder,
+    logout_on_switch: bool,
+    active: Option<(String, UserSession)>,
+    on_expiring: Option<ExpiringCallback>,
+    expiry_watch: Option<Arc<ExpiryWatch>>,
+}
+
+impl SessionManager {
+    /// Creates a manager without an active account that logs in with the builder
+    pub fn new(builder: LoginBuilder) -> Self {
+        Self {
+            builder,
+            logout_on_switch: false,
+            active: None,
+            on_expiring: None,
+            expiry_watch: None,
+        }
+    }
+
+    /// Registers a callback invoked when the active session is about to expire
+    ///
+    /// The callback is called once per session, with the session, as soon as
+    /// its [`time_to_expiry`](UserSession::time_to_expiry) drops below
+    /// `threshold`, e.g. to start a refresh in the background. A session that
+    /// is already within the threshold when it becomes active is reported
+    /// right away, and sessions without a known expiry are never reported.
+    /// Switching to another account cancels the pending notification of the
+    /// previous session.
+    ///
+    /// The notification is scheduled on the Tokio runtime that
+    /// [`switch_to`](Self::switch_to) or
+    /// [`adopt_if_fresher`](Self::adopt_if_fresher) runs on, and the time to
+    /// expiry is read from the [`clock`](LoginBuilder::clock) of the builder,
+    /// at least once a minute. A session adopted outside of a Tokio runtime is
+    /// not reported.
+    pub fn on_expiring<F>(mut self, threshold: Duration, callback: F) -> Self
+    where
+        F: Fn(&UserSession) + Send + Sync + 'static,
+    {
+        self.on_expiring = Some(ExpiringCallback {
+            threshold,
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    /// Sets whether the previous account is logged out when switching
+    ///
+    /// Disabled by default, which leaves the previous session valid on
+    /// Niconico's side.
+    pub fn logout_on_switch(mut self, logout_on_switch: bool) -> Self {
+        self.logout_on_switch = logout_on_switch;
+        self
+    }
+
+    /// Returns the session of the active account, if any
+    pub fn session(&self) -> Option<&UserSession> {
+        self.active.as_ref().map(|(_, user_session)| user_session)
+    }
+
+    /// Returns the email address or phone number of the active account, if any
+    pub fn account(&self) -> Option<&str> {
+        self.active.as_ref().map(|(mail_tel, _)| mail_tel.as_str())
+    }
+
+    /// Makes another account the active one
+    ///
+    /// The new account is logged in first, and the active session is only
+    /// replaced once that succeeded, so a failed switch keeps the previous
+    /// account active. When [`logout_on_switch`](Self::logout_on_switch) is
+    /// enabled, the previous account is then logged out; a failure to do so
+    /// does not undo the switch and is only logged when the `tracing`
+    /// feature is enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The credentials of the account to switch to
+    ///
+    /// # Returns
+    ///
+    /// Returns the session of the new active account, or the `LoginError`
+    /// of the failed login.
+    pub async fn switch_to(
+        &mut self,
+        credentials: &Credentials,
+    ) -> Result<&UserSession, LoginError> {
+        let user_session = self.builder.login(credentials).await?;
+        self.expiry_watch = self.watch_expiry(&user_session);
+        let previous = self
+            .active
+            .replace((credentials.mail_tel.clone(), user_session));
+
+        if let Some((_, previous)) = previous.filter(|_| self.logout_on_switch) {
+            if let Err(_error) = logout(&previous).await {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %_error, "failed to log out the previous account");
+            }
+        }
+
+        Ok(self.session().expect("the active session was just set"))
+    }
+
+    /// Replaces the active session with another one of the same account if it lasts longer
+    ///
+    /// This reconciles the sessions that several nodes hold for the same
+    /// account, e.g. when they exchange their sessions and each keeps the
+    /// best one. The session is adopted only if it carries a token, has not
+    /// expired according to the [`clock`](LoginBuilder::clock) of the builder,
+    /// and expires strictly later than the active one. Sessions without a
+    /// known expiry are never adopted and never replaced, since their expiries
+    /// cannot be compared, and neither is a session whose token embeds
+    /// another account ID than the active one. The session is not checked with
+    /// Niconico; use [`validate_session`](crate::validate_session) first when
+    /// it may have been revoked.
+    ///
+    /// A session whose expiry comes from a `Max-Age` attribute is never
+    /// adopted either: `Max-Age` counts from when the session was received,
+    /// which is lost when a node rebuilds the session of another one with
+    /// [`UserSession::new`], so an old session would look as fresh as a new
+    /// one. Exchange sessions with
+    /// [`to_netscape_cookies`](UserSession::to_netscape_cookies), which
+    /// records the absolute expiry, instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The session held elsewhere for the active account
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the session was adopted, which is never the case
+    /// without an active account.
+    pub fn adopt_if_fresher(&mut self, other: &UserSession) -> bool {
+        let Some((_, active)) = &self.active else {
+            return false;
+        };
+        let clock = self.builder.clock_ref();
+        let (Some(active_expiry), Some(other_expiry)) = (active.expires_at(), other.expires_at())
+        else {
+            return false;
+        };
+        let same_account = match (active.embedded_user_id(), other.embedded_user_id()) {
+            (Some(active_id), Some(other_id)) => active_id == other_id,
+            _ => true,
+        };
+        if !other.has_token()
+            || other.max_age().is_some()
+            || other.is_expired_with(clock)
+            || other_expiry <= active_expiry
+            || !same_account
+        {
+            return false;
+        }
+
+        self.expiry_watch = self.watch_expiry(other);
+        if let Some((_, active)) = &mut self.active {
+            *active = other.clone();
+        }
+        true
+    }
+
+    /// Schedules the expiry notification of a session that becomes active
+    ///
+    /// The clock is read again at least every [`EXPIRY_POLL_INTERVAL`], so the
+    /// notification follows a clock that jumps, such as a `FakeClock`.
+    fn watch_expiry(&self, user_session: &UserSession) -> Option<Arc<ExpiryWatch>> {
+        let on_expiring = self.on_expiring.clone()?;
+        user_session.expires_at()?;
+        let clock = self.builder.shared_clock();
+        let user_session = user_session.clone();
+
+        let runtime = tokio::runtime::Handle::try_current().ok()?;
+        let task = runtime.spawn(async move {
+            while let Some(delay) = user_session
+                .time_to_expiry_with(clock.as_ref())
+                .map(|time_to_expiry| time_to_expiry.saturating_sub(on_expiring.threshold))
+                .filter(|delay| !delay.is_zero())
+            {
+                tokio::time::sleep(delay.min(EXPIRY_POLL_INTERVAL)).await;
+            }
+            (on_expiring.callback)(&user_session);
+        });
+        Some(Arc::new(ExpiryWatch(task.abort_handle())))
+    }
+}
+
+/// Longest time the expiry notification waits before reading the clock again
+const EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A callback registered with [`SessionManager::on_expiring`]
+#[derive(Clone)]
+struct ExpiringCallback {
+    threshold: Duration,
+    callback: Arc<dyn Fn(&UserSession) + Send + Sync>,
+}
+
+impl fmt::Debug for ExpiringCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpiringCallback")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The pending expiry notification of a session, cancelled when dropped
+///
+/// Clones of a manager share the notification of the session they share.
+#[derive(Debug)]
+struct ExpiryWatch(AbortHandle);
+
+impl Drop for ExpiryWatch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_session_token_eq, serve, serve_once};
+    use reqwest::Url;
+
+    fn credentials(mail_tel: &str) -> Credentials {
+        Credentials {
+            mail_tel: mail_tel.to_string(),
+            password: "password".into(),
+        }
+    }
+
+    /// Tests that switching replaces the active session only on success
+    #[tokio::test]
+    async fn test_switch_to() {
+        let (url, _) = serve_once(
+            "HTTP/1.1 302 Found\r\nSet-Cookie: user_session=user_session_a\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let mut manager =
+            SessionManager::new(LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]));
+        assert!(manager.session().is_none());
+
+        manager
+            .switch_to(&credentials("a@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(manager.account(), Some("a@example.com"));
+
+        let (url, _) = serve_once("HTTP/1.1 302 Found\r\nContent-Length: 0\r\n\r\n").await;
+        manager.builder = LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]);
+        let result = manager.switch_to(&credentials("b@example.com")).await;
+
+        assert!(matches!(result, Err(LoginError::UserSessionNotFound(_))));
+        assert_eq!(manager.account(), Some("a@example.com"));
+        assert_session_token_eq(manager.session().unwrap(), "user_session_a");
+    }
+
+    /// Tests that only a valid session of the same account expiring later is adopted
+    #[tokio::test]
+    async fn test_adopt_if_fresher() {
+        let session = |token: &str, expires: &str| {
+            UserSession::new(format!("user_session={token}; Expires={expires}").into())
+        };
+        let (url, _) = serve_once(concat!(
+            "HTTP/1.1 302 Found\r\n",
+            "Set-Cookie: user_session=user_session_1_a; Expires=Wed, 21 Oct 2037 07:28:00 GMT\r\n",
+            "Content-Length: 0\r\n\r\n"
+        ))
+        .await;
+        let mut manager =
+            SessionManager::new(LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]));
+        let fresher = session("user_session_1_b", "Thu, 22 Oct 2037 07:28:00 GMT");
+        assert!(!manager.adopt_if_fresher(&fresher));
+
+        manager
+            .switch_to(&credentials("a@example.com"))
+            .await
+            .unwrap();
+
+        let staler = session("user_session_1_c", "Tue, 20 Oct 2037 07:28:00 GMT");
+        let equal = session("user_session_1_d", "Wed, 21 Oct 2037 07:28:00 GMT");
+        let other_account = session("user_session_2_e", "Thu, 22 Oct 2037 07:28:00 GMT");
+        let mut cleared = fresher.clone();
+        cleared.clear();
+        for rejected in [&staler, &equal, &other_account, &cleared] {
+            assert!(!manager.adopt_if_fresher(rejected));
+            assert_session_token_eq(manager.session().unwrap(), "user_session_1_a");
+        }
+
+        assert!(manager.adopt_if_fresher(&fresher));
+        assert_session_token_eq(manager.session().unwrap(), "user_session_1_b");
+        assert_eq!(manager.account(), Some("a@example.com"));
+
+        let later = session("user_session_1_f", "Fri, 23 Oct 2037 07:28:00 GMT");
+        manager.builder = manager
+            .builder
+            .clone()
+            .clock(Arc::new(crate::FakeClock::new(later.expires_at().unwrap())));
+        assert!(!manager.adopt_if_fresher(&later));
+    }
+
+    /// Tests that a session rebuilt from a Max-Age cookie is not mistaken for a fresher one
+    #[test]
+    fn test_adopt_if_fresher_max_age() {
+        let mut manager =
+            SessionManager::new(LoginBuilder::new()).on_expiring(Duration::from_secs(60), |_| {});
+        let active = UserSession::new(
+            "user_session=user_session_1_a; Expires=Wed, 21 Oct 2037 07:28:00 GMT".into(),
+        );
+        manager.active = Some(("a@example.com".to_string(), active));
+
+        // Outside of a Tokio runtime, so adopting must not schedule the notification
+        let fresher = UserSession::new(
+            "user_session=user_session_1_b; Expires=Thu, 22 Oct 2037 07:28:00 GMT".into(),
+        );
+        assert!(manager.adopt_if_fresher(&fresher));
+        assert!(manager.expiry_watch.is_none());
+
+        let rebuilt = UserSession::new(
+            "user_session=user_session_1_c; Max-Age=2000000000; Expires=Thu, 22 Oct 2037 07:28:00 GMT"
+                .into(),
+        );
+        assert!(rebuilt.expires_at().unwrap() > fresher.expires_at().unwrap());
+        assert!(!manager.adopt_if_fresher(&rebuilt));
+        assert_session_token_eq(manager.session().unwrap(), "user_session_1_b");
+
+        let exchanged = UserSession::from_netscape_cookies(&rebuilt.to_netscape_cookies()).unwrap();
+        assert!(manager.adopt_if_fresher(&exchanged));
+    }
+
+    /// Tests that the expiry callback fires once a fake clock enters the threshold
+    #[tokio::test(start_paused = true)]
+    async fn test_on_expiring_follows_fake_clock() {
+        let fresher = UserSession::new(
+            "user_session=user_session_1_b; Expires=Thu, 22 Oct 2037 07:28:00 GMT".into(),
+        );
+        let clock = Arc::new(crate::FakeClock::new(
+            fresher.expires_at().unwrap() - Duration::from_secs(60 * 60),
+        ));
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut manager = SessionManager::new(LoginBuilder::new().clock(clock.clone()))
+            .on_expiring(Duration::from_secs(60), move |session| {
+                sender.send(session.masked_preview()).unwrap();
+            });
+        manager.active = Some((
+            "a@example.com".to_string(),
+            UserSession::new(
+                "user_session=user_session_1_a; Expires=Wed, 21 Oct 2037 07:28:00 GMT".into(),
+            ),
+        ));
+        assert!(manager.adopt_if_fresher(&fresher));
+
+        let wait = EXPIRY_POLL_INTERVAL * 2;
+        assert!(tokio::time::timeout(wait, receiver.recv()).await.is_err());
+
+        clock.advance(Duration::from_secs(60 * 60 - 30));
+        let notified = tokio::time::timeout(wait, receiver.recv()).await;
+        assert_eq!(notified.unwrap().unwrap(), "user_session_••••••");
+    }
+
+    /// Tests that the expiry callback fires within the threshold and is cancelled with the manager
+    #[tokio::test]
+    async fn test_on_expiring() {
+        let expiring = concat!(
+            "HTTP/1.1 302 Found\r\n",
+            "Set-Cookie: user_session=user_session_a; Expires=Wed, 21 Oct 2037 07:28:00 GMT\r\n",
+            "Content-Length: 0\r\n\r\n"
+        );
+        let (url, _) = serve(vec![expiring, expiring]).await;
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut manager =
+            SessionManager::new(LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]))
+                .on_expiring(
+                    Duration::from_secs(100 * 365 * 24 * 60 * 60),
+                    move |session| {
+                        sender.send(session.masked_preview()).unwrap();
+                    },
+                );
+
+        manager
+            .switch_to(&credentials("a@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), "user_session_••••••");
+
+        manager.on_expiring.as_mut().unwrap().threshold = Duration::ZERO;
+        manager
+            .switch_to(&credentials("b@example.com"))
+            .await
+            .unwrap();
+        drop(manager);
+        assert!(receiver.recv().await.is_none());
+    }
+}
@@ -0,0 +1,37 @@
+//! Callbacks for observing the login process
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{AuditRecord, LoginError};
+
+/// Receives notifications about the progress of a login
+///
+/// Every method has an empty default implementation, so implementors only
+/// override the events they are interested in. Observers are registered with
+/// [`LoginBuilder::observer`](crate::LoginBuilder::observer).
+pub trait LoginObserver: Send + Sync {
+    /// Called before a failed attempt is retried
+    ///
+    /// `attempt` counts the retries from 1, `error` is the error of the failed
+    /// attempt and `delay` is how long the retry will wait.
+    fn on_retry(&self, attempt: u32, error: &LoginError, delay: Duration) {
+        let _ = (attempt, error, delay);
+    }
+
+    /// Called after every attempt at a login endpoint, whether it succeeded or not
+    fn on_attempt(&self, record: &AuditRecord) {
+        let _ = record;
+    }
+}
+
+/// An observer shared by the clones of a `LoginBuilder`
+#[derive(Clone)]
+pub(crate) struct SharedObserver(pub(crate) Arc<dyn LoginObserver>);
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedObserver(..)")
+    }
+}
@@ -0,0 +1,11 @@
+//! Commonly used items, for glob importing
+//!
+//! ```
+//! use niconico::prelude::*;
+//! ```
+
+pub use crate::{
+    login, login_robust, CaptchaProvider, Clock, CredentialProvider, Credentials, LoginBuilder,
+    LoginDetails, LoginError, LoginObserver, LoginResult, LoginSession, LoginWarning, RateLimiter,
+    RetryPolicy, SecretLike, SessionManager, SessionOutcome, UserId, UserSession,
+};
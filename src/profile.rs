@@ -0,0 +1,218 @@
+//! The profile of the logged-in account
+
+use reqwest::{IntoUrl, Method, StatusCode};
+use serde::Deserialize;
+
+use crate::request::{authenticated_request, default_client};
+use crate::validation::USERS_ME_URL;
+use crate::{request_error, LoginError, UserId, UserSession};
+
+/// Profile of the account a session belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserProfile {
+    /// ID of the account
+    pub id: UserId,
+    /// Display name of the account
+    pub nickname: String,
+    /// Whether the account has a premium subscription
+    pub is_premium: bool,
+    /// Number of unread notifications, when Niconico reports it
+    pub unread_notifications: Option<u32>,
+}
+
+/// Fetches the profile of the account a session belongs to
+///
+/// # Arguments
+///
+/// * `user_session` - The session of the account
+///
+/// # Returns
+///
+/// * `Ok(UserProfile)` with the profile of the account
+/// * `Err(LoginError::UnexpectedStatus)` if Niconico rejected the session
+/// * `Err(LoginError)` if the request failed or the response was not understood
+pub async fn fetch_profile(user_session: &UserSession) -> Result<UserProfile, LoginError> {
+    fetch_profile_with(&default_client()?, user_session).await
+}
+
+/// Fetches the profile of the account a session belongs to with a client
+pub(crate) async fn fetch_profile_with(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+) -> Result<UserProfile, LoginError> {
+    let res = authenticated_request(client, user_session, Method::GET, USERS_ME_URL)?
+        .header("X-Frontend-Id", "6")
+        .send()
+        .await
+        .map_err(request_error)?;
+    if res.status() != StatusCode::OK {
+        return Err(LoginError::UnexpectedStatus(res.status()));
+    }
+
+    let body = res.text().await.map_err(request_error)?;
+    parse_profile(&body)
+}
+
+/// Returns the numeric ID of the account a session belongs to
+///
+/// Niconico embeds the ID in the session token, `user_session_<id>_<secret>`,
+/// in which case no request is made and the session is not checked. Other
+/// tokens fall back to the users/me endpoint, of which only the ID is read.
+/// Use [`fetch_profile`] when more than the identity is needed.
+///
+/// # Arguments
+///
+/// * `user_session` - The session of the account
+///
+/// # Returns
+///
+/// * `Ok(UserId)` with the ID of the account
+/// * `Err(LoginError::UnexpectedStatus)` if Niconico rejected the session
+/// * `Err(LoginError)` if the request failed or the response was not understood
+pub async fn fetch_user_id(user_session: &UserSession) -> Result<UserId, LoginError> {
+    fetch_user_id_from(&default_client()?, user_session, USERS_ME_URL).await
+}
+
+/// Returns the numeric ID of the account a session belongs to with a client
+pub(crate) async fn fetch_user_id_with(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+) -> Result<UserId, LoginError> {
+    fetch_user_id_from(client, user_session, USERS_ME_URL).await
+}
+
+/// Returns the numeric ID of the account a session belongs to, asking an endpoint if needed
+async fn fetch_user_id_from(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+    url: impl IntoUrl,
+) -> Result<UserId, LoginError> {
+    if let Some(user_id) = user_session.embedded_user_id() {
+        return Ok(user_id);
+    }
+
+    let res = authenticated_request(client, user_session, Method::GET, url)?
+        .header("X-Frontend-Id", "6")
+        .send()
+        .await
+        .map_err(request_error)?;
+    if res.status() != StatusCode::OK {
+        return Err(LoginError::UnexpectedStatus(res.status()));
+    }
+
+    let body = res.text().await.map_err(request_error)?;
+    let UsersMeIdResponse { data } =
+        serde_json::from_str(&body).map_err(LoginError::ResponseParseError)?;
+    Ok(data.user.id)
+}
+
+/// The part of the users/me response that holds the ID
+#[derive(Deserialize)]
+struct UsersMeIdResponse {
+    data: UsersMeIdData,
+}
+
+#[derive(Deserialize)]
+struct UsersMeIdData {
+    user: UsersMeIdUser,
+}
+
+#[derive(Deserialize)]
+struct UsersMeIdUser {
+    id: UserId,
+}
+
+/// Response of the users/me endpoint
+#[derive(Deserialize)]
+struct UsersMeResponse {
+    data: UsersMeData,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsersMeData {
+    user: UsersMeUser,
+    #[serde(default)]
+    unread_notification_count: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsersMeUser {
+    id: UserId,
+    nickname: String,
+    #[serde(default)]
+    is_premium: bool,
+}
+
+/// Parses the body of the users/me endpoint
+///
+/// The unread notification count is read from `data.unreadNotificationCount`
+/// and is `None` when the field is missing.
+fn parse_profile(body: &str) -> Result<UserProfile, LoginError> {
+    let UsersMeResponse { data } =
+        serde_json::from_str(body).map_err(LoginError::ResponseParseError)?;
+
+    Ok(UserProfile {
+        id: data.user.id,
+        nickname: data.user.nickname,
+        is_premium: data.user.is_premium,
+        unread_notifications: data.unread_notification_count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the profile is read from the users/me response
+    #[test]
+    fn test_parse_profile() {
+        let body = r#"{"meta":{"status":200},"data":{"user":{"id":12345,"nickname":"nico","isPremium":true},"unreadNotificationCount":3}}"#;
+
+        assert_eq!(
+            parse_profile(body).unwrap(),
+            UserProfile {
+                id: UserId(12345),
+                nickname: "nico".to_string(),
+                is_premium: true,
+                unread_notifications: Some(3),
+            }
+        );
+    }
+
+    /// Tests that the ID is taken from the token when embedded and fetched otherwise
+    #[tokio::test]
+    async fn test_fetch_user_id_from() {
+        let client = default_client().unwrap();
+        let embedded = UserSession::new("user_session=user_session_12345_abcdef".into());
+        let unreachable = "http://127.0.0.1:1/";
+        assert_eq!(
+            fetch_user_id_from(&client, &embedded, unreachable)
+                .await
+                .unwrap(),
+            UserId(12345)
+        );
+
+        let (url, _) = crate::test_support::serve_once(concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 46\r\n\r\n",
+            r#"{"data":{"user":{"id":678,"nickname":"nico"}}}"#
+        ))
+        .await;
+        let opaque = UserSession::new("user_session=user_session_opaque".into());
+        assert_eq!(
+            fetch_user_id_from(&client, &opaque, url).await.unwrap(),
+            UserId(678)
+        );
+    }
+
+    /// Tests that a missing unread notification count is reported as unknown
+    #[test]
+    fn test_parse_profile_without_notifications() {
+        let body = r#"{"data":{"user":{"id":1,"nickname":"nico"}}}"#;
+
+        let profile = parse_profile(body).unwrap();
+        assert_eq!(profile.unread_notifications, None);
+        assert!(!profile.is_premium);
+    }
+}
@@ -0,0 +1,138 @@
+//! Sources of credentials, such as secret vaults
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+use crate::{Credentials, LoginError};
+
+/// A source of login credentials, such as a secret vault
+///
+/// Implement this to look the credentials up when they are needed instead of
+/// keeping them in memory for the lifetime of the program.
+pub trait CredentialProvider: Send + Sync {
+    /// Fetches the current credentials
+    fn credentials(&self) -> impl Future<Output = Result<Credentials, LoginError>> + Send;
+}
+
+/// A [`CredentialProvider`] that caches the credentials of another one for a while
+///
+/// The credentials are fetched again once the TTL has elapsed. Concurrent
+/// calls share a single fetch: while one caller fetches, the others wait for
+/// its result instead of querying the inner provider themselves. Failed
+/// fetches are not cached. The stale credentials are dropped as soon as fresh
+/// ones arrive, which zeroizes the password.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use niconico::{CachedCredentialProvider, CredentialProvider, Credentials, LoginError};
+///
+/// struct Vault;
+///
+/// impl CredentialProvider for Vault {
+///     async fn credentials(&self) -> Result<Credentials, LoginError> {
+///         // Query the vault
+/// #       unimplemented!()
+///     }
+/// }
+///
+/// # async fn run() -> Result<(), LoginError> {
+/// let provider = CachedCredentialProvider::new(Vault, Duration::from_secs(300));
+/// let credentials = provider.credentials().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct CachedCredentialProvider<P> {
+    inner: P,
+    ttl: Duration,
+    cached: Mutex<Option<(Credentials, Instant)>>,
+}
+
+impl<P: CredentialProvider> CachedCredentialProvider<P> {
+    /// Wraps a provider, caching its credentials for `ttl`
+    pub fn new(inner: P, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Discards the cached credentials, so that the next call fetches them again
+    pub async fn invalidate(&self) {
+        *self.cached.lock().await = None;
+    }
+}
+
+impl<P: CredentialProvider> CredentialProvider for CachedCredentialProvider<P> {
+    async fn credentials(&self) -> Result<Credentials, LoginError> {
+        // Holding the lock while fetching makes concurrent callers wait for this fetch
+        let mut cached = self.cached.lock().await;
+        if let Some((credentials, fetched_at)) = &*cached {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(credentials.clone());
+            }
+        }
+
+        *cached = None;
+        let credentials = self.inner.credentials().await?;
+        *cached = Some((credentials.clone(), Instant::now()));
+
+        Ok(credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Counts the fetches and hands out numbered passwords
+    #[derive(Default)]
+    struct CountingProvider(AtomicU32);
+
+    impl CredentialProvider for CountingProvider {
+        async fn credentials(&self) -> Result<Credentials, LoginError> {
+            let fetch = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(Credentials {
+                mail_tel: "user@example.com".to_string(),
+                password: format!("password{fetch}").into(),
+            })
+        }
+    }
+
+    /// Tests that concurrent calls share a single fetch of the inner provider
+    #[tokio::test]
+    async fn test_single_flight() {
+        let provider = CachedCredentialProvider::new(CountingProvider::default(), Duration::MAX);
+
+        let (a, b) = tokio::join!(provider.credentials(), provider.credentials());
+
+        assert_eq!(provider.inner.0.load(Ordering::SeqCst), 1);
+        assert_eq!(a.unwrap().password.expose_secret(), "password1");
+        assert_eq!(b.unwrap().password.expose_secret(), "password1");
+    }
+
+    /// Tests that the credentials are fetched again once the TTL has elapsed
+    #[tokio::test]
+    async fn test_refresh_after_ttl() {
+        let provider =
+            CachedCredentialProvider::new(CountingProvider::default(), Duration::from_millis(50));
+
+        provider.credentials().await.unwrap();
+        provider.credentials().await.unwrap();
+        assert_eq!(provider.inner.0.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(provider.inner.0.load(Ordering::SeqCst), 2);
+        assert_eq!(credentials.password.expose_secret(), "password2");
+    }
+}
@@ -0,0 +1,57 @@
+//! Throttling the login requests of many concurrent logins
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// A rate limiter that login requests wait for before they are sent
+///
+/// Share one limiter between the builders of all tasks to throttle a whole
+/// fleet of logins, however many run concurrently. Limiters are attached with
+/// [`LoginBuilder::rate_limiter`](crate::LoginBuilder::rate_limiter). With the
+/// `governor` feature, this is implemented for the direct rate limiters of
+/// the `governor` crate.
+pub trait RateLimiter: Send + Sync {
+    /// Waits until another request may be sent
+    fn until_ready(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+#[cfg(feature = "governor")]
+impl RateLimiter for governor::DefaultDirectRateLimiter {
+    fn until_ready(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(governor::DefaultDirectRateLimiter::until_ready(self))
+    }
+}
+
+/// A rate limiter shared by the clones of a `LoginBuilder`
+#[derive(Clone)]
+pub(crate) struct SharedRateLimiter(pub(crate) Arc<dyn RateLimiter>);
+
+impl fmt::Debug for SharedRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedRateLimiter(..)")
+    }
+}
+
+#[cfg(all(test, feature = "governor"))]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+    use std::time::{Duration, Instant};
+
+    /// Tests that a governor limiter delays requests beyond its quota
+    #[tokio::test]
+    async fn test_governor_rate_limiter() {
+        let quota = governor::Quota::per_second(NonZeroU32::new(20).unwrap())
+            .allow_burst(NonZeroU32::new(1).unwrap());
+        let limiter: Arc<dyn RateLimiter> = Arc::new(governor::RateLimiter::direct(quota));
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.until_ready().await;
+        }
+
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+}
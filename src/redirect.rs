@@ -0,0 +1,225 @@
+//! Account states signalled by the redirect of the login response
+
+use std::net::IpAddr;
+
+use reqwest::header::{self, HeaderMap};
+use reqwest::{StatusCode, Url};
+
+/// Base against which relative redirects are resolved
+const ACCOUNT_URL: &str = "https://account.nicovideo.jp/";
+
+/// Hosts that followed redirects may lead to by default, with their subdomains
+pub(crate) const DEFAULT_REDIRECT_HOSTS: [&str; 1] = ["nicovideo.jp"];
+
+/// A redirect about to be followed after the login response
+///
+/// Passed to the callback registered with
+/// [`LoginBuilder::on_redirect`](crate::LoginBuilder::on_redirect).
+#[derive(Debug, Clone)]
+pub struct RedirectHop {
+    /// The number of the hop, counting from 1 for the redirect of the login response
+    pub hop: u32,
+    /// The status of the response that redirects
+    pub status: StatusCode,
+    /// The target of the redirect, without its query and fragment, which may carry tokens
+    pub location: Url,
+}
+
+/// Returns a URL without its query and fragment
+pub(crate) fn scrubbed(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
+
+/// Returns whether a URL points to one of the hosts or their subdomains
+pub(crate) fn host_allowed(url: &Url, hosts: &[String]) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    hosts.iter().any(|allowed| {
+        host.eq_ignore_ascii_case(allowed)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", allowed.to_ascii_lowercase()))
+    })
+}
+
+/// Returns whether a URL may receive the session, which requires TLS except on the loopback interface
+pub(crate) fn is_secure(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let loopback = host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+
+    url.scheme() == "https" || url.scheme() == "http" && loopback
+}
+
+/// Returns the target of the redirect, resolving relative locations
+///
+/// The login response redirects relative to the account domain.
+pub(crate) fn location(headers: &HeaderMap) -> Option<Url> {
+    let base = Url::parse(ACCOUNT_URL).expect("ACCOUNT_URL is a valid URL");
+    location_from(headers, &base)
+}
+
+/// Returns the target of the redirect of a response, resolving relative locations against its URL
+pub(crate) fn location_from(headers: &HeaderMap, base: &Url) -> Option<Url> {
+    let location = headers.get(header::LOCATION)?.to_str().ok()?;
+    base.join(location).ok()
+}
+
+/// Returns the `message` query parameter of the redirect
+///
+/// The login page reports why a login did not go through with this parameter.
+pub(crate) fn message(headers: &HeaderMap) -> Option<String> {
+    location(headers)?
+        .query_pairs()
+        .find_map(|(key, value)| (key == "message").then(|| value.into_owned()))
+}
+
+/// Returns whether the response redirects to the password change interstitial
+pub(crate) fn to_password_change(headers: &HeaderMap) -> bool {
+    location(headers).is_some_and(|location| location.path().starts_with("/password/change"))
+}
+
+/// Returns whether the response withholds the session until the password is reset
+///
+/// Detected from a redirect to the `/password/reset` page or from a
+/// `password_expired` message. Unlike the password change interstitial, no
+/// session is issued before the reset.
+pub(crate) fn to_password_reset(headers: &HeaderMap) -> bool {
+    password_reset_url(headers).is_some()
+        || message(headers).is_some_and(|message| message == "password_expired")
+}
+
+/// Returns the target of the redirect if it is the password reset page
+pub(crate) fn password_reset_url(headers: &HeaderMap) -> Option<Url> {
+    location(headers).filter(|location| location.path().starts_with("/password/reset"))
+}
+
+/// Messages with which a login reports that the other sessions of the account were ended
+const SESSIONS_INVALIDATED_MESSAGES: [&str; 2] = ["other_sessions_logged_out", "duplicate_login"];
+
+/// Returns whether the response reports that the other sessions of the account were ended
+///
+/// Detected from the `message` parameter of the redirect, the way the login
+/// page reports its other notices.
+pub(crate) fn invalidates_previous_sessions(headers: &HeaderMap) -> bool {
+    message(headers).is_some_and(|message| SESSIONS_INVALIDATED_MESSAGES.contains(&&*message))
+}
+
+/// Returns whether the response sends the login back to the form for wrong credentials
+pub(crate) fn to_bad_credentials(headers: &HeaderMap) -> bool {
+    message(headers).is_some_and(|message| message == "cant_login")
+}
+
+/// Returns whether the response redirects to the multi-factor authentication page
+pub(crate) fn to_mfa(headers: &HeaderMap) -> bool {
+    location(headers).is_some_and(|location| location.path().starts_with("/mfa"))
+}
+
+/// Returns whether the response reports an account whose email is not verified
+pub(crate) fn to_email_verification(headers: &HeaderMap) -> bool {
+    message(headers).is_some_and(|message| message == "email_not_verified")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    /// Tests that only the allowed hosts and their subdomains are accepted
+    #[test]
+    fn test_host_allowed() {
+        let hosts = DEFAULT_REDIRECT_HOSTS.map(String::from);
+        let allowed = |url: &str| host_allowed(&Url::parse(url).unwrap(), &hosts);
+
+        assert!(allowed("https://nicovideo.jp/"));
+        assert!(allowed("https://www.NicoVideo.jp/my"));
+        assert!(!allowed("https://evilnicovideo.jp/"));
+        assert!(!allowed("https://nicovideo.jp.example.com/"));
+    }
+
+    /// Tests that only https and the loopback interface may receive the session
+    #[test]
+    fn test_is_secure() {
+        let secure = |url: &str| is_secure(&Url::parse(url).unwrap());
+
+        assert!(secure("https://www.nicovideo.jp/"));
+        assert!(secure("http://127.0.0.1:8080/"));
+        assert!(secure("http://[::1]/"));
+        assert!(secure("http://localhost/"));
+        assert!(!secure("http://www.nicovideo.jp/"));
+        assert!(!secure("http://10.0.0.1/"));
+        assert!(!secure("ftp://www.nicovideo.jp/"));
+    }
+
+    fn redirect(location: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::LOCATION, HeaderValue::from_static(location));
+        headers
+    }
+
+    /// Tests that relative redirects are resolved against the account domain
+    #[test]
+    fn test_location_relative() {
+        let location = location(&redirect("/login?message=cant_login")).unwrap();
+        assert_eq!(
+            location.as_str(),
+            "https://account.nicovideo.jp/login?message=cant_login"
+        );
+    }
+
+    /// Tests that a relative redirect of a later hop stays on the host of that hop
+    #[test]
+    fn test_location_from() {
+        let base = Url::parse("https://www.nicovideo.jp/my/top").unwrap();
+        let location = location_from(&redirect("/my/history"), &base).unwrap();
+        assert_eq!(location.as_str(), "https://www.nicovideo.jp/my/history");
+    }
+
+    /// Tests that the message query parameter is extracted
+    #[test]
+    fn test_message() {
+        let headers =
+            redirect("https://account.nicovideo.jp/login?site=niconico&message=cant_login");
+        assert_eq!(message(&headers).as_deref(), Some("cant_login"));
+        assert_eq!(message(&HeaderMap::new()), None);
+    }
+
+    /// Tests that rejected credentials and the multi-factor authentication page are detected
+    #[test]
+    fn test_to_bad_credentials_and_mfa() {
+        let rejected = redirect("/login?site=niconico&message=cant_login");
+        assert!(to_bad_credentials(&rejected));
+        assert!(!to_mfa(&rejected));
+
+        let mfa = redirect("https://account.nicovideo.jp/mfa?site=niconico");
+        assert!(to_mfa(&mfa));
+        assert!(!to_bad_credentials(&mfa));
+    }
+
+    /// Tests that a forced password reset is detected from the page or the message
+    #[test]
+    fn test_to_password_reset() {
+        let headers = redirect("/password/reset?token=abc");
+        assert!(to_password_reset(&headers));
+        assert_eq!(
+            password_reset_url(&headers).unwrap().as_str(),
+            "https://account.nicovideo.jp/password/reset?token=abc"
+        );
+
+        let headers = redirect("/login?message=password_expired");
+        assert!(to_password_reset(&headers));
+        assert_eq!(password_reset_url(&headers), None);
+
+        assert!(!to_password_reset(&redirect("/password/change")));
+    }
+}
@@ -0,0 +1,50 @@
+//! Detection of access blocked because of the client's region
+
+use reqwest::StatusCode;
+
+/// Phrases of the pages served to clients outside of the permitted regions
+const MARKERS: &[&str] = &[
+    "not available in your region",
+    "not available in your country",
+    "not available from your region",
+    "not available from your country",
+    "海外からのアクセス",
+    "日本国外からのアクセス",
+];
+
+/// Returns whether a status refuses the request because of the client's region
+///
+/// `451 Unavailable For Legal Reasons` is the status defined for this.
+pub(crate) fn blocks(status: StatusCode) -> bool {
+    status == StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS
+}
+
+/// Returns whether the body of a forbidden response explains a region block
+///
+/// Matching is case-insensitive for the English phrases.
+pub(crate) fn detect(body: &str) -> bool {
+    let body = body.to_lowercase();
+    MARKERS.iter().any(|marker| body.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that region blocks are recognized from the status
+    #[test]
+    fn test_blocks() {
+        assert!(blocks(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS));
+        assert!(!blocks(StatusCode::FORBIDDEN));
+    }
+
+    /// Tests that region blocks are recognized from the page in English and Japanese
+    #[test]
+    fn test_detect() {
+        assert!(detect(
+            "<p>This service is Not Available in Your Region.</p>"
+        ));
+        assert!(detect("<p>海外からのアクセスは制限されています</p>"));
+        assert!(!detect("<p>Access denied</p>"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Authenticated requests made with a session
+
+use reqwest::{header, IntoUrl, Method, RequestBuilder, Response};
+use secrecy::ExposeSecret;
+
+use crate::builder::USER_AGENT;
+use crate::csrf::CSRF_TOKEN_HEADER;
+use crate::{request_error, LoginError, UserSession};
+
+/// Returns the client of the free functions that send requests with a session
+///
+/// Requests made on behalf of a [`LoginBuilder`](crate::LoginBuilder) use a
+/// client built from its configuration instead.
+pub(crate) fn default_client() -> Result<reqwest::Client, LoginError> {
+    reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .map_err(LoginError::ClientError)
+}
+
+impl UserSession {
+    /// Attaches the session to a request
+    ///
+    /// The session and its other cookies are sent in the `Cookie` header,
+    /// and a stored [`csrf_token`](Self::csrf_token) in the `X-CSRF-Token`
+    /// header, so the request can be sent with any client.
+    ///
+    /// # Returns
+    ///
+    /// Returns the request, or `LoginError::InvalidSessionToken` if the
+    /// session cannot be sent as a cookie.
+    pub fn apply_to(&self, request: RequestBuilder) -> Result<RequestBuilder, LoginError> {
+        let mut request = request.header(header::COOKIE, self.cookie_header()?);
+        if let Some(csrf_token) = self.csrf_token() {
+            request = request.header(CSRF_TOKEN_HEADER, csrf_token.expose_secret());
+        }
+        Ok(request)
+    }
+}
+
+/// Sends an authenticated `GET` request
+///
+/// The session cookies and the default user agent are attached to the
+/// request, and the raw response is returned whatever its status.
+///
+/// # Arguments
+///
+/// * `user_session` - The session to authenticate with
+/// * `url` - The URL to request
+pub async fn get(user_session: &UserSession, url: impl IntoUrl) -> Result<Response, LoginError> {
+    authenticated_request(&default_client()?, user_session, Method::GET, url)?
+        .send()
+        .await
+        .map_err(request_error)
+}
+
+/// Sends an authenticated `POST` request
+///
+/// The session cookies and the default user agent are attached to the
+/// request, and the raw response is returned whatever its status.
+///
+/// # Arguments
+///
+/// * `user_session` - The session to authenticate with
+/// * `url` - The URL to request
+/// * `body` - The request body
+pub async fn post(
+    user_session: &UserSession,
+    url: impl IntoUrl,
+    body: impl Into<reqwest::Body>,
+) -> Result<Response, LoginError> {
+    authenticated_request(&default_client()?, user_session, Method::POST, url)?
+        .body(body)
+        .send()
+        .await
+        .map_err(request_error)
+}
+
+/// Prepares a request carrying the session cookies
+pub(crate) fn authenticated_request(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+    method: Method,
+    url: impl IntoUrl,
+) -> Result<reqwest::RequestBuilder, LoginError> {
+    user_session.apply_to(client.request(method, url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve_once;
+
+    /// Tests that the session cookie and user agent are attached to the request
+    #[tokio::test]
+    async fn test_get_attaches_session() {
+        let (url, request) = serve_once("HTTP/1.1 204 No Content\r\n\r\n").await;
+        let user_session = UserSession::new("user_session=user_session_123; Path=/".into());
+
+        let res = get(&user_session, url).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.starts_with("get / "));
+        assert!(request.contains("cookie: user_session=user_session_123\r\n"));
+        assert!(request.contains("user-agent: toof-jp/niconico\r\n"));
+        assert!(!request.contains("x-csrf-token"));
+    }
+
+    /// Tests that a stored CSRF token is attached alongside the cookies
+    #[tokio::test]
+    async fn test_apply_to_attaches_csrf_token() {
+        let (url, request) = serve_once("HTTP/1.1 204 No Content\r\n\r\n").await;
+        let user_session = UserSession::new("user_session=user_session_123".into())
+            .with_csrf_token("abc-1".into());
+
+        user_session
+            .apply_to(reqwest::Client::new().get(url))
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.contains("cookie: user_session=user_session_123\r\n"));
+        assert!(request.contains("x-csrf-token: abc-1\r\n"));
+    }
+}
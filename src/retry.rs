@@ -0,0 +1,517 @@
+//! Retrying logins that failed for transient reasons
+
+use std::future::Future;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use tokio::time::Instant;
+
+use crate::budget::BudgetTracker;
+use crate::{Credentials, LoginBuilder, LoginError, LoginObserver, LoginResult};
+
+/// Policy deciding how often and how late failed logins are retried
+///
+/// Only errors for which [`LoginError::is_retryable`] returns `true` are
+/// retried, unless [`retryable_statuses`](Self::retryable_statuses) narrows
+/// down or widens the statuses that are. The delay before the `n`th retry is
+/// `initial_delay * 2^(n - 1)`, clamped to `max_delay`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Upper bound of the delay before any retry, if any
+    pub max_delay: Option<Duration>,
+    /// Time after the first attempt started beyond which no retry is started
+    ///
+    /// A retry whose delay would end past this limit is not made, and the
+    /// last error is returned instead.
+    pub max_total_elapsed: Option<Duration>,
+    /// Statuses of `LoginError::UnexpectedStatus` that are retried, if any
+    ///
+    /// When set, an unexpected status is retried exactly when it is listed,
+    /// e.g. 502, 503 and 504 but not 500, instead of whenever it is a server
+    /// error. Other errors are still retried according to
+    /// [`LoginError::is_retryable`].
+    pub retryable_statuses: Option<Vec<StatusCode>>,
+}
+
+impl Default for RetryPolicy {
+    /// Three retries, waiting 1, 2 and 4 seconds
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: None,
+            max_total_elapsed: None,
+            retryable_statuses: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the given retry, counting from 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.initial_delay.saturating_mul(factor);
+
+        self.max_delay
+            .map_or(delay, |max_delay| delay.min(max_delay))
+    }
+
+    /// Returns whether the policy retries an error
+    pub fn is_retryable(&self, error: &LoginError) -> bool {
+        match (error, &self.retryable_statuses) {
+            (LoginError::UnexpectedStatus(status), Some(statuses)) => statuses.contains(status),
+            (LoginError::AllEndpointsFailed(errors), _) => {
+                errors.iter().all(|error| self.is_retryable(error))
+            }
+            (error, _) => error.is_retryable(),
+        }
+    }
+
+    /// Returns whether a retry after the delay would end within the total time limit
+    fn within_total_elapsed(&self, started: Instant, delay: Duration) -> bool {
+        self.max_total_elapsed
+            .is_none_or(|max_total_elapsed| started.elapsed() + delay <= max_total_elapsed)
+    }
+
+    /// Runs an operation, retrying it according to the policy
+    ///
+    /// Every retry is reported to the observer and, with the `tracing`
+    /// feature, logged. No retry is made whose delay would end past the
+    /// deadline of the budget, if any.
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        budget: Option<&BudgetTracker>,
+        observer: Option<&dyn LoginObserver>,
+        mut operation: F,
+    ) -> Result<T, LoginError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, LoginError>>,
+    {
+        let started = Instant::now();
+        let mut retry = 0;
+        loop {
+            match operation().await {
+                Err(error)
+                    if self.is_retryable(&error)
+                        && retry < self.max_retries
+                        && self.within_total_elapsed(started, self.delay(retry + 1))
+                        && budget
+                            .is_none_or(|budget| budget.leaves_time_for(self.delay(retry + 1))) =>
+                {
+                    retry += 1;
+                    let delay = self.delay(retry);
+
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(attempt = retry, %error, ?delay, "retrying login");
+                    if let Some(observer) = observer {
+                        observer.on_retry(retry, &error, delay);
+                    }
+
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl LoginBuilder {
+    /// Attempts to log in, retrying transient failures according to `policy`
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The user credentials to use for login
+    /// * `policy` - How often and how late to retry
+    ///
+    /// # Returns
+    ///
+    /// Returns the result of the first attempt that succeeded or failed with
+    /// an error that is not retryable, or the last error once the retries are
+    /// exhausted. The [`budget`](Self::budget) of the builder is shared by
+    /// all attempts, and a retry whose delay would end past its
+    /// `max_duration` is not made, so the last error is returned within it.
+    pub async fn login_with_retry(
+        &self,
+        credentials: &Credentials,
+        policy: &RetryPolicy,
+    ) -> LoginResult {
+        let budget = BudgetTracker::start(self.budget_ref());
+        let idempotency_key = self.new_idempotency_key();
+        policy
+            .run(Some(&budget), self.observer_ref(), || async {
+                self.login_details_within(credentials, &budget, idempotency_key.as_ref())
+                    .await
+                    .map(|details| details.user_session)
+            })
+            .await
+    }
+}
+
+/// Attempts to log in with defaults suited to unattended use
+///
+/// This applies a 15 second timeout to each attempt and retries transient
+/// failures up to 3 times, waiting 1, 2 and 4 seconds in between.
+/// Credential errors and other errors that are not retryable are returned
+/// immediately. Use [`LoginBuilder::login_with_retry`] to tune these values.
+///
+/// # Arguments
+///
+/// * `credentials` - The user credentials to use for login
+///
+/// # Returns
+///
+/// Returns a `LoginResult` which is either:
+/// * `Ok(UserSession)` containing the session token on successful login
+/// * `Err(LoginError)` containing the last error that occurred
+pub async fn login_robust(credentials: Credentials) -> LoginResult {
+    login_robust_with(LoginBuilder::new(), &credentials).await
+}
+
+/// Logs in with the defaults of [`login_robust`] applied to a builder
+async fn login_robust_with(builder: LoginBuilder, credentials: &Credentials) -> LoginResult {
+    builder
+        .timeout(Duration::from_secs(15))
+        .login_with_retry(credentials, &RetryPolicy::default())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve;
+    use reqwest::Url;
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Tests that the delay doubles with every retry
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+    }
+
+    /// Tests that transient errors are retried until the retries are exhausted
+    #[tokio::test]
+    async fn test_run_retries_transient_errors() {
+        let mut attempts = 0;
+
+        let result: Result<(), _> = fast_policy()
+            .run(None, None, || {
+                attempts += 1;
+                async { Err(LoginError::ServiceUnavailable) }
+            })
+            .await;
+
+        assert!(
+            matches!(result, Err(LoginError::ServiceUnavailable)),
+            "{result:?}"
+        );
+        assert_eq!(attempts, 4);
+    }
+
+    /// Tests that errors which are not retryable are returned immediately
+    #[tokio::test]
+    async fn test_run_fails_fast() {
+        let mut attempts = 0;
+
+        let result: Result<(), _> = fast_policy()
+            .run(None, None, || {
+                attempts += 1;
+                async { Err(LoginError::PasswordChangeRequired) }
+            })
+            .await;
+
+        assert!(matches!(result, Err(LoginError::PasswordChangeRequired)));
+        assert_eq!(attempts, 1);
+    }
+
+    /// Tests that retrying stops at the first success
+    #[tokio::test]
+    async fn test_run_stops_on_success() {
+        let mut attempts = 0;
+
+        let result = fast_policy()
+            .run(None, None, || {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 2 {
+                        Err(LoginError::Timeout(crate::TimeoutPhase::Read))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    /// Records the retries it observes
+    #[derive(Default)]
+    struct RecordingObserver(std::sync::Mutex<Vec<(u32, Duration)>>);
+
+    impl LoginObserver for RecordingObserver {
+        fn on_retry(&self, attempt: u32, _: &LoginError, delay: Duration) {
+            self.0.lock().unwrap().push((attempt, delay));
+        }
+    }
+
+    /// Tests that every retry is reported to the observer
+    #[tokio::test]
+    async fn test_run_notifies_observer() {
+        let observer = RecordingObserver::default();
+
+        let _: Result<(), _> = fast_policy()
+            .run(None, Some(&observer), || async {
+                Err(LoginError::ServiceUnavailable)
+            })
+            .await;
+
+        assert_eq!(
+            *observer.0.lock().unwrap(),
+            [
+                (1, Duration::from_millis(1)),
+                (2, Duration::from_millis(2)),
+                (3, Duration::from_millis(4)),
+            ]
+        );
+    }
+
+    /// Tests that only the listed unexpected statuses are retried when configured
+    #[test]
+    fn test_is_retryable_statuses() {
+        let unexpected = |status| LoginError::UnexpectedStatus(status);
+        let default = RetryPolicy::default();
+        assert!(default.is_retryable(&unexpected(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert!(!default.is_retryable(&unexpected(StatusCode::TOO_MANY_REQUESTS)));
+
+        let policy = RetryPolicy {
+            retryable_statuses: Some(vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]),
+            ..RetryPolicy::default()
+        };
+        assert!(!policy.is_retryable(&unexpected(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert!(policy.is_retryable(&unexpected(StatusCode::BAD_GATEWAY)));
+        assert!(policy.is_retryable(&unexpected(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(policy.is_retryable(&LoginError::ServiceUnavailable));
+        assert!(!policy.is_retryable(&LoginError::AllEndpointsFailed(vec![
+            unexpected(StatusCode::BAD_GATEWAY),
+            unexpected(StatusCode::INTERNAL_SERVER_ERROR),
+        ])));
+    }
+
+    /// Tests that the delays are clamped to the maximum delay
+    #[test]
+    fn test_delay_clamped() {
+        let policy = RetryPolicy {
+            max_delay: Some(Duration::from_secs(3)),
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(3));
+        assert_eq!(policy.delay(30), Duration::from_secs(3));
+    }
+
+    /// Tests that no retry is started whose delay would end past the deadline of the budget
+    #[tokio::test]
+    async fn test_run_respects_budget_deadline() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_secs(10),
+            ..RetryPolicy::default()
+        };
+        let budget = BudgetTracker::start(&crate::LoginBudget {
+            max_duration: Some(Duration::from_millis(50)),
+            ..crate::LoginBudget::default()
+        });
+        let mut attempts = 0;
+
+        let started = Instant::now();
+        let result: Result<(), _> = policy
+            .run(Some(&budget), None, || {
+                attempts += 1;
+                async { Err(LoginError::ServiceUnavailable) }
+            })
+            .await;
+
+        assert!(
+            matches!(result, Err(LoginError::ServiceUnavailable)),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(attempts, 1);
+    }
+
+    /// Tests that no retry is started that would end past the total time limit
+    #[tokio::test]
+    async fn test_run_respects_max_total_elapsed() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_millis(20),
+            max_delay: Some(Duration::from_millis(20)),
+            max_total_elapsed: Some(Duration::from_millis(50)),
+            retryable_statuses: None,
+        };
+        let mut attempts = 0;
+
+        let started = Instant::now();
+        let result: Result<(), _> = policy
+            .run(None, None, || {
+                attempts += 1;
+                async { Err(LoginError::ServiceUnavailable) }
+            })
+            .await;
+
+        assert!(
+            matches!(result, Err(LoginError::ServiceUnavailable)),
+            "{result:?}"
+        );
+        assert!(started.elapsed() <= Duration::from_millis(100));
+        assert!((2..=3).contains(&attempts));
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "password".into(),
+        }
+    }
+
+    /// Returns a builder sending the login to the server
+    fn builder_for(url: &str) -> LoginBuilder {
+        LoginBuilder::new().base_urls([Url::parse(url).unwrap()])
+    }
+
+    /// Advances the paused clock a millisecond at a time until dropped
+    ///
+    /// An idle runtime would jump the paused clock to the next timer while a
+    /// response is still in flight on a real socket, firing the timeouts.
+    /// Keeping the runtime busy lets the I/O complete first.
+    struct Ticker(tokio::task::JoinHandle<()>);
+
+    impl Ticker {
+        fn start() -> Self {
+            Self(tokio::spawn(async {
+                loop {
+                    tokio::time::advance(Duration::from_millis(1)).await;
+                }
+            }))
+        }
+    }
+
+    impl Drop for Ticker {
+        fn drop(&mut self) {
+            self.0.abort();
+        }
+    }
+
+    /// Tests that the robust login returns rejected credentials without retrying
+    #[tokio::test(start_paused = true)]
+    async fn test_login_robust_fails_fast_on_credentials() {
+        let (url, requests) = serve(vec![concat!(
+            "HTTP/1.1 302 Found\r\n",
+            "Location: https://account.nicovideo.jp/login?message=cant_login\r\n",
+            "Content-Length: 0\r\n\r\n"
+        )])
+        .await;
+
+        let _ticker = Ticker::start();
+        let started = Instant::now();
+        let result = login_robust_with(builder_for(&url), &credentials()).await;
+
+        assert!(
+            matches!(result, Err(LoginError::InvalidCredentials)),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(requests.await.unwrap().len(), 1);
+    }
+
+    /// Tests that the robust login retries maintenance 3 times, waiting 1, 2 and 4 seconds
+    #[tokio::test(start_paused = true)]
+    async fn test_login_robust_retries_maintenance() {
+        const MAINTENANCE: &str = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+        let (url, requests) = serve(vec![MAINTENANCE; 4]).await;
+
+        let _ticker = Ticker::start();
+        let started = Instant::now();
+        let result = login_robust_with(builder_for(&url), &credentials()).await;
+
+        assert!(
+            matches!(result, Err(LoginError::ServiceUnavailable)),
+            "{result:?}"
+        );
+        let backoff = Duration::from_secs(1 + 2 + 4);
+        assert!((backoff..backoff + Duration::from_secs(1)).contains(&started.elapsed()));
+        assert_eq!(requests.await.unwrap().len(), 4);
+    }
+
+    /// Tests that every attempt of the robust login times out after 15 seconds
+    #[tokio::test(start_paused = true)]
+    async fn test_login_robust_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let _server = tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let _ticker = Ticker::start();
+        let started = Instant::now();
+        let result = login_robust_with(builder_for(&url), &credentials()).await;
+
+        assert!(matches!(result, Err(LoginError::Timeout(_))), "{result:?}");
+        let expected = Duration::from_secs(4 * 15 + 1 + 2 + 4);
+        assert!((expected..expected + Duration::from_secs(1)).contains(&started.elapsed()));
+    }
+
+    /// Tests that the total time limit counts the backoff of a paused Tokio clock
+    #[tokio::test(start_paused = true)]
+    async fn test_run_max_total_elapsed_paused_clock() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Some(Duration::from_secs(1)),
+            max_total_elapsed: Some(Duration::from_secs(3)),
+            retryable_statuses: None,
+        };
+        let mut attempts = 0;
+
+        let started = Instant::now();
+        let result: Result<(), _> = policy
+            .run(None, None, || {
+                attempts += 1;
+                async { Err(LoginError::ServiceUnavailable) }
+            })
+            .await;
+
+        assert!(
+            matches!(result, Err(LoginError::ServiceUnavailable)),
+            "{result:?}"
+        );
+        assert_eq!(attempts, 4);
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+}
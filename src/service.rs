@@ -0,0 +1,106 @@
+//! Logging in as a `tower` service
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tower_service::Service;
+
+use crate::{Credentials, LoginBuilder, LoginError, LoginResult};
+
+/// A `tower` service that logs in with the credentials of every request
+///
+/// Requires the `tower` feature. The service wraps a [`LoginBuilder`], so it
+/// can be composed with `tower` middleware such as timeouts, rate limits,
+/// retries or load shedding. It is always ready, and every call logs in with
+/// its own copy of the builder, so calls run concurrently and do not wait for
+/// each other. Clones share whatever the builder shares between its clones,
+/// such as an attached rate limiter, observer or clock; everything else,
+/// including the HTTP client that is built for each login, is independent.
+///
+/// # Examples
+///
+/// ```no_run
+/// use niconico::{Credentials, LoginBuilder, LoginService};
+/// use tower_service::Service;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), niconico::LoginError> {
+/// let mut service = LoginService::new(LoginBuilder::new());
+/// let credentials = Credentials {
+///     mail_tel: "user@example.com".to_string(),
+///     password: "password".into(),
+/// };
+///
+/// std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+/// let user_session = service.call(credentials).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LoginService {
+    builder: LoginBuilder,
+}
+
+impl LoginService {
+    /// Creates a service that logs in with the settings of the builder
+    pub fn new(builder: LoginBuilder) -> Self {
+        Self { builder }
+    }
+
+    /// Returns the builder the service logs in with
+    pub fn builder(&self) -> &LoginBuilder {
+        &self.builder
+    }
+}
+
+impl From<LoginBuilder> for LoginService {
+    fn from(builder: LoginBuilder) -> Self {
+        Self::new(builder)
+    }
+}
+
+impl Service<Credentials> for LoginService {
+    type Response = crate::UserSession;
+    type Error = LoginError;
+    type Future = Pin<Box<dyn Future<Output = LoginResult> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, credentials: Credentials) -> Self::Future {
+        let builder = self.builder.clone();
+        Box::pin(async move { builder.login(&credentials).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_session_token_eq, serve_once, LOGIN_OK_RESPONSE};
+    use reqwest::Url;
+
+    /// Tests that a call logs in with the builder and the credentials of the request
+    #[tokio::test]
+    async fn test_call() {
+        let (url, request) = serve_once(LOGIN_OK_RESPONSE).await;
+        let mut service =
+            LoginService::from(LoginBuilder::new().base_urls([Url::parse(&url).unwrap()]));
+        let credentials = Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "password".into(),
+        };
+
+        std::future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap();
+        let user_session = service.call(credentials).await.unwrap();
+
+        assert_session_token_eq(&user_session, "user_session_123");
+        assert!(request
+            .await
+            .unwrap()
+            .contains("mail_tel=user%40example.com"));
+    }
+}
@@ -0,0 +1,1128 @@
+//! The session obtained by logging in
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::Url;
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::{
+    cookie, Clock, CookieDiagnostics, LoginError, LoginTimings, LoginWarning, SystemClock, UserId,
+};
+
+/// Represents a successful login session
+#[derive(Debug, Clone)]
+pub struct UserSession {
+    /// The `Set-Cookie` header value of the `user_session` cookie
+    pub user_session: SecretString,
+    /// The other cookies set alongside the session, keyed by name
+    cookies: BTreeMap<String, SecretString>,
+    /// Application-defined label, which this crate does not interpret
+    label: Option<String>,
+    /// The CSRF token of the session, if fetched
+    csrf_token: Option<SecretString>,
+    /// The other `user_session` cookies set alongside the session
+    alternate_sessions: Vec<SecretString>,
+    /// When the session cookie was received, which `Max-Age` counts from
+    received_at: SystemTime,
+}
+
+/// A successful login together with the account states noticed on the way
+#[derive(Debug, Clone)]
+pub struct LoginDetails {
+    /// The session obtained by logging in
+    pub user_session: UserSession,
+    /// Account states that did not prevent the login but deserve attention
+    pub warnings: Vec<LoginWarning>,
+    /// The target Niconico redirected to, resolved against the account domain
+    pub location: Option<Url>,
+    /// Every `Set-Cookie` header of the login response, when capturing them
+    /// was enabled with
+    /// [`LoginBuilder::capture_raw_set_cookies`](crate::LoginBuilder::capture_raw_set_cookies)
+    pub raw_set_cookies: Option<RawSetCookies>,
+    /// Where the time of the login was spent, when measuring it was enabled
+    /// with [`LoginBuilder::capture_timings`](crate::LoginBuilder::capture_timings)
+    pub timings: Option<LoginTimings>,
+    /// The time Niconico reported in the `Date` header of the login response,
+    /// if it was present and valid
+    pub server_date: Option<SystemTime>,
+    /// How far and in which direction the local clock was from
+    /// [`server_date`](Self::server_date) when the response was received
+    ///
+    /// The `Date` header has a resolution of one second, so a skew below that
+    /// is noise. A large skew makes expiry checks such as
+    /// [`UserSession::is_expired`] wrong by the same amount.
+    pub clock_skew: Option<ClockSkew>,
+}
+
+/// How the local clock compared with the time Niconico reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSkew {
+    /// The local clock was ahead of Niconico by the duration
+    Ahead(Duration),
+    /// The local clock was behind Niconico by the duration
+    Behind(Duration),
+}
+
+impl ClockSkew {
+    /// Returns the skew between the local clock and `server_date`
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The local time
+    /// * `server_date` - The time the server reported
+    pub fn between(now: SystemTime, server_date: SystemTime) -> Self {
+        match now.duration_since(server_date) {
+            Ok(ahead) => ClockSkew::Ahead(ahead),
+            Err(behind) => ClockSkew::Behind(behind.duration()),
+        }
+    }
+
+    /// Returns how far the clocks were apart, regardless of the direction
+    pub fn duration(self) -> Duration {
+        match self {
+            ClockSkew::Ahead(duration) | ClockSkew::Behind(duration) => duration,
+        }
+    }
+}
+
+/// The raw `Set-Cookie` header values of a response
+///
+/// The values carry the session token, so they are redacted from `Debug`
+/// output and only available through [`expose`](Self::expose).
+#[derive(Clone, Default)]
+pub struct RawSetCookies(Vec<String>);
+
+impl RawSetCookies {
+    /// Collects the `Set-Cookie` header values of a response
+    ///
+    /// Values that are not valid UTF-8 are converted lossily.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        Self(
+            headers
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .collect(),
+        )
+    }
+
+    /// Returns the header values, in the order they were received
+    pub fn expose(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for RawSetCookies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RawSetCookies([REDACTED; {}])", self.0.len())
+    }
+}
+
+/// Host that sets the session cookie, which a cookie without `Domain` is limited to
+const SESSION_HOST: &str = "account.nicovideo.jp";
+
+/// URL of the host that sets the session cookie
+pub(crate) const SESSION_URL: &str = "https://account.nicovideo.jp/";
+
+/// Prefix shared by every session token, which carries no secret information
+const TOKEN_PREFIX: &str = "user_session_";
+
+/// Placeholder shown in place of the hidden part of a token
+const MASK: &str = "••••••";
+
+impl UserSession {
+    /// Creates a session from the `Set-Cookie` header value of the `user_session` cookie
+    ///
+    /// The cookie counts as received now according to the [`SystemClock`],
+    /// which is where a `Max-Age` attribute counts from.
+    pub fn new(user_session: SecretString) -> Self {
+        Self {
+            user_session,
+            cookies: BTreeMap::new(),
+            label: None,
+            csrf_token: None,
+            alternate_sessions: Vec::new(),
+            received_at: SystemClock.now(),
+        }
+    }
+
+    /// Records when the session cookie was received, according to a clock
+    pub(crate) fn received_with(mut self, clock: &dyn Clock) -> Self {
+        self.received_at = clock.now();
+        self
+    }
+
+    /// Returns the `Set-Cookie` header value of the `user_session` cookie
+    ///
+    /// This is the same secret as the [`user_session`](Self::user_session)
+    /// field, e.g. `user_session=user_session_123; Path=/`.
+    pub fn token(&self) -> &SecretString {
+        &self.user_session
+    }
+
+    /// Attaches an application-defined label, e.g. the name of the account
+    ///
+    /// The label is kept for bookkeeping when managing many sessions and is
+    /// otherwise ignored by this crate. It survives clones and, with the
+    /// `serde` feature, serialization.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Returns the application-defined label, if any
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Stores the CSRF token of the session, e.g. from [`fetch_csrf_token`](crate::fetch_csrf_token)
+    pub fn with_csrf_token(mut self, csrf_token: SecretString) -> Self {
+        self.csrf_token = Some(csrf_token);
+        self
+    }
+
+    /// Returns the CSRF token of the session, if one was stored
+    pub fn csrf_token(&self) -> Option<&SecretString> {
+        self.csrf_token.as_ref()
+    }
+
+    /// Returns the `Set-Cookie` header value of another cookie held by the session
+    pub fn cookie(&self, name: &str) -> Option<&SecretString> {
+        self.cookies.get(name)
+    }
+
+    /// Returns the names of the cookies held by the session, never their values
+    ///
+    /// `user_session` comes first unless the session was cleared, followed
+    /// by the other cookies in alphabetical order. The names are safe to log,
+    /// e.g. to check whether Niconico sent `nicosid` or `user_session_secure`.
+    pub fn cookie_names(&self) -> Vec<String> {
+        let session = (!self.token_value().is_empty()).then(|| "user_session".to_string());
+        session
+            .into_iter()
+            .chain(self.cookies.keys().cloned())
+            .collect()
+    }
+
+    /// Returns whether the session holds the `user_session_secure` cookie
+    ///
+    /// Some HTTPS-only endpoints require this cookie in addition to
+    /// `user_session` and answer `403 Forbidden` without it, so check this
+    /// before choosing such an endpoint. A cookie without a value does not
+    /// count.
+    pub fn has_secure(&self) -> bool {
+        self.cookies
+            .get(SECURE_COOKIE)
+            .is_some_and(|cookie_str| !cookie::value(cookie_str.expose_secret()).is_empty())
+    }
+
+    /// Returns the `Set-Cookie` header values of the other session cookies of the login response
+    ///
+    /// A load-balanced backend may set several `user_session` cookies scoped
+    /// to different domains or paths. The most broadly scoped one becomes
+    /// the session, and the others are kept here in the order they were set.
+    pub fn alternate_session_cookies(&self) -> &[SecretString] {
+        &self.alternate_sessions
+    }
+
+    /// Makes one of the [`alternate_session_cookies`](Self::alternate_session_cookies) the session
+    ///
+    /// The previous session cookie takes the place of the selected one among
+    /// the alternates.
+    ///
+    /// # Returns
+    ///
+    /// Returns `false` without changing the session if there is no alternate
+    /// at `index`.
+    pub fn select_alternate_session(&mut self, index: usize) -> bool {
+        let Some(alternate) = self.alternate_sessions.get_mut(index) else {
+            return false;
+        };
+        std::mem::swap(&mut self.user_session, alternate);
+        true
+    }
+
+    /// Keeps another session cookie set by the login response
+    pub(crate) fn add_alternate_session(&mut self, cookie_str: &str) {
+        self.alternate_sessions.push(cookie_str.into());
+    }
+
+    /// Folds the cookies set by a later response into the session
+    ///
+    /// Cookies replace any previous cookie of the same name and new cookies
+    /// are added. A `user_session` cookie only replaces the session if it
+    /// carries a session token, so a deletion cookie does not discard it.
+    /// `Set-Cookie` headers that are not valid UTF-8 are skipped, and headers
+    /// folded by a proxy are split into their cookies. A replaced session
+    /// counts as received now according to the [`SystemClock`]; see
+    /// [`merge_cookies_with`](Self::merge_cookies_with).
+    pub fn merge_cookies(&mut self, headers: &HeaderMap) {
+        self.merge_cookies_with(headers, &SystemClock);
+    }
+
+    /// Folds the cookies set by a later response into the session, reading the time from a clock
+    ///
+    /// See [`merge_cookies`](Self::merge_cookies). A replaced session counts
+    /// as received at the time of the clock, which is where its `Max-Age`
+    /// attribute counts from.
+    pub fn merge_cookies_with(&mut self, headers: &HeaderMap, clock: &dyn Clock) {
+        for header_value in headers.get_all(header::SET_COOKIE) {
+            if let Ok(header_str) = header_value.to_str() {
+                for cookie_str in cookie::split_folded(header_str) {
+                    self.set_cookie_with(cookie_str, clock);
+                }
+            }
+        }
+    }
+
+    /// Stores a cookie set by a `Set-Cookie` header value
+    pub(crate) fn set_cookie(&mut self, cookie_str: &str) {
+        self.set_cookie_with(cookie_str, &SystemClock);
+    }
+
+    /// Stores a cookie set by a `Set-Cookie` header value, received at the time of a clock
+    fn set_cookie_with(&mut self, cookie_str: &str, clock: &dyn Clock) {
+        match cookie::name(cookie_str) {
+            "user_session" if cookie::value(cookie_str).starts_with(TOKEN_PREFIX) => {
+                self.user_session = cookie_str.into();
+                self.received_at = clock.now();
+            }
+            "user_session" => {}
+            name => {
+                self.cookies.insert(name.to_string(), cookie_str.into());
+            }
+        }
+    }
+
+    /// Wipes the session token and the other cookies from memory
+    ///
+    /// The token is replaced with an empty one and the other cookies and the
+    /// CSRF token are removed, which zeroizes their previous values. The label is kept. A
+    /// cleared session carries no token and is rejected by Niconico, so use
+    /// this once the token has been handed off or is no longer needed.
+    pub fn clear(&mut self) {
+        self.user_session = SecretString::from("");
+        self.cookies.clear();
+        self.csrf_token = None;
+        self.alternate_sessions.clear();
+    }
+
+    /// Returns a preview of the session token that is safe to display
+    ///
+    /// Only the first and last two characters of the secret part of the token
+    /// are shown, e.g. `user_session_12••••••34`. Tokens too short for this to
+    /// hide most of the secret are masked entirely.
+    pub fn masked_preview(&self) -> String {
+        let token = self.token_value();
+        let (prefix, secret) = token.split_at(if token.starts_with(TOKEN_PREFIX) {
+            TOKEN_PREFIX.len()
+        } else {
+            0
+        });
+
+        let chars: Vec<char> = secret.chars().collect();
+        if chars.len() < 12 {
+            return format!("{prefix}{MASK}");
+        }
+
+        let head: String = chars[..2].iter().collect();
+        let tail: String = chars[chars.len() - 2..].iter().collect();
+        format!("{prefix}{head}{MASK}{tail}")
+    }
+
+    /// Returns the time at which the session cookie expires
+    ///
+    /// When the cookie has a valid `Max-Age` attribute, the expiry is that long
+    /// after the cookie was received, and `Expires` is ignored as the cookie
+    /// specification requires. Otherwise it is read from the `Expires`
+    /// attribute. `None` means that neither attribute is present and valid.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        cookie_expires_at(self.user_session.expose_secret(), self.received_at)
+    }
+
+    /// Returns the lifetime given by the `Max-Age` attribute of the session cookie
+    ///
+    /// A zero or negative `Max-Age`, which asks for the cookie to be removed,
+    /// is a zero duration. `None` means that the attribute is missing or could
+    /// not be parsed.
+    pub fn max_age(&self) -> Option<Duration> {
+        cookie_max_age(self.user_session.expose_secret())
+    }
+
+    /// Returns how long the session cookie remains valid
+    ///
+    /// `None` means that the expiry is not known, and an expired session
+    /// returns a zero duration.
+    pub fn time_to_expiry(&self) -> Option<Duration> {
+        self.time_to_expiry_with(&SystemClock)
+    }
+
+    /// Returns how long the session cookie remains valid according to a clock
+    ///
+    /// See [`time_to_expiry`](Self::time_to_expiry).
+    pub fn time_to_expiry_with(&self, clock: &dyn Clock) -> Option<Duration> {
+        let expires_at = self.expires_at()?;
+        Some(expires_at.duration_since(clock.now()).unwrap_or_default())
+    }
+
+    /// Returns whether the session cookie has expired
+    ///
+    /// A session without a known expiry is never considered expired.
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_with(&SystemClock)
+    }
+
+    /// Returns whether the session cookie has expired according to a clock
+    ///
+    /// See [`is_expired`](Self::is_expired).
+    pub fn is_expired_with(&self, clock: &dyn Clock) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= clock.now())
+    }
+
+    /// Returns whether the session cookie would be sent to a URL
+    ///
+    /// This follows the cookie matching rules using the `Domain`, `Path` and
+    /// `Secure` attributes of the cookie. A cookie without a `Domain` attribute
+    /// only applies to `account.nicovideo.jp`, the host that set it, and one
+    /// without a `Path` attribute applies to every path.
+    pub fn applies_to(&self, url: &Url) -> bool {
+        cookie_applies_to(self.user_session.expose_secret(), url)
+    }
+
+    /// Exports the session and its other cookies in the Netscape `cookies.txt` format
+    ///
+    /// Browsers and tools such as curl or yt-dlp can import the result. The
+    /// domain, path, secure flag and expiry of every cookie are taken from its
+    /// attributes, with `account.nicovideo.jp` for cookies without a `Domain`
+    /// attribute. The expiry follows [`expires_at`](Self::expires_at), so a
+    /// `Max-Age` is exported as the time it ends at, counted from when the
+    /// session was received, and cookies with neither `Max-Age` nor `Expires`
+    /// are exported as session cookies. The output contains the session token,
+    /// so treat it as a secret.
+    pub fn to_netscape_cookies(&self) -> String {
+        let mut netscape = String::from("# Netscape HTTP Cookie File\n");
+        for cookie_str in self.cookie_strs() {
+            netscape += &netscape_line(cookie_str, cookie_expires_at(cookie_str, self.received_at));
+            netscape.push('\n');
+        }
+        netscape
+    }
+
+    /// Imports a session from the Netscape `cookies.txt` format
+    ///
+    /// Only cookies for `nicovideo.jp` and its subdomains are kept, so a file
+    /// exported from a whole browser profile can be used. Comments and
+    /// malformed lines are skipped, while lines marked with `#HttpOnly_` are
+    /// read as cookies.
+    ///
+    /// # Arguments
+    ///
+    /// * `netscape` - The contents of a `cookies.txt` file
+    ///
+    /// # Returns
+    ///
+    /// Returns the session, or `LoginError::UserSessionNotFound` if there is
+    /// no `user_session` cookie carrying a session token.
+    pub fn from_netscape_cookies(netscape: &str) -> Result<Self, LoginError> {
+        let mut diagnostics = CookieDiagnostics::default();
+        let mut session: Option<Self> = None;
+        let mut others = Vec::new();
+
+        for cookie_str in netscape.lines().filter_map(parse_netscape_line) {
+            diagnostics.set_cookie_count += 1;
+            diagnostics
+                .cookie_names
+                .push(cookie::name(&cookie_str).to_string());
+
+            match &mut session {
+                Some(session) => session.set_cookie(&cookie_str),
+                None if cookie::name(&cookie_str) == "user_session"
+                    && cookie::value(&cookie_str).starts_with(TOKEN_PREFIX) =>
+                {
+                    session = Some(Self::new(cookie_str.into()));
+                }
+                None => others.push(cookie_str),
+            }
+        }
+
+        let mut session = session.ok_or(LoginError::UserSessionNotFound(diagnostics))?;
+        for cookie_str in others {
+            session.set_cookie(&cookie_str);
+        }
+        Ok(session)
+    }
+
+    /// Returns the `Set-Cookie` header values of the session and its other cookies
+    pub(crate) fn cookie_strs(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(&self.user_session)
+            .chain(self.cookies.values())
+            .map(ExposeSecret::expose_secret)
+    }
+
+    /// Returns a `Cookie` header value carrying the session and its other cookies
+    pub(crate) fn cookie_header(&self) -> Result<HeaderValue, LoginError> {
+        let mut cookie_header = format!("user_session={}", self.token_value());
+        for (name, cookie_str) in &self.cookies {
+            cookie_header += &format!("; {name}={}", cookie::value(cookie_str.expose_secret()));
+        }
+
+        let mut value =
+            HeaderValue::from_str(&cookie_header).map_err(|_| LoginError::InvalidSessionToken)?;
+        value.set_sensitive(true);
+
+        Ok(value)
+    }
+
+    /// Returns a `Cookie` header value carrying the cookies that would be sent to a URL, if any
+    ///
+    /// Unlike [`cookie_header`](Self::cookie_header), this follows the
+    /// scoping rules of [`applies_to`](Self::applies_to) for every cookie, so
+    /// that a redirect only receives the cookies meant for its target.
+    pub(crate) fn cookie_header_for(&self, url: &Url) -> Result<Option<HeaderValue>, LoginError> {
+        let cookie_pairs: Vec<_> = std::iter::once(("user_session", &self.user_session))
+            .chain(
+                self.cookies
+                    .iter()
+                    .map(|(name, cookie_str)| (name.as_str(), cookie_str)),
+            )
+            .map(|(name, cookie_str)| (name, cookie_str.expose_secret()))
+            .filter(|(_, cookie_str)| cookie_applies_to(cookie_str, url))
+            .map(|(name, cookie_str)| format!("{name}={}", cookie::value(cookie_str)))
+            .collect();
+        if cookie_pairs.is_empty() {
+            return Ok(None);
+        }
+
+        let mut value = HeaderValue::from_str(&cookie_pairs.join("; "))
+            .map_err(|_| LoginError::InvalidSessionToken)?;
+        value.set_sensitive(true);
+
+        Ok(Some(value))
+    }
+
+    /// Returns the account ID embedded in the token, `user_session_<id>_<secret>`, if any
+    pub(crate) fn embedded_user_id(&self) -> Option<UserId> {
+        let (user_id, secret) = self
+            .token_value()
+            .strip_prefix(TOKEN_PREFIX)?
+            .split_once('_')?;
+        if secret.is_empty() {
+            return None;
+        }
+        user_id.parse().ok()
+    }
+
+    /// Returns whether the session carries a session token, unlike a cleared one
+    pub(crate) fn has_token(&self) -> bool {
+        self.token_value().starts_with(TOKEN_PREFIX)
+    }
+
+    /// Returns the value of the `user_session` cookie without its attributes
+    fn token_value(&self) -> &str {
+        cookie::value(self.user_session.expose_secret())
+    }
+}
+
+/// Name of the cookie that HTTPS-only endpoints expect alongside the session
+const SECURE_COOKIE: &str = "user_session_secure";
+
+/// Domain that the cookies of an imported session must belong to
+pub(crate) const COOKIE_DOMAIN: &str = "nicovideo.jp";
+
+/// Returns whether a cookie would be sent to a URL, see [`UserSession::applies_to`]
+fn cookie_applies_to(cookie_str: &str, url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.to_ascii_lowercase();
+
+    let domain_matches = match cookie::attribute(cookie_str, "Domain") {
+        Some(domain) => {
+            let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+            !domain.is_empty()
+                && (host == domain
+                    || host
+                        .strip_suffix(&domain)
+                        .is_some_and(|subdomain| subdomain.ends_with('.')))
+        }
+        None => host == SESSION_HOST,
+    };
+
+    let path = cookie::attribute(cookie_str, "Path")
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or("/");
+    let path_matches = url.path() == path
+        || url
+            .path()
+            .strip_prefix(path)
+            .is_some_and(|rest| path.ends_with('/') || rest.starts_with('/'));
+
+    let secure_matches = !cookie::flag(cookie_str, "Secure") || url.scheme() == "https";
+
+    domain_matches && path_matches && secure_matches
+}
+
+/// Returns the time at which a cookie received at a given time expires, see [`UserSession::expires_at`]
+fn cookie_expires_at(cookie_str: &str, received_at: SystemTime) -> Option<SystemTime> {
+    if let Some(max_age) = cookie_max_age(cookie_str) {
+        return received_at.checked_add(max_age);
+    }
+    let expires = cookie::attribute(cookie_str, "Expires")?;
+    httpdate::parse_http_date(expires).ok()
+}
+
+/// Returns the lifetime given by the `Max-Age` attribute of a cookie, see [`UserSession::max_age`]
+fn cookie_max_age(cookie_str: &str) -> Option<Duration> {
+    let max_age = cookie::attribute(cookie_str, "Max-Age")?;
+    let seconds: i64 = max_age.parse().ok()?;
+    Some(Duration::from_secs(seconds.max(0).unsigned_abs()))
+}
+
+/// Formats a `Set-Cookie` header value as a line of a Netscape `cookies.txt` file, with its expiry
+fn netscape_line(cookie_str: &str, expires_at: Option<SystemTime>) -> String {
+    let (domain, include_subdomains) = match cookie::attribute(cookie_str, "Domain") {
+        Some(domain) => (format!(".{}", domain.trim_start_matches('.')), "TRUE"),
+        None => (SESSION_HOST.to_string(), "FALSE"),
+    };
+    let domain = if cookie::flag(cookie_str, "HttpOnly") {
+        format!("#HttpOnly_{domain}")
+    } else {
+        domain
+    };
+    let path = cookie::attribute(cookie_str, "Path")
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or("/");
+    let secure = if cookie::flag(cookie_str, "Secure") {
+        "TRUE"
+    } else {
+        "FALSE"
+    };
+    let expires = expires_at
+        .and_then(|expires| expires.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |expires| expires.as_secs());
+
+    format!(
+        "{domain}\t{include_subdomains}\t{path}\t{secure}\t{expires}\t{}\t{}",
+        cookie::name(cookie_str),
+        cookie::value(cookie_str)
+    )
+}
+
+/// Parses a line of a Netscape `cookies.txt` file into a `Set-Cookie` header value
+///
+/// Returns `None` for comments, malformed lines and cookies of other domains.
+fn parse_netscape_line(line: &str) -> Option<String> {
+    let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+        Some(line) => (line, true),
+        None if line.starts_with('#') => return None,
+        None => (line, false),
+    };
+    let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+    let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else {
+        return None;
+    };
+
+    if !cookie::in_domain(domain, COOKIE_DOMAIN) || name.is_empty() {
+        return None;
+    }
+    let host = domain.trim_start_matches('.').to_ascii_lowercase();
+
+    let mut cookie_str = format!("{name}={value}");
+    if include_subdomains.eq_ignore_ascii_case("TRUE") {
+        cookie_str += &format!("; Domain={host}");
+    }
+    cookie_str += &format!("; Path={path}");
+    if let Some(expires) = expires.parse::<u64>().ok().filter(|&expires| expires > 0) {
+        let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(expires);
+        cookie_str += &format!("; Expires={}", httpdate::fmt_http_date(expires));
+    }
+    if secure.eq_ignore_ascii_case("TRUE") {
+        cookie_str += "; Secure";
+    }
+    if http_only {
+        cookie_str += "; HttpOnly";
+    }
+    Some(cookie_str)
+}
+
+/// The serialized form of a [`UserSession`], with its secrets exposed
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedSession {
+    user_session: String,
+    #[serde(default)]
+    cookies: BTreeMap<String, String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    csrf_token: Option<String>,
+    #[serde(default)]
+    alternate_sessions: Vec<String>,
+    #[serde(default = "SystemTime::now")]
+    received_at: SystemTime,
+}
+
+/// Serializes the session with its token, other cookies and label
+///
+/// Requires the `serde` feature. The output exposes the secrets of the
+/// session, so store it as carefully as the session itself.
+#[cfg(feature = "serde")]
+impl serde::Serialize for UserSession {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let expose = |secret: &SecretString| secret.expose_secret().to_string();
+        SerializedSession {
+            user_session: expose(&self.user_session),
+            cookies: self
+                .cookies
+                .iter()
+                .map(|(name, cookie_str)| (name.clone(), expose(cookie_str)))
+                .collect(),
+            label: self.label.clone(),
+            csrf_token: self.csrf_token.as_ref().map(expose),
+            alternate_sessions: self.alternate_sessions.iter().map(expose).collect(),
+            received_at: self.received_at,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Deserializes a session written by its `Serialize` implementation
+///
+/// Requires the `serde` feature.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for UserSession {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let session = SerializedSession::deserialize(deserializer)?;
+        Ok(Self {
+            user_session: session.user_session.into(),
+            cookies: session
+                .cookies
+                .into_iter()
+                .map(|(name, cookie_str)| (name, cookie_str.into()))
+                .collect(),
+            label: session.label,
+            csrf_token: session.csrf_token.map(Into::into),
+            alternate_sessions: session
+                .alternate_sessions
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            received_at: session.received_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that only the names of the cookies are listed
+    #[test]
+    fn test_cookie_names() {
+        let mut user_session = UserSession::new("user_session=user_session_secret1".into());
+        let mut headers = HeaderMap::new();
+        for cookie_str in ["nicosid=secret2; Path=/", "user_session_secure=secret3"] {
+            headers.append(header::SET_COOKIE, HeaderValue::from_static(cookie_str));
+        }
+        user_session.merge_cookies(&headers);
+
+        let names = user_session.cookie_names();
+        assert_eq!(names, ["user_session", "nicosid", "user_session_secure"]);
+        assert!(names.iter().all(|name| !name.contains("secret")));
+
+        user_session.clear();
+        assert!(user_session.cookie_names().is_empty());
+    }
+
+    /// Tests that the masked preview only shows the edges of a long token
+    #[test]
+    fn test_masked_preview_long_token() {
+        let session =
+            UserSession::new("user_session=user_session_12345678_abcdef34; Path=/".into());
+        assert_eq!(session.masked_preview(), "user_session_12••••••34");
+    }
+
+    /// Tests that the masked preview reveals nothing of a short token
+    #[test]
+    fn test_masked_preview_short_token() {
+        let session = UserSession::new("user_session=user_session_1234".into());
+        assert_eq!(session.masked_preview(), "user_session_••••••");
+    }
+
+    /// Tests that only the cookies scoped to a URL are put in its Cookie header
+    #[test]
+    fn test_cookie_header_for() {
+        let mut session = UserSession::new(
+            "user_session=user_session_123; Domain=.nicovideo.jp; Path=/; Secure".into(),
+        );
+        session.set_cookie("nicosid=1; Domain=.nicovideo.jp; Path=/");
+        session.set_cookie("account_only=2; Path=/");
+        let header_for = |url: &str| {
+            session
+                .cookie_header_for(&Url::parse(url).unwrap())
+                .unwrap()
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        assert_eq!(
+            header_for("https://account.nicovideo.jp/").as_deref(),
+            Some("user_session=user_session_123; account_only=2; nicosid=1")
+        );
+        assert_eq!(
+            header_for("https://www.nicovideo.jp/").as_deref(),
+            Some("user_session=user_session_123; nicosid=1")
+        );
+        assert_eq!(
+            header_for("http://www.nicovideo.jp/").as_deref(),
+            Some("nicosid=1")
+        );
+        assert_eq!(header_for("https://example.com/"), None);
+    }
+
+    /// Tests that the expiry is read from the Expires attribute
+    #[test]
+    fn test_expires_at() {
+        let session = UserSession::new(
+            "user_session=user_session_123; expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/".into(),
+        );
+
+        assert_eq!(
+            session.expires_at(),
+            Some(httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap())
+        );
+        assert!(session.is_expired());
+        assert_eq!(session.time_to_expiry(), Some(Duration::ZERO));
+        assert_eq!(session.max_age(), None);
+    }
+
+    /// Tests that the expiry is counted from the receipt of a cookie with only Max-Age
+    #[test]
+    fn test_max_age() {
+        let clock = crate::FakeClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let session = UserSession::new("user_session=user_session_123; Max-Age=90; Path=/".into())
+            .received_with(&clock);
+
+        assert_eq!(session.max_age(), Some(Duration::from_secs(90)));
+        assert_eq!(
+            session.expires_at(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_090))
+        );
+        assert_eq!(
+            session.time_to_expiry_with(&clock),
+            Some(Duration::from_secs(90))
+        );
+
+        clock.advance(Duration::from_secs(90));
+        assert!(session.is_expired_with(&clock));
+
+        for (max_age, expected) in [
+            ("0", Some(Duration::ZERO)),
+            ("-1", Some(Duration::ZERO)),
+            ("soon", None),
+            ("", None),
+        ] {
+            let session = UserSession::new(
+                format!("user_session=user_session_123; Max-Age={max_age}").into(),
+            );
+            assert_eq!(session.max_age(), expected, "{max_age}");
+        }
+    }
+
+    /// Tests that Max-Age takes precedence over Expires when both are present
+    #[test]
+    fn test_max_age_over_expires() {
+        let clock = crate::FakeClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let session = UserSession::new(
+            "user_session=user_session_123; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Max-Age=90"
+                .into(),
+        )
+        .received_with(&clock);
+
+        assert_eq!(
+            session.time_to_expiry_with(&clock),
+            Some(Duration::from_secs(90))
+        );
+
+        let session = UserSession::new(
+            "user_session=user_session_123; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Max-Age=never"
+                .into(),
+        )
+        .received_with(&clock);
+        assert_eq!(
+            session.expires_at(),
+            Some(httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap())
+        );
+    }
+
+    /// Tests that a session without an Expires attribute never expires
+    #[test]
+    fn test_expires_at_missing() {
+        let session = UserSession::new("user_session=user_session_123; Path=/".into());
+
+        assert_eq!(session.expires_at(), None);
+        assert_eq!(session.time_to_expiry(), None);
+        assert!(!session.is_expired());
+    }
+
+    /// Tests that the expiry follows a fake clock across the boundary
+    #[test]
+    fn test_expiry_with_fake_clock() {
+        let session = UserSession::new(
+            "user_session=user_session_123; Expires=Wed, 21 Oct 2037 07:28:00 GMT".into(),
+        );
+        let expires_at = session.expires_at().unwrap();
+        let clock = crate::FakeClock::new(expires_at - Duration::from_secs(90));
+
+        assert!(!session.is_expired_with(&clock));
+        assert_eq!(
+            session.time_to_expiry_with(&clock),
+            Some(Duration::from_secs(90))
+        );
+
+        clock.advance(Duration::from_secs(90));
+        assert!(session.is_expired_with(&clock));
+        assert_eq!(session.time_to_expiry_with(&clock), Some(Duration::ZERO));
+    }
+
+    /// Tests that a cookie scoped to the parent domain applies to every subdomain
+    #[test]
+    fn test_applies_to_domain() {
+        let session = UserSession::new(
+            "user_session=user_session_123; Domain=.nicovideo.jp; Path=/; Secure".into(),
+        );
+        let applies_to = |url: &str| session.applies_to(&Url::parse(url).unwrap());
+
+        assert!(applies_to("https://www.nicovideo.jp/watch/sm9"));
+        assert!(applies_to("https://sp.nicovideo.jp/"));
+        assert!(applies_to("https://nicovideo.jp/"));
+        assert!(!applies_to("http://www.nicovideo.jp/"));
+        assert!(!applies_to("https://notnicovideo.jp/"));
+        assert!(!applies_to("https://nicovideo.jp.example.com/"));
+    }
+
+    /// Tests that a cookie without Domain is host-only and that Path is honored
+    #[test]
+    fn test_applies_to_host_only_and_path() {
+        let session = UserSession::new("user_session=user_session_123; Path=/my".into());
+        let applies_to = |url: &str| session.applies_to(&Url::parse(url).unwrap());
+
+        assert!(applies_to("https://account.nicovideo.jp/my"));
+        assert!(applies_to("https://account.nicovideo.jp/my/history"));
+        assert!(!applies_to("https://account.nicovideo.jp/mylist"));
+        assert!(!applies_to("https://account.nicovideo.jp/"));
+        assert!(!applies_to("https://www.nicovideo.jp/my"));
+    }
+
+    /// Tests that the token accessor returns the session cookie
+    #[test]
+    fn test_token() {
+        let session = UserSession::new("user_session=user_session_123; Path=/".into());
+
+        assert_eq!(
+            session.token().expose_secret(),
+            "user_session=user_session_123; Path=/"
+        );
+        assert_eq!(
+            session.token().expose_secret(),
+            session.user_session.expose_secret()
+        );
+    }
+
+    /// Tests that only a secure cookie with a value counts
+    #[test]
+    fn test_has_secure() {
+        let mut session = UserSession::new("user_session=user_session_123".into());
+        assert!(!session.has_secure());
+
+        session.set_cookie("user_session_secure=; Max-Age=0");
+        assert!(!session.has_secure());
+
+        session.set_cookie("user_session_secure=abc; Secure");
+        assert!(session.has_secure());
+    }
+
+    /// Tests that merging replaces cookies of the same name and adds new ones
+    #[test]
+    fn test_merge_cookies() {
+        let mut session = UserSession::new("user_session=user_session_old".into());
+        session.set_cookie("nicosid=old");
+
+        let mut headers = HeaderMap::new();
+        for cookie_str in [
+            "nicosid=new; Path=/",
+            "nicohistory=added",
+            "user_session=user_session_new; Path=/",
+        ] {
+            headers.append(header::SET_COOKIE, HeaderValue::from_static(cookie_str));
+        }
+        session.merge_cookies(&headers);
+
+        assert_eq!(session.token_value(), "user_session_new");
+        assert_eq!(
+            session.cookie("nicosid").unwrap().expose_secret(),
+            "nicosid=new; Path=/"
+        );
+        assert!(session.cookie("nicohistory").is_some());
+        assert_eq!(
+            session.cookie_header().unwrap(),
+            "user_session=user_session_new; nicohistory=added; nicosid=new"
+        );
+    }
+
+    /// Tests that the raw Set-Cookie values are kept in order but redacted from Debug
+    #[test]
+    fn test_raw_set_cookies() {
+        let mut headers = HeaderMap::new();
+        for cookie_str in ["user_session=user_session_secret", "nicosid=123"] {
+            headers.append(header::SET_COOKIE, HeaderValue::from_static(cookie_str));
+        }
+
+        let raw = RawSetCookies::from_headers(&headers);
+        assert_eq!(
+            raw.expose(),
+            ["user_session=user_session_secret", "nicosid=123"]
+        );
+        assert_eq!(format!("{raw:?}"), "RawSetCookies([REDACTED; 2])");
+    }
+
+    /// Tests that the label is kept through clones and cookie updates
+    #[test]
+    fn test_label() {
+        let session = UserSession::new("user_session=user_session_123".into());
+        assert_eq!(session.label(), None);
+
+        let mut session = session.with_label("main account");
+        session.set_cookie("user_session=user_session_456");
+
+        assert_eq!(session.clone().label(), Some("main account"));
+    }
+
+    /// Tests that serde round-trips the token, the other cookies and the label
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let mut session = UserSession::new("user_session=user_session_123; Max-Age=60".into())
+            .with_label("main account");
+        session.set_cookie("nicosid=abc; Path=/");
+
+        let json = serde_json::to_string(&session).unwrap();
+        let restored: UserSession = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            restored.token().expose_secret(),
+            "user_session=user_session_123; Max-Age=60"
+        );
+        assert_eq!(
+            restored.cookie("nicosid").unwrap().expose_secret(),
+            "nicosid=abc; Path=/"
+        );
+        assert_eq!(restored.label(), Some("main account"));
+        assert_eq!(restored.expires_at(), session.expires_at());
+    }
+
+    /// Tests that clearing removes the token and the other cookies but keeps the label
+    #[test]
+    fn test_clear() {
+        let mut session =
+            UserSession::new("user_session=user_session_123".into()).with_label("main account");
+        session.set_cookie("nicosid=456");
+
+        session.clear();
+
+        assert!(session.user_session.expose_secret().is_empty());
+        assert!(session.cookie("nicosid").is_none());
+        assert_eq!(session.label(), Some("main account"));
+    }
+
+    /// Tests that a deletion cookie does not discard the session
+    #[test]
+    fn test_merge_cookies_ignores_session_deletion() {
+        let mut session = UserSession::new("user_session=user_session_123".into());
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("user_session=deleted; Max-Age=0"),
+        );
+        session.merge_cookies(&headers);
+
+        assert_eq!(session.token_value(), "user_session_123");
+        assert!(session.cookie("user_session").is_none());
+    }
+
+    /// Tests that a session survives exporting to and importing from cookies.txt
+    #[test]
+    fn test_netscape_cookies_round_trip() {
+        let mut session = UserSession::new(
+            "user_session=user_session_123; Expires=Wed, 21 Oct 2037 07:28:00 GMT; Domain=.nicovideo.jp; Path=/; Secure; HttpOnly"
+                .into(),
+        );
+        session.set_cookie("nicosid=456; Path=/my");
+
+        let netscape = session.to_netscape_cookies();
+        assert_eq!(
+            netscape,
+            "# Netscape HTTP Cookie File\n\
+             #HttpOnly_.nicovideo.jp\tTRUE\t/\tTRUE\t2139722880\tuser_session\tuser_session_123\n\
+             account.nicovideo.jp\tFALSE\t/my\tFALSE\t0\tnicosid\t456\n"
+        );
+
+        let imported = UserSession::from_netscape_cookies(&netscape).unwrap();
+        assert_eq!(imported.token_value(), "user_session_123");
+        assert_eq!(imported.expires_at(), session.expires_at());
+        assert!(imported.applies_to(&Url::parse("https://www.nicovideo.jp/").unwrap()));
+        assert!(!imported.applies_to(&Url::parse("http://www.nicovideo.jp/").unwrap()));
+        assert_eq!(
+            imported.cookie_header().unwrap(),
+            session.cookie_header().unwrap()
+        );
+        assert_eq!(imported.to_netscape_cookies(), netscape);
+    }
+
+    /// Tests that Max-Age is exported as the time it ends at, taking precedence over Expires
+    #[test]
+    fn test_to_netscape_cookies_max_age() {
+        let clock = crate::FakeClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let mut session = UserSession::new(
+            "user_session=user_session_123; Expires=Wed, 21 Oct 2037 07:28:00 GMT; Max-Age=90; Path=/"
+                .into(),
+        )
+        .received_with(&clock);
+        session.set_cookie("nicosid=456; Max-Age=30; Path=/");
+
+        assert_eq!(
+            session.to_netscape_cookies(),
+            "# Netscape HTTP Cookie File\n\
+             account.nicovideo.jp\tFALSE\t/\tFALSE\t1090\tuser_session\tuser_session_123\n\
+             account.nicovideo.jp\tFALSE\t/\tFALSE\t1030\tnicosid\t456\n"
+        );
+    }
+
+    /// Tests that cookies of other domains and malformed lines are skipped on import
+    #[test]
+    fn test_from_netscape_cookies_filters() {
+        let netscape = "# comment\n\
+             .example.com\tTRUE\t/\tFALSE\t0\tuser_session\tuser_session_other\n\
+             not a cookie line\n\
+             .nicovideo.jp\tTRUE\t/\tFALSE\t0\tnicosid\t456\r\n";
+
+        let Err(LoginError::UserSessionNotFound(diagnostics)) =
+            UserSession::from_netscape_cookies(netscape)
+        else {
+            panic!("expected UserSessionNotFound");
+        };
+        assert_eq!(diagnostics.cookie_names, ["nicosid"]);
+
+        let netscape =
+            format!("{netscape}.nicovideo.jp\tTRUE\t/\tFALSE\t0\tuser_session\tuser_session_123\n");
+        let imported = UserSession::from_netscape_cookies(&netscape).unwrap();
+        assert_eq!(
+            imported.cookie_header().unwrap(),
+            "user_session=user_session_123; nicosid=456"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Helpers shared by the unit tests
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use secrecy::ExposeSecret;
+
+use crate::{cookie, UserSession};
+
+/// A login response issuing the session `user_session_123`
+pub(crate) const LOGIN_OK_RESPONSE: &str =
+    "HTTP/1.1 302 Found\r\nSet-Cookie: user_session=user_session_123\r\nContent-Length: 0\r\n\r\n";
+
+/// Asserts that a session carries the expected token without revealing either on failure
+///
+/// The token is the value of the `user_session` cookie, e.g.
+/// `user_session_123`. On a mismatch only masked previews, the lengths and
+/// the position of the first difference are printed.
+#[track_caller]
+pub(crate) fn assert_session_token_eq(user_session: &UserSession, expected: &str) {
+    let actual = cookie::value(user_session.user_session.expose_secret());
+    if actual == expected {
+        return;
+    }
+
+    let first_difference = actual
+        .chars()
+        .zip(expected.chars())
+        .take_while(|(a, e)| a == e)
+        .count();
+    let expected_session = UserSession::new(format!("user_session={expected}").into());
+    panic!(
+        "session token mismatch: got {} ({} chars), expected {} ({} chars), first difference at char {}",
+        user_session.masked_preview(),
+        actual.chars().count(),
+        expected_session.masked_preview(),
+        expected.chars().count(),
+        first_difference,
+    );
+}
+
+/// Parses a recorded response from `tests/fixtures` into its status and headers
+///
+/// Fixtures hold the status line followed by one header per line.
+pub(crate) fn fixture(raw: &str) -> (StatusCode, HeaderMap) {
+    let mut lines = raw.lines();
+    let status_line = lines.next().expect("fixture has a status line");
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .expect("fixture has a valid status code");
+
+    let mut headers = HeaderMap::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').expect("fixture header has a colon");
+        headers.append(
+            HeaderName::from_bytes(name.trim().as_bytes()).expect("valid header name"),
+            HeaderValue::from_str(value.trim()).expect("valid header value"),
+        );
+    }
+
+    (status, headers)
+}
+
+/// Serves a single HTTP request on localhost with a canned response
+///
+/// Returns the base URL of the server and a handle resolving to the raw
+/// request head that was received.
+pub(crate) async fn serve_once(
+    response: &'static str,
+) -> (String, tokio::task::JoinHandle<String>) {
+    let (url, handle) = serve(vec![response]).await;
+    let handle = tokio::spawn(async move { handle.await.unwrap().remove(0) });
+
+    (url, handle)
+}
+
+/// Serves one HTTP request per canned response on localhost, in order
+///
+/// Returns the base URL of the server and a handle resolving to the raw
+/// request heads that were received.
+pub(crate) async fn serve(
+    responses: Vec<&'static str>,
+) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = socket.read(&mut buffer).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            socket.write_all(response.as_bytes()).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request).into_owned());
+        }
+        requests
+    });
+
+    (url, handle)
+}
+
+/// Serves the same response to every request over keep-alive connections
+///
+/// Returns the base URL of the server and a handle resolving to the number of
+/// connections that were accepted once `count` requests were answered.
+pub(crate) async fn serve_keep_alive(
+    response: &'static str,
+    count: usize,
+) -> (String, tokio::task::JoinHandle<usize>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = tokio::spawn(async move {
+        let (served, mut answered) = tokio::sync::mpsc::unbounded_channel();
+        let mut connections = 0;
+        let mut requests = 0;
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (mut socket, _) = accepted.unwrap();
+                    connections += 1;
+                    let served = served.clone();
+                    tokio::spawn(async move {
+                        let mut request = Vec::new();
+                        let mut buffer = [0; 1024];
+                        loop {
+                            let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                                match socket.read(&mut buffer).await {
+                                    Ok(0) | Err(_) => return,
+                                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                                }
+                                continue;
+                            };
+                            let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                            let content_length: usize = head
+                                .lines()
+                                .find_map(|line| line.strip_prefix("content-length:"))
+                                .map_or(0, |length| length.trim().parse().unwrap());
+                            while request.len() < end + 4 + content_length {
+                                match socket.read(&mut buffer).await {
+                                    Ok(0) | Err(_) => return,
+                                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                                }
+                            }
+                            request.drain(..end + 4 + content_length);
+                            socket.write_all(response.as_bytes()).await.unwrap();
+                            let _ = served.send(());
+                        }
+                    });
+                }
+                Some(()) = answered.recv() => {
+                    requests += 1;
+                    if requests == count {
+                        return connections;
+                    }
+                }
+            }
+        }
+    });
+
+    (url, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a mismatching token is reported without revealing either token
+    #[test]
+    fn test_assert_session_token_eq_masks_mismatch() {
+        const ACTUAL: &str = "user_session_actual_secret_0123";
+        const EXPECTED: &str = "user_session_expected_secret_0123";
+
+        let user_session = UserSession::new(format!("user_session={ACTUAL}; Path=/").into());
+        assert_session_token_eq(&user_session, ACTUAL);
+
+        let payload = std::panic::catch_unwind(|| {
+            assert_session_token_eq(&user_session, EXPECTED);
+        })
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+
+        assert!(message.contains("first difference at char 13"), "{message}");
+        assert!(!message.contains("actual_secret"));
+        assert!(!message.contains("expected_secret"));
+    }
+}
@@ -0,0 +1,205 @@
+//! An in-process stand-in for the Niconico login endpoint
+//!
+//! Requires the `testing` feature. [`MockNiconico`] answers the login request
+//! the way Niconico does for each [`MockOutcome`], so applications can test
+//! their handling of every outcome without network access or a real account.
+//!
+//! # Examples
+//!
+//! ```
+//! use niconico::testing::{MockNiconico, MockOutcome};
+//! use niconico::{Credentials, LoginError};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let niconico = MockNiconico::start(MockOutcome::BadCredentials).await;
+//! let credentials = Credentials {
+//!     mail_tel: "user@example.com".to_string(),
+//!     password: "password".into(),
+//! };
+//!
+//! let result = niconico.builder().login(&credentials).await;
+//! assert!(matches!(result, Err(LoginError::InvalidCredentials)));
+//! # }
+//! ```
+
+use std::fmt;
+
+use reqwest::Url;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::LoginBuilder;
+
+/// Session token issued by [`MockOutcome::Success`]
+pub const MOCK_SESSION_TOKEN: &str = "user_session_0000000_mock0000000000000000000000000";
+
+/// How [`MockNiconico`] answers the login request
+///
+/// Each outcome mirrors the response Niconico sends, and the documentation
+/// names the error that a default [`LoginBuilder`] reports for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockOutcome {
+    /// Redirects with a `user_session` cookie, marked `Secure` and `HttpOnly`,
+    /// whose token is [`MOCK_SESSION_TOKEN`]
+    Success,
+    /// Redirects back to the login form with `message=cant_login`, reported
+    /// as `LoginError::InvalidCredentials`
+    BadCredentials,
+    /// Redirects to the multi-factor authentication page without a session,
+    /// reported as `LoginError::MfaRequired`
+    MfaRequired,
+    /// Answers `429 Too Many Requests` with a `Retry-After` header, reported
+    /// as `LoginError::UnexpectedStatus`
+    RateLimited,
+    /// Answers `503 Service Unavailable` with the maintenance page, reported
+    /// as `LoginError::ServiceUnavailable`
+    Maintenance,
+}
+
+impl MockOutcome {
+    /// Returns the response Niconico sends for the outcome
+    fn response(self) -> ResponseTemplate {
+        match self {
+            MockOutcome::Success => ResponseTemplate::new(302)
+                .append_header("Location", "https://www.nicovideo.jp/")
+                .append_header(
+                    "Set-Cookie",
+                    format!(
+                        "user_session={MOCK_SESSION_TOKEN}; Max-Age=2592000; Domain=.nicovideo.jp; Path=/; Secure; HttpOnly"
+                    ),
+                )
+                .append_header(
+                    "Set-Cookie",
+                    "user_session_secure=mock; Max-Age=2592000; Domain=.nicovideo.jp; Path=/; Secure; HttpOnly",
+                ),
+            MockOutcome::BadCredentials => ResponseTemplate::new(302).append_header(
+                "Location",
+                "https://account.nicovideo.jp/login?site=niconico&message=cant_login",
+            ),
+            MockOutcome::MfaRequired => ResponseTemplate::new(302).append_header(
+                "Location",
+                "https://account.nicovideo.jp/mfa?site=niconico&continue=https%3A%2F%2Fwww.nicovideo.jp%2F",
+            ),
+            MockOutcome::RateLimited => {
+                ResponseTemplate::new(429).append_header("Retry-After", "60")
+            }
+            MockOutcome::Maintenance => ResponseTemplate::new(503)
+                .set_body_raw(MAINTENANCE_PAGE, "text/html; charset=UTF-8"),
+        }
+    }
+}
+
+/// Body of the maintenance page
+const MAINTENANCE_PAGE: &str =
+    "<html><head><title>メンテナンス中 - niconico</title></head><body>ただいまメンテナンス中です</body></html>";
+
+/// An in-process server standing in for the Niconico login endpoint
+///
+/// The server runs until it is dropped. Point a builder at it with
+/// [`builder`](Self::builder), or with [`LoginBuilder::base_urls`] and
+/// [`url`](Self::url).
+pub struct MockNiconico {
+    server: MockServer,
+}
+
+impl MockNiconico {
+    /// Starts a server answering every login request with the outcome
+    pub async fn start(outcome: MockOutcome) -> Self {
+        let niconico = Self {
+            server: MockServer::start().await,
+        };
+        niconico.respond_with(outcome).await;
+        niconico
+    }
+
+    /// Changes how the following login requests are answered
+    ///
+    /// The requests received so far are forgotten.
+    pub async fn respond_with(&self, outcome: MockOutcome) {
+        self.server.reset().await;
+        Mock::given(method("POST"))
+            .and(path("/login/redirector"))
+            .respond_with(outcome.response())
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Returns the base URL of the server
+    pub fn url(&self) -> Url {
+        Url::parse(&self.server.uri()).expect("the mock server has a valid URL")
+    }
+
+    /// Returns a default builder that logs in to the server
+    pub fn builder(&self) -> LoginBuilder {
+        LoginBuilder::new().base_urls([self.url()])
+    }
+
+    /// Returns the number of login requests received since the last outcome was set
+    pub async fn login_requests(&self) -> usize {
+        self.server
+            .received_requests()
+            .await
+            .map_or(0, |requests| requests.len())
+    }
+}
+
+impl fmt::Debug for MockNiconico {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockNiconico")
+            .field("url", &self.server.uri())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_session_token_eq;
+    use crate::{Credentials, LoginError};
+
+    fn credentials() -> Credentials {
+        Credentials {
+            mail_tel: "user@example.com".to_string(),
+            password: "password".into(),
+        }
+    }
+
+    /// Tests that every outcome is reported as documented
+    #[tokio::test]
+    async fn test_outcomes() {
+        let niconico = MockNiconico::start(MockOutcome::Success).await;
+        let builder = niconico.builder().require_secure_cookie(true);
+
+        let user_session = builder.login(&credentials()).await.unwrap();
+        assert_session_token_eq(&user_session, MOCK_SESSION_TOKEN);
+        assert!(user_session.has_secure());
+        assert_eq!(niconico.login_requests().await, 1);
+
+        niconico.respond_with(MockOutcome::BadCredentials).await;
+        let result = builder.login(&credentials()).await;
+        assert!(
+            matches!(result, Err(LoginError::InvalidCredentials)),
+            "{result:?}"
+        );
+
+        niconico.respond_with(MockOutcome::MfaRequired).await;
+        let result = builder.login(&credentials()).await;
+        assert!(matches!(result, Err(LoginError::MfaRequired)), "{result:?}");
+
+        niconico.respond_with(MockOutcome::RateLimited).await;
+        let result = builder.login(&credentials()).await;
+        assert!(
+            matches!(result, Err(LoginError::UnexpectedStatus(status)) if status == 429),
+            "{result:?}"
+        );
+
+        niconico.respond_with(MockOutcome::Maintenance).await;
+        let result = builder.login(&credentials()).await;
+        assert!(
+            matches!(result, Err(LoginError::ServiceUnavailable)),
+            "{result:?}"
+        );
+        assert_eq!(niconico.login_requests().await, 1);
+    }
+}
@@ -0,0 +1,111 @@
+//! Timing breakdown of a login
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+/// Where the time of a login was spent
+///
+/// reqwest does not report the TCP connect and TLS handshake separately, so
+/// they are part of [`time_to_first_byte`](Self::time_to_first_byte), which
+/// still tells network issues apart from a slow server when compared with
+/// [`dns`](Self::dns) and [`total`](Self::total).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginTimings {
+    /// Time spent resolving the host name
+    ///
+    /// `None` when the name was not resolved by this crate, which is the case
+    /// with a `reqwest-middleware` client, on a prewarmed connection, or with
+    /// the built-in Hickory resolver of the `hickory-dns` feature and no
+    /// custom resolver.
+    pub dns: Option<Duration>,
+    /// Time from sending the login request until the response headers arrived,
+    /// including name resolution, connecting and the TLS handshake
+    pub time_to_first_byte: Duration,
+    /// Time of the whole login at the endpoint that answered, including
+    /// retries of a response without a session cookie
+    pub total: Duration,
+}
+
+/// Records how long name resolution took
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DnsTimer(Arc<Mutex<Option<Duration>>>);
+
+impl DnsTimer {
+    /// Returns the duration of the last resolution, if any
+    pub(crate) fn get(&self) -> Option<Duration> {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wraps a resolver, or the system resolver with `None`, to time its resolutions
+    pub(crate) fn resolver(&self, inner: Option<Arc<dyn Resolve>>) -> TimingResolver {
+        TimingResolver {
+            inner,
+            timer: self.clone(),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(duration);
+    }
+}
+
+/// A resolver that records the duration of every resolution
+pub(crate) struct TimingResolver {
+    inner: Option<Arc<dyn Resolve>>,
+    timer: DnsTimer,
+}
+
+impl Resolve for TimingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let inner = self.inner.clone();
+        let timer = self.timer.clone();
+
+        Box::pin(async move {
+            let started = Instant::now();
+            let addrs = match inner {
+                Some(inner) => inner.resolve(name).await,
+                None => system_resolve(name).await,
+            };
+            timer.record(started.elapsed());
+            addrs
+        })
+    }
+}
+
+/// Resolves a name with the system resolver, as reqwest does by default
+pub(crate) async fn system_resolve(
+    name: Name,
+) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+    Ok(Box::new(addrs.into_iter()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that resolutions through the system resolver are timed
+    #[tokio::test]
+    async fn test_timing_resolver() {
+        let timer = DnsTimer::default();
+        assert_eq!(timer.get(), None);
+
+        let addrs = timer
+            .resolver(None)
+            .resolve("localhost".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(addrs.count() > 0);
+        assert!(timer.get().is_some());
+    }
+}
@@ -0,0 +1,60 @@
+//! Typed Niconico account IDs
+
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The numeric ID of a Niconico account
+///
+/// This is serialized as a plain number and parsed from its decimal form,
+/// so it cannot be confused with other numeric fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserId(pub u64);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(UserId)
+    }
+}
+
+impl From<u64> for UserId {
+    fn from(id: u64) -> Self {
+        UserId(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a user ID is parsed from and formatted as its decimal form
+    #[test]
+    fn test_parse_and_display() {
+        let user_id: UserId = "12345678".parse().unwrap();
+        assert_eq!(user_id, UserId(12345678));
+        assert_eq!(user_id.to_string(), "12345678");
+
+        assert!("".parse::<UserId>().is_err());
+        assert!("-1".parse::<UserId>().is_err());
+        assert!("user/123".parse::<UserId>().is_err());
+    }
+
+    /// Tests that a user ID is serialized as a plain number
+    #[test]
+    fn test_serde() {
+        assert_eq!(serde_json::to_string(&UserId(123)).unwrap(), "123");
+        assert_eq!(serde_json::from_str::<UserId>("123").unwrap(), UserId(123));
+        assert!(serde_json::from_str::<UserId>("\"123\"").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Checking whether a stored session is still usable
+
+use std::future::Future;
+
+use reqwest::{IntoUrl, Method, StatusCode};
+use tokio::task::{JoinError, JoinSet};
+
+use crate::request::{authenticated_request, default_client};
+use crate::{request_error, Credentials, LoginBuilder, LoginError, UserSession};
+
+/// Endpoint that answers with the logged-in user, or 401 without a valid session
+pub(crate) const USERS_ME_URL: &str = "https://nvapi.nicovideo.jp/v1/users/me";
+
+/// How [`ensure_session`] obtained the session it returned
+#[derive(Debug, Clone)]
+pub enum SessionOutcome {
+    /// The given session was still valid and is returned unchanged
+    Reused(UserSession),
+    /// The given session was missing or invalid, so a fresh login was made
+    Refreshed(UserSession),
+}
+
+impl SessionOutcome {
+    /// Returns the session, however it was obtained
+    pub fn into_user_session(self) -> UserSession {
+        match self {
+            SessionOutcome::Reused(user_session) | SessionOutcome::Refreshed(user_session) => {
+                user_session
+            }
+        }
+    }
+}
+
+/// Checks whether a session is still accepted by Niconico
+///
+/// # Arguments
+///
+/// * `user_session` - The session to check
+///
+/// # Returns
+///
+/// * `Ok(true)` if Niconico recognizes the session as logged in
+/// * `Ok(false)` if Niconico rejected the session
+/// * `Err(LoginError)` if the check itself failed
+pub async fn validate_session(user_session: &UserSession) -> Result<bool, LoginError> {
+    validate_session_at(&default_client()?, user_session, USERS_ME_URL).await
+}
+
+/// Checks a session against an endpoint answering like `users/me`
+async fn validate_session_at(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+    url: impl IntoUrl,
+) -> Result<bool, LoginError> {
+    let res = authenticated_request(client, user_session, Method::GET, url)?
+        .header("X-Frontend-Id", "6")
+        .send()
+        .await
+        .map_err(request_error)?;
+
+    classify_validation(res.status())
+}
+
+/// Checks that a freshly issued session is accepted, failing with `VerificationFailed` otherwise
+pub(crate) async fn verify_session(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+) -> Result<(), LoginError> {
+    verify_session_at(client, user_session, USERS_ME_URL).await
+}
+
+/// Checks that a session is accepted by an endpoint answering like `users/me`
+async fn verify_session_at(
+    client: &reqwest::Client,
+    user_session: &UserSession,
+    url: impl IntoUrl,
+) -> Result<(), LoginError> {
+    match validate_session_at(client, user_session, url).await? {
+        true => Ok(()),
+        false => Err(LoginError::VerificationFailed),
+    }
+}
+
+/// Checks many sessions concurrently, e.g. to prune a pool of stored sessions
+///
+/// At most `concurrency` checks run at the same time, and a `concurrency` of
+/// 0 is treated as 1. A failed check does not affect the others. The checks
+/// are spawned on the Tokio runtime this is called from.
+///
+/// # Arguments
+///
+/// * `sessions` - The sessions to check
+/// * `concurrency` - The maximum number of checks running at the same time
+///
+/// # Returns
+///
+/// Returns the result of [`validate_session`] for every session, in the
+/// order of the sessions.
+pub async fn validate_many(
+    sessions: &[UserSession],
+    concurrency: usize,
+) -> Vec<Result<bool, LoginError>> {
+    run_bounded(sessions, concurrency, |user_session| async move {
+        validate_session(&user_session).await
+    })
+    .await
+}
+
+/// Runs a task on every session with bounded parallelism, keeping the input order
+pub(crate) async fn run_bounded<T, F, Fut>(
+    sessions: &[UserSession],
+    concurrency: usize,
+    check: F,
+) -> Vec<Result<T, LoginError>>
+where
+    T: Send + 'static,
+    F: Fn(UserSession) -> Fut,
+    Fut: Future<Output = Result<T, LoginError>> + Send + 'static,
+{
+    let mut results: Vec<_> = sessions.iter().map(|_| None).collect();
+    let mut tasks = JoinSet::new();
+    let mut store = |joined: Result<(usize, Result<T, LoginError>), JoinError>| match joined {
+        Ok((index, result)) => results[index] = Some(result),
+        Err(error) => std::panic::resume_unwind(error.into_panic()),
+    };
+
+    for (index, user_session) in sessions.iter().enumerate() {
+        if tasks.len() >= concurrency.max(1) {
+            if let Some(joined) = tasks.join_next().await {
+                store(joined);
+            }
+        }
+        let check = check(user_session.clone());
+        tasks.spawn(async move { (index, check.await) });
+    }
+    while let Some(joined) = tasks.join_next().await {
+        store(joined);
+    }
+
+    results
+        .into_iter()
+        .map(|result| result.expect("every check was joined"))
+        .collect()
+}
+
+/// Returns a valid session, reusing the given one when Niconico still accepts it
+///
+/// # Arguments
+///
+/// * `user_session` - A previously stored session, if any
+/// * `credentials` - The credentials to log in with when the session is unusable
+///
+/// # Returns
+///
+/// Returns either:
+/// * `Ok(SessionOutcome)` telling whether the session was reused or refreshed
+/// * `Err(LoginError)` if validation or the fresh login failed
+pub async fn ensure_session(
+    user_session: Option<UserSession>,
+    credentials: &Credentials,
+) -> Result<SessionOutcome, LoginError> {
+    if let Some(user_session) = user_session {
+        if validate_session(&user_session).await? {
+            return Ok(SessionOutcome::Reused(user_session));
+        }
+    }
+
+    let user_session = LoginBuilder::new().login(credentials).await?;
+    Ok(SessionOutcome::Refreshed(user_session))
+}
+
+/// Interprets the status of the validation request
+fn classify_validation(status: StatusCode) -> Result<bool, LoginError> {
+    match status {
+        StatusCode::OK => Ok(true),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(false),
+        status => Err(LoginError::UnexpectedStatus(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests how the statuses of the validation request are interpreted
+    #[test]
+    fn test_classify_validation() {
+        assert!(matches!(classify_validation(StatusCode::OK), Ok(true)));
+        assert!(matches!(
+            classify_validation(StatusCode::UNAUTHORIZED),
+            Ok(false)
+        ));
+        assert!(matches!(
+            classify_validation(StatusCode::INTERNAL_SERVER_ERROR),
+            Err(LoginError::UnexpectedStatus(_))
+        ));
+    }
+
+    /// Tests that a rejected session fails verification
+    #[tokio::test]
+    async fn test_verify_session_at() {
+        use crate::test_support::serve_once;
+
+        let client = default_client().unwrap();
+        let user_session = UserSession::new("user_session=user_session_123".into());
+        let (url, _) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(verify_session_at(&client, &user_session, url).await.is_ok());
+
+        let (url, _) = serve_once("HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(matches!(
+            verify_session_at(&client, &user_session, url).await,
+            Err(LoginError::VerificationFailed)
+        ));
+
+        let (url, _) =
+            serve_once("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(matches!(
+            verify_session_at(&client, &user_session, url).await,
+            Err(LoginError::UnexpectedStatus(
+                StatusCode::SERVICE_UNAVAILABLE
+            ))
+        ));
+    }
+
+    /// Tests that the outcome gives back the session in both cases
+    #[test]
+    fn test_session_outcome_into_user_session() {
+        let user_session = UserSession::new("user_session=user_session_123".into());
+
+        let outcome = SessionOutcome::Reused(user_session.clone());
+        assert!(outcome.into_user_session().cookie_header().is_ok());
+
+        let outcome = SessionOutcome::Refreshed(user_session);
+        assert!(outcome.into_user_session().cookie_header().is_ok());
+    }
+
+    /// Tests that the checks run with bounded parallelism and keep the input order
+    #[tokio::test]
+    async fn test_run_bounded() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let sessions: Vec<_> = (0..6)
+            .map(|index| UserSession::new(format!("user_session=user_session_{index}").into()))
+            .collect();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let results = run_bounded(&sessions, 2, |user_session| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                match user_session.cookie_header()?.to_str().unwrap() {
+                    "user_session=user_session_1" => Ok(false),
+                    "user_session=user_session_3" => Err(LoginError::ServiceUnavailable),
+                    _ => Ok(true),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert!(matches!(
+            results[..],
+            [
+                Ok(true),
+                Ok(false),
+                Ok(true),
+                Err(LoginError::ServiceUnavailable),
+                Ok(true),
+                Ok(true)
+            ]
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+//! Non-fatal conditions reported on a successful login
+
+use std::time::SystemTime;
+
+use reqwest::header::{self, HeaderMap};
+use reqwest::Url;
+
+/// Account state noticed during a login that still produced a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginWarning {
+    /// Niconico redirected to the "please change your password" interstitial
+    PasswordChangeRequired,
+    /// Niconico set `user_session` without `user_session_secure`
+    ///
+    /// Only reported when enabled with
+    /// [`LoginBuilder::warn_on_missing_secure`](crate::LoginBuilder::warn_on_missing_secure).
+    /// See [`UserSession::has_secure`](crate::UserSession::has_secure).
+    SecureSessionMissing,
+    /// Niconico reported that logging in ended the other sessions of the account
+    ///
+    /// Sessions of the same account stored elsewhere, e.g. by a
+    /// [`SessionManager`](crate::SessionManager), may no longer be accepted
+    /// and should be checked with [`validate_session`](crate::validate_session).
+    PreviousSessionInvalidated,
+    /// The login response announced that this client will stop working
+    ///
+    /// Niconico may do so with a `Warning` header or, as described in
+    /// RFC 8594, a `Sunset` header. The login succeeded, but the crate or its
+    /// user agent should be updated before the announced date.
+    ClientDeprecated {
+        /// The values of the `Warning` headers, joined with `, `
+        warning: Option<String>,
+        /// The date of the `Sunset` header, if it was valid
+        sunset: Option<SystemTime>,
+    },
+    /// [`LoginBuilder::follow_redirects`](crate::LoginBuilder::follow_redirects)
+    /// stopped at its limit while the last response still redirected
+    ///
+    /// The cookies of the remaining hops were not collected, so the limit may
+    /// need to be raised.
+    RedirectLimitReached {
+        /// The target that was not followed, without its query and fragment
+        location: Url,
+    },
+}
+
+/// Name of the header announcing when a resource stops being served
+const SUNSET: &str = "sunset";
+
+impl LoginWarning {
+    /// Returns the deprecation announced by the headers of a response, if any
+    pub(crate) fn client_deprecated(headers: &HeaderMap) -> Option<Self> {
+        let warnings: Vec<_> = headers
+            .get_all(header::WARNING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        let sunset = headers.get(SUNSET);
+        if warnings.is_empty() && sunset.is_none() {
+            return None;
+        }
+
+        Some(LoginWarning::ClientDeprecated {
+            warning: (!warnings.is_empty()).then(|| warnings.join(", ")),
+            sunset: sunset
+                .and_then(|sunset| sunset.to_str().ok())
+                .and_then(|sunset| httpdate::parse_http_date(sunset).ok()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    /// Tests that a Warning or Sunset header is reported and their absence is not
+    #[test]
+    fn test_client_deprecated() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(LoginWarning::client_deprecated(&headers), None);
+
+        headers.insert(
+            SUNSET,
+            HeaderValue::from_static("Wed, 21 Oct 2037 07:28:00 GMT"),
+        );
+        headers.append(
+            header::WARNING,
+            HeaderValue::from_static(r#"299 - "Deprecated client""#),
+        );
+        assert_eq!(
+            LoginWarning::client_deprecated(&headers),
+            Some(LoginWarning::ClientDeprecated {
+                warning: Some(r#"299 - "Deprecated client""#.to_string()),
+                sunset: httpdate::parse_http_date("Wed, 21 Oct 2037 07:28:00 GMT").ok(),
+            })
+        );
+
+        headers.remove(header::WARNING);
+        headers.insert(SUNSET, HeaderValue::from_static("soon"));
+        assert_eq!(
+            LoginWarning::client_deprecated(&headers),
+            Some(LoginWarning::ClientDeprecated {
+                warning: None,
+                sunset: None,
+            })
+        );
+    }
+}